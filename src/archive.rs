@@ -0,0 +1,331 @@
+#![allow(dead_code)]
+extern crate miniz_oxide;
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use super::romdb;
+
+/*
+ * Compressed ROMs
+ *
+ * gzip: https://www.rfc-editor.org/rfc/rfc1952
+ * zip: https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+ *
+ * Both wrap raw deflate streams, which miniz_oxide inflates.  For a zip we
+ * walk the central directory, since entries written with a data descriptor
+ * only have their sizes there, and take the named entry or the first one that
+ * looks like a ROM.  Only stored and deflated entries are supported.
+ */
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const ZIP_LOCAL_MAGIC: &[u8] = b"PK\x03\x04";
+const ZIP_EMPTY_MAGIC: &[u8] = b"PK\x05\x06"; // an archive with nothing in it is just the end record
+
+const ZIP_LOCAL_HEADER_SIZE: usize = 30;
+const ZIP_CENTRAL_HEADER_SIZE: usize = 46;
+const ZIP_END_SIZE: usize = 22;
+const ZIP_CENTRAL_SIGNATURE: u32 = 0x02014B50;
+const ZIP_END_SIGNATURE: u32 = 0x06054B50;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+// gzip header flags
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+pub const ROM_EXTENSIONS: &[&str] = &["nes", "unf", "unif", "fds", "qd"];
+
+// larger than any NES ROM or disk image, so a zip bomb can't exhaust memory
+pub const MAX_ROM_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn is_archive(bytes: &[u8]) -> bool {
+    return bytes.starts_with(GZIP_MAGIC) || bytes.starts_with(ZIP_LOCAL_MAGIC) || bytes.starts_with(ZIP_EMPTY_MAGIC);
+}
+
+// the ROM inside a zip or gzip file.  entry picks a file out of a zip by name
+pub fn extract(bytes: &[u8], entry: Option<&str>) -> Result<Vec<u8>, String> {
+    if bytes.starts_with(GZIP_MAGIC) {
+        return gunzip(bytes);
+    }
+    return unzip(bytes, entry);
+}
+
+pub fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    if bytes.len() < 18 || !bytes.starts_with(GZIP_MAGIC) {
+        return Err("Not a gzip file".to_string());
+    }
+    if bytes[2] != METHOD_DEFLATED as u8 {
+        return Err(format!("Unsupported gzip compression method {}", bytes[2]));
+    }
+    let flags = bytes[3];
+    let mut position = 10;
+    if flags & FEXTRA > 0 {
+        let length = read_u16(bytes, position).ok_or("Truncated gzip header")? as usize;
+        position += 2 + length;
+    }
+    for flag in [FNAME, FCOMMENT].iter() {
+        if flags & flag > 0 {
+            let end = bytes.get(position..).and_then(|rest| rest.iter().position(|&byte| byte == 0)).ok_or("Truncated gzip header")?;
+            position += end + 1;
+        }
+    }
+    if flags & FHCRC > 0 {
+        position += 2;
+    }
+    if position + 8 > bytes.len() {
+        return Err("Truncated gzip header".to_string());
+    }
+
+    let trailer = bytes.len() - 8;
+    let expected_crc = read_u32(bytes, trailer).unwrap();
+    let expected_size = read_u32(bytes, trailer + 4).unwrap();
+    let data = inflate(&bytes[position..trailer], expected_size as usize)?;
+    if data.len() as u32 != expected_size {
+        return Err(format!("Uncompressed size is {} bytes, expected {}", data.len(), expected_size));
+    }
+    check_crc(&data, expected_crc)?;
+    return Ok(data);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZipEntry {
+    pub name: String,
+    pub method: u16,
+    pub crc32: u32,
+    pub compressed_size: usize,
+    pub size: usize,
+    pub encrypted: bool,
+    local_header_offset: usize,
+}
+
+pub fn unzip(bytes: &[u8], entry: Option<&str>) -> Result<Vec<u8>, String> {
+    let entries = zip_entries(bytes)?;
+    let found = match entry {
+        // a path inside the archive, or just the file name if that's unambiguous
+        Some(name) => entries.iter().find(|e| e.name == name)
+            .or_else(|| entries.iter().find(|e| file_name(&e.name).eq_ignore_ascii_case(name))),
+        None => entries.iter().find(|e| is_rom_name(&e.name)),
+    };
+    return match (found, entry) {
+        (Some(found), _) => read_zip_entry(bytes, found),
+        (None, Some(name)) => Err(format!("'{}' isn't in the archive", name)),
+        (None, None) => {
+            let (last, rest) = ROM_EXTENSIONS.split_last().unwrap();
+            Err(format!("No .{} or .{} file in the archive", rest.join(", ."), last))
+        }
+    };
+}
+
+pub fn zip_entries(bytes: &[u8]) -> Result<Vec<ZipEntry>, String> {
+    // the end record is last, unless the archive has a comment after it
+    let end = (0..=bytes.len().saturating_sub(ZIP_END_SIZE)).rev()
+        .find(|&position| read_u32(bytes, position) == Some(ZIP_END_SIGNATURE))
+        .ok_or("Not a zip file (no end of central directory)")?;
+    let count = read_u16(bytes, end + 10).unwrap() as usize;
+    let directory_offset = read_u32(bytes, end + 16).unwrap();
+    if directory_offset == 0xFFFFFFFF {
+        return Err("Zip64 archives are not supported".to_string());
+    }
+
+    let mut entries = Vec::new();
+    let mut position = directory_offset as usize;
+    for _ in 0..count {
+        if read_u32(bytes, position) != Some(ZIP_CENTRAL_SIGNATURE) || position + ZIP_CENTRAL_HEADER_SIZE > bytes.len() {
+            return Err("Truncated zip central directory".to_string());
+        }
+        let flags = read_u16(bytes, position + 8).unwrap();
+        let name_length = read_u16(bytes, position + 28).unwrap() as usize;
+        let extra_length = read_u16(bytes, position + 30).unwrap() as usize;
+        let comment_length = read_u16(bytes, position + 32).unwrap() as usize;
+        let name_start = position + ZIP_CENTRAL_HEADER_SIZE;
+        let name = bytes.get(name_start..name_start + name_length).ok_or("Truncated zip central directory")?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: read_u16(bytes, position + 10).unwrap(),
+            crc32: read_u32(bytes, position + 16).unwrap(),
+            compressed_size: read_u32(bytes, position + 20).unwrap() as usize,
+            size: read_u32(bytes, position + 24).unwrap() as usize,
+            encrypted: flags & 0x01 > 0,
+            local_header_offset: read_u32(bytes, position + 42).unwrap() as usize,
+        });
+        position = name_start + name_length + extra_length + comment_length;
+    }
+    return Ok(entries);
+}
+
+fn read_zip_entry(bytes: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, String> {
+    if entry.encrypted {
+        return Err(format!("'{}' is encrypted", entry.name));
+    }
+    let header = entry.local_header_offset;
+    if bytes.get(header..header + 4) != Some(ZIP_LOCAL_MAGIC) || header + ZIP_LOCAL_HEADER_SIZE > bytes.len() {
+        return Err(format!("Missing local header for '{}'", entry.name));
+    }
+    // the local extra field can differ from the central directory's
+    let name_length = read_u16(bytes, header + 26).unwrap() as usize;
+    let extra_length = read_u16(bytes, header + 28).unwrap() as usize;
+    let start = header + ZIP_LOCAL_HEADER_SIZE + name_length + extra_length;
+    let compressed = bytes.get(start..start + entry.compressed_size).ok_or_else(|| format!("'{}' is truncated", entry.name))?;
+
+    let data = match entry.method {
+        METHOD_STORED => compressed.to_vec(),
+        METHOD_DEFLATED => inflate(compressed, entry.size)?,
+        method => return Err(format!("'{}' uses unsupported compression method {}", entry.name, method)),
+    };
+    if data.len() != entry.size {
+        return Err(format!("'{}' is {} bytes, expected {}", entry.name, data.len(), entry.size));
+    }
+    check_crc(&data, entry.crc32)?;
+    return Ok(data);
+}
+
+// size is what the archive says the data inflates to, which is never trusted past MAX_ROM_SIZE
+fn inflate(compressed: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    if size > MAX_ROM_SIZE {
+        return Err(format!("Compressed file is too large for a ROM ({} bytes)", size));
+    }
+    return match miniz_oxide::inflate::decompress_to_vec_with_limit(compressed, size) {
+        Ok(data) => Ok(data),
+        Err(e) if e.status == miniz_oxide::inflate::TINFLStatus::HasMoreOutput => Err(format!("Compressed file is larger than the {} bytes it claims", size)),
+        Err(e) => Err(format!("Bad deflate data ({:?})", e.status)),
+    };
+}
+
+fn check_crc(data: &[u8], expected: u32) -> Result<(), String> {
+    let crc = romdb::crc32(data);
+    if crc != expected {
+        return Err(format!("CRC32 mismatch: expected {:08X}, found {:08X}", expected, crc));
+    }
+    return Ok(());
+}
+
+fn is_rom_name(name: &str) -> bool {
+    return match Path::new(name).extension() {
+        Some(extension) => ROM_EXTENSIONS.iter().any(|rom| extension.to_string_lossy().eq_ignore_ascii_case(rom)),
+        None => false,
+    };
+}
+
+fn file_name(path: &str) -> &str {
+    return path.rsplit('/').next().unwrap_or(path);
+}
+
+fn read_u16(bytes: &[u8], position: usize) -> Option<u16> {
+    return Some(u16::from_le_bytes(bytes.get(position..position + 2)?.try_into().unwrap()));
+}
+
+fn read_u32(bytes: &[u8], position: usize) -> Option<u32> {
+    return Some(u32::from_le_bytes(bytes.get(position..position + 4)?.try_into().unwrap()));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // builds a zip of (name, data, deflate?) entries
+    pub fn zip_file(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data, deflate) in files.iter() {
+            let (method, compressed) = match deflate {
+                true => (METHOD_DEFLATED, miniz_oxide::deflate::compress_to_vec(data, 6)),
+                false => (METHOD_STORED, data.to_vec()),
+            };
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            fields.extend_from_slice(&0u16.to_le_bytes());  // flags
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);              // time and date
+            fields.extend_from_slice(&romdb::crc32(data).to_le_bytes());
+            fields.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());  // extra length
+
+            directory.extend_from_slice(&ZIP_CENTRAL_SIGNATURE.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 6]);              // comment length, disk, internal attributes
+            directory.extend_from_slice(&[0; 4]);              // external attributes
+            directory.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            bytes.extend_from_slice(ZIP_LOCAL_MAGIC);
+            bytes.extend_from_slice(&fields);
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&compressed);
+        }
+        let directory_offset = bytes.len() as u32;
+        bytes.extend_from_slice(&directory);
+        bytes.extend_from_slice(&ZIP_END_SIGNATURE.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]); // disk numbers
+        bytes.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&directory_offset.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        return bytes;
+    }
+
+    pub fn gzip_file(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x1F, 0x8B, METHOD_DEFLATED as u8, FNAME, 0, 0, 0, 0, 0, 0xFF];
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+        bytes.extend_from_slice(&romdb::crc32(data).to_le_bytes());
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        return bytes;
+    }
+
+    #[test]
+    fn zip() {
+        let rom = (0..1000).map(|i| (i % 7) as u8).collect::<Vec<u8>>();
+        let bytes = zip_file(&[("readme.txt", b"hello", false), ("games/a.nes", &rom, true), ("b.NES", b"stored", false)]);
+        assert!(is_archive(&bytes));
+
+        let entries = zip_entries(&bytes).unwrap();
+        assert_eq!(entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), vec!["readme.txt", "games/a.nes", "b.NES"]);
+        assert_eq!(entries[1].method, METHOD_DEFLATED);
+
+        assert_eq!(extract(&bytes, None).unwrap(), rom);
+        assert_eq!(extract(&bytes, Some("b.NES")).unwrap(), b"stored");
+        assert_eq!(extract(&bytes, Some("a.nes")).unwrap(), rom);
+        assert_eq!(extract(&bytes, Some("readme.txt")).unwrap(), b"hello");
+        assert_eq!(extract(&bytes, Some("c.nes")).unwrap_err(), "'c.nes' isn't in the archive");
+
+        let no_roms = zip_file(&[("readme.txt", b"hello", false)]);
+        assert_eq!(extract(&no_roms, None).unwrap_err(), "No .nes, .unf, .unif, .fds or .qd file in the archive");
+        assert_eq!(extract(&zip_file(&[]), None).unwrap_err(), "No .nes, .unf, .unif, .fds or .qd file in the archive");
+
+        // flip a byte of the stored entry
+        let mut corrupt = zip_file(&[("b.nes", b"stored", false)]);
+        corrupt[ZIP_LOCAL_HEADER_SIZE + 5] ^= 0xFF;
+        assert!(extract(&corrupt, None).unwrap_err().starts_with("CRC32 mismatch"));
+        assert!(unzip(&bytes[..bytes.len() - 30], None).is_err());
+    }
+
+    #[test]
+    fn gzip() {
+        let rom = (0..1000).map(|i| (i % 13) as u8).collect::<Vec<u8>>();
+        let bytes = gzip_file("a.nes", &rom);
+        assert!(is_archive(&bytes));
+        assert_eq!(extract(&bytes, None).unwrap(), rom);
+
+        let mut corrupt = bytes.clone();
+        let length = corrupt.len();
+        corrupt[length - 8] ^= 0xFF;
+        assert!(gunzip(&corrupt).unwrap_err().starts_with("CRC32 mismatch"));
+        assert_eq!(gunzip(&bytes[..12]).unwrap_err(), "Not a gzip file");
+
+        // a zip bomb claiming to be smaller than it is, or too big to be a ROM
+        let mut bomb = bytes.clone();
+        bomb[length - 4..].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(gunzip(&bomb).unwrap_err(), "Compressed file is larger than the 100 bytes it claims");
+        bomb[length - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(gunzip(&bomb).unwrap_err().contains("too large"));
+        assert!(!is_archive(b"NES\x1A"));
+    }
+}
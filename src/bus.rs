@@ -2,6 +2,7 @@
 extern crate hex;
 
 use super::cartridge;
+use super::cdl;
 
 const BUS_RAM_SIZE: usize = 64 * 1024;
 
@@ -30,13 +31,35 @@ impl Bus {
             self.ram[usize::from(addr & 0x7FF)] = data;
         } else if addr <= 0x3FFF { // ppu flags
             self.write_to_ppu(addr & 0x0007, data);
-        } else if addr >= 0x4020 { // program rom
-            self.ram[usize::from(addr)] = data;
+            if let Some(cart) = self.cartridge.as_mut() {
+                cart.ppu_register_write(0x2000 | (addr & 0x0007), data);
+            }
+        } else if addr >= 0x4020 { // cartridge space
+            match self.cartridge.as_mut() {
+                Some(cart) => cart.write(addr, data),
+                None => self.ram[usize::from(addr)] = data,
+            }
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        return self.read_as(addr, cdl::PrgAccess::Data);
+    }
+
+    // read on behalf of the cpu, telling the cartridge what the byte is being used for
+    pub fn read_as(&mut self, addr: u16, access: cdl::PrgAccess) -> u8 {
+        return if addr >= 0x4020 {
+            match self.cartridge.as_mut() {
+                Some(cart) => cart.read(addr, access),
+                None => self.ram[addr as usize],
+            }
+        } else {
+            self.peek(addr)
         }
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
-        let _read_only: bool = false; // this will be a parameter in the future
+    // read without side effects, for debuggers and trace logs
+    pub fn peek(&self, addr: u16) -> u8 {
         return if addr <= 0x1FFF {
             // cpu bus has 8k addressable range but only 
             // 2k physical ram, so mirror 2k ram 4 times
@@ -47,7 +70,7 @@ impl Bus {
             // program rom (or cpu rom if no cartridge is loaded)
             match self.cartridge.as_ref() {
                 Some(cart) => {
-                    cart.read(addr)
+                    cart.peek(addr)
                 }
                 None => {
                     self.ram[addr as usize]
@@ -73,7 +96,7 @@ impl Bus {
         let mut result: Vec<u8> = Vec::new();
         for offset in 0..num_bytes {
             let abs_addr = addr + (offset as u16);
-            result.push(self.read(abs_addr));
+            result.push(self.peek(abs_addr));
         }
         return hex::encode_upper(result);
     }
@@ -142,9 +165,48 @@ impl Bus {
         }
     }
 
+    pub fn log_prg_access(&mut self, addr: u16, access: cdl::PrgAccess) {
+        if let Some(cart) = self.cartridge.as_mut() {
+            cart.log_prg_access(addr, access);
+        }
+    }
+
+    pub fn cartridge(&self) -> Option<&cartridge::Cartridge> {
+        return self.cartridge.as_deref();
+    }
+
+    pub fn cartridge_mut(&mut self) -> Option<&mut cartridge::Cartridge> {
+        return self.cartridge.as_deref_mut();
+    }
+
+    // advances the cartridge by one cpu cycle
+    pub fn cpu_clock(&mut self) {
+        if let Some(cart) = self.cartridge.as_mut() {
+            cart.cpu_clock();
+        }
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        return match self.cartridge.as_ref() {
+            Some(cart) => cart.irq_pending(),
+            None => false,
+        }
+    }
+
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<u32> {
+        return match self.cartridge.as_ref() {
+            Some(cart) => cart.prg_rom_offset(addr),
+            None => None,
+        }
+    }
+
     pub fn connect_cartridge(&mut self, cartridge: Box<cartridge::Cartridge>) {
         self.cartridge = Some(cartridge);
     }
+
+    pub fn disconnect_cartridge(&mut self) -> Option<Box<cartridge::Cartridge>> {
+        return self.cartridge.take();
+    }
 }
 
 pub fn create_bus() -> Bus {
@@ -0,0 +1,251 @@
+#![allow(dead_code)]
+
+/*
+ * Shadow call stack maintained alongside the 6502 hardware stack.  Every
+ * JSR and interrupt entry pushes a frame, every RTS and RTI pops one.  Each
+ * frame remembers where the stack pointer should be when the matching return
+ * executes, so code that juggles the hardware stack (PLA/PLA to drop a return
+ * address, pushing a fake address for the "RTS trick", etc.) is detected and
+ * reported instead of silently corrupting the shadow stack.
+ */
+
+// only the most recent violations are kept; jump tables using the RTS trick
+// report one on every dispatch
+pub const MAX_VIOLATIONS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Subroutine,
+    Nmi,
+    Irq,
+    Brk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReturnKind {
+    Rts,
+    Rti,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StackFrame {
+    pub kind: FrameKind,
+    pub call_site: u16,   // address of the JSR, or of the interrupted instruction
+    pub target: u16,      // subroutine entry point or interrupt handler address
+    pub return_addr: u16, // where execution should resume on return
+    pub stack_ptr: u8,    // stack pointer once the return address was pushed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViolationKind {
+    // the return popped a frame, but to a different address than was pushed
+    ReturnAddressChanged,
+    // the return happened above the top frame; the frames in between were abandoned
+    FramesDiscarded(usize),
+    // the return doesn't correspond to any frame (e.g. the "RTS trick")
+    UnmatchedReturn,
+    // a return of the other kind (RTS from an interrupt handler, RTI from a subroutine)
+    MismatchedReturnKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StackViolation {
+    pub kind: ViolationKind,
+    pub return_kind: ReturnKind,
+    pub stack_ptr: u8,
+    pub return_addr: u16,
+    pub expected_addr: Option<u16>,
+}
+
+pub struct CallStack {
+    frames: Vec<StackFrame>,
+    violations: Vec<StackViolation>,
+    violation_count: usize, // including the ones no longer kept
+}
+
+impl CallStack {
+
+    pub fn push_call(&mut self, call_site: u16, target: u16, return_addr: u16, stack_ptr: u8) {
+        self.frames.push(StackFrame {
+            kind: FrameKind::Subroutine,
+            call_site,
+            target,
+            return_addr,
+            stack_ptr,
+        });
+    }
+
+    pub fn push_interrupt(&mut self, kind: FrameKind, interrupted_addr: u16, handler: u16, stack_ptr: u8) {
+        self.frames.push(StackFrame {
+            kind,
+            call_site: interrupted_addr,
+            target: handler,
+            return_addr: interrupted_addr,
+            stack_ptr,
+        });
+    }
+
+    // stack_ptr is the value before the return address was pulled
+    pub fn pop_return(&mut self, return_kind: ReturnKind, stack_ptr: u8, return_addr: u16) {
+        // frames pushed deeper than the current stack pointer can never be returned to
+        let mut discarded: usize = 0;
+        while let Some(frame) = self.frames.last() {
+            if frame.stack_ptr >= stack_ptr {
+                break;
+            }
+            self.frames.pop();
+            discarded += 1;
+        }
+        if discarded > 0 {
+            self.record(ViolationKind::FramesDiscarded(discarded), return_kind, stack_ptr, return_addr, None);
+        }
+
+        let frame = match self.frames.last() {
+            Some(frame) if frame.stack_ptr == stack_ptr => *frame,
+            _ => {
+                self.record(ViolationKind::UnmatchedReturn, return_kind, stack_ptr, return_addr, None);
+                return;
+            }
+        };
+        self.frames.pop();
+
+        let expected_return = if frame.kind == FrameKind::Subroutine { ReturnKind::Rts } else { ReturnKind::Rti };
+        if expected_return != return_kind {
+            self.record(ViolationKind::MismatchedReturnKind, return_kind, stack_ptr, return_addr, Some(frame.return_addr));
+        } else if frame.return_addr != return_addr {
+            self.record(ViolationKind::ReturnAddressChanged, return_kind, stack_ptr, return_addr, Some(frame.return_addr));
+        }
+    }
+
+    fn record(&mut self, kind: ViolationKind, return_kind: ReturnKind, stack_ptr: u8, return_addr: u16, expected_addr: Option<u16>) {
+        if self.violations.len() == MAX_VIOLATIONS {
+            self.violations.remove(0);
+        }
+        self.violation_count += 1;
+        self.violations.push(StackViolation {
+            kind,
+            return_kind,
+            stack_ptr,
+            return_addr,
+            expected_addr,
+        });
+    }
+
+    // innermost frame last
+    pub fn frames(&self) -> &[StackFrame] {
+        return &self.frames;
+    }
+
+    pub fn depth(&self) -> usize {
+        return self.frames.len();
+    }
+
+    // oldest first, at most MAX_VIOLATIONS
+    pub fn violations(&self) -> &[StackViolation] {
+        return &self.violations;
+    }
+
+    pub fn violation_count(&self) -> usize {
+        return self.violation_count;
+    }
+
+    pub fn clear_violations(&mut self) {
+        self.violations.clear();
+        self.violation_count = 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.clear_violations();
+    }
+}
+
+pub fn create_call_stack() -> CallStack {
+    return CallStack {
+        frames: Vec::new(),
+        violations: Vec::new(),
+        violation_count: 0,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_and_return() {
+        let mut stack = create_call_stack();
+        stack.push_call(0x8000, 0x9000, 0x8003, 0xFB);
+        stack.push_call(0x9004, 0xA000, 0x9007, 0xF9);
+        assert_eq!(stack.depth(), 2);
+        assert_eq!(stack.frames()[1].target, 0xA000);
+        stack.pop_return(ReturnKind::Rts, 0xF9, 0x9007);
+        stack.pop_return(ReturnKind::Rts, 0xFB, 0x8003);
+        assert_eq!(stack.depth(), 0);
+        assert!(stack.violations().is_empty());
+    }
+
+    #[test]
+    fn interrupt_and_return() {
+        let mut stack = create_call_stack();
+        stack.push_call(0x8000, 0x9000, 0x8003, 0xFB);
+        stack.push_interrupt(FrameKind::Nmi, 0x9010, 0xC000, 0xF8);
+        assert_eq!(stack.frames()[1].kind, FrameKind::Nmi);
+        stack.pop_return(ReturnKind::Rti, 0xF8, 0x9010);
+        assert_eq!(stack.depth(), 1);
+        assert!(stack.violations().is_empty());
+    }
+
+    #[test]
+    fn dropped_return_address() {
+        // subroutine at $A000 discards its own return address with PLA/PLA and returns to $8003
+        let mut stack = create_call_stack();
+        stack.push_call(0x8000, 0x9000, 0x8003, 0xFB);
+        stack.push_call(0x9004, 0xA000, 0x9007, 0xF9);
+        stack.pop_return(ReturnKind::Rts, 0xFB, 0x8003);
+        assert_eq!(stack.depth(), 0);
+        assert_eq!(stack.violations().len(), 1);
+        assert_eq!(stack.violations()[0].kind, ViolationKind::FramesDiscarded(1));
+    }
+
+    #[test]
+    fn rts_trick() {
+        // push an address and RTS to it, as a jump table would
+        let mut stack = create_call_stack();
+        stack.push_call(0x8000, 0x9000, 0x8003, 0xFB);
+        stack.pop_return(ReturnKind::Rts, 0xF9, 0xB000);
+        assert_eq!(stack.depth(), 1);
+        assert_eq!(stack.violations()[0].kind, ViolationKind::UnmatchedReturn);
+
+        // a long running jump table only keeps the latest violations
+        for dispatch in 0..1000 {
+            stack.pop_return(ReturnKind::Rts, 0xF9, 0xB000 + dispatch);
+        }
+        assert_eq!(stack.violations().len(), MAX_VIOLATIONS);
+        assert_eq!(stack.violation_count(), 1001);
+        assert_eq!(stack.violations()[MAX_VIOLATIONS - 1].return_addr, 0xB000 + 999);
+        stack.clear_violations();
+        assert_eq!(stack.violation_count(), 0);
+    }
+
+    #[test]
+    fn overwritten_return_address() {
+        let mut stack = create_call_stack();
+        stack.push_call(0x8000, 0x9000, 0x8003, 0xFB);
+        stack.pop_return(ReturnKind::Rts, 0xFB, 0x8100);
+        assert_eq!(stack.depth(), 0);
+        let violation = stack.violations()[0];
+        assert_eq!(violation.kind, ViolationKind::ReturnAddressChanged);
+        assert_eq!(violation.expected_addr, Some(0x8003));
+        assert_eq!(violation.return_addr, 0x8100);
+    }
+
+    #[test]
+    fn rti_from_subroutine() {
+        let mut stack = create_call_stack();
+        stack.push_call(0x8000, 0x9000, 0x8003, 0xFB);
+        stack.pop_return(ReturnKind::Rti, 0xFB, 0x8003);
+        assert_eq!(stack.depth(), 0);
+        assert_eq!(stack.violations()[0].kind, ViolationKind::MismatchedReturnKind);
+    }
+}
@@ -1,115 +1,852 @@
 #![allow(dead_code)]
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::path::Path;
 use std::convert::TryInto;
+use std::fmt;
 
+use super::archive;
+use super::cdl;
+use super::fds;
 use super::mapper;
+use super::patch;
+use super::romdb;
+use super::unif;
 
 const PROGRAM_ROM_CHUNK_SIZE: usize = 16384;
 const CHARACTER_ROM_CHUNK_SIZE: usize = 8192;
+const PRG_RAM_SIZE: usize = 8192;
+const FOUR_SCREEN_VRAM_SIZE: usize = 2048;
+const TRAINER_SIZE: usize = 512;
+const TRAINER_OFFSET: usize = 0x1000; // $7000 in the PRG RAM bank at $6000
 
 pub struct Cartridge {
     header: Header,
     mapper: Box<dyn mapper::Mapper>,
     program_rom: Vec<u8>,
     character_rom: Vec<u8>,
+    chr_ram: Vec<u8>, // pattern memory on boards without CHR ROM
+    prg_ram: Vec<u8>,
+    vram: Vec<u8>, // extra nametable RAM on four screen boards
+    code_data_logger: Option<cdl::CodeDataLogger>,
+    warnings: Vec<LoadWarning>,
+    game_info: Option<romdb::GameInfo>, // database entry matching the ROM's hash
+    save_filename: Option<String>, // where battery-backed PRG RAM is persisted
+    save_dirty: bool,
 }
 
 impl Cartridge {
 
-    pub fn read(&self, addr: u16) -> u8 {
-        let mapped_addr: u32 = self.mapper.map_address(addr);
-        //println!("Input addr: {}; Mapped addr: {}", addr, mapped_addr);
-        return self.program_rom[mapped_addr as usize];
+    // CPU bus ($4020-$FFFF)
+    pub fn read(&mut self, addr: u16, access: cdl::PrgAccess) -> u8 {
+        let mapping = self.mapper.cpu_read(addr);
+        //println!("Input addr: {}; Mapping: {:?}", addr, mapping);
+        if let mapper::CpuMapping::PrgRom(offset) = mapping {
+            if let Some(logger) = self.code_data_logger.as_mut() {
+                logger.log_prg(offset, addr, access);
+            }
+        }
+        return self.resolve_cpu_read(addr, mapping);
+    }
+
+    // read without side effects, for debuggers and trace logs
+    pub fn peek(&self, addr: u16) -> u8 {
+        return self.resolve_cpu_read(addr, self.mapper.cpu_peek(addr));
+    }
+
+    fn resolve_cpu_read(&self, addr: u16, mapping: mapper::CpuMapping) -> u8 {
+        return match mapping {
+            mapper::CpuMapping::PrgRom(offset) => read_wrapped(&self.program_rom, offset),
+            mapper::CpuMapping::PrgRam(offset) => read_wrapped(&self.prg_ram, offset),
+            mapper::CpuMapping::Data(data) => data,
+            mapper::CpuMapping::Unmapped => (addr >> 8) as u8, // open bus usually holds the high byte of the address
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let data = if addr >= 0x8000 && self.mapper.has_bus_conflicts() {
+            data & self.peek(addr)
+        } else {
+            data
+        };
+        if let mapper::CpuMapping::PrgRam(offset) = self.mapper.cpu_write(addr, data) {
+            write_wrapped(&mut self.prg_ram, offset, data);
+            self.save_dirty |= self.header.has_battery;
+        }
+    }
+
+    // cpu writes to $2000-$2007 are visible to the cartridge too
+    pub fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        self.mapper.ppu_register_write(addr, data);
+    }
+
+    // PPU bus ($0000-$3EFF).  ciram is the console's 2k of nametable RAM, which
+    // the cartridge decides how to wire up
+    pub fn ppu_read(&mut self, addr: u16, ciram: &[u8], access: cdl::ChrAccess) -> u8 {
+        let mapping = self.mapper.ppu_read(addr);
+        let data = match self.resolve_ppu_mapping(addr, mapping) {
+            mapper::PpuMapping::Chr(offset) if self.character_rom.is_empty() => read_wrapped(&self.chr_ram, offset),
+            mapper::PpuMapping::Chr(offset) => {
+                if let Some(logger) = self.code_data_logger.as_mut() {
+                    logger.log_chr(offset, access);
+                }
+                read_wrapped(&self.character_rom, offset)
+            }
+            mapper::PpuMapping::Ciram(offset) => read_wrapped(ciram, offset),
+            mapper::PpuMapping::Vram(offset) => read_wrapped(&self.vram, offset),
+            mapper::PpuMapping::Data(data) => data,
+            _ => 0x00,
+        };
+        self.mapper.ppu_fetched(addr);
+        return data;
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8, ciram: &mut [u8]) {
+        let mapping = self.mapper.ppu_write(addr, data);
+        match self.resolve_ppu_mapping(addr, mapping) {
+            mapper::PpuMapping::Ciram(offset) => write_wrapped(ciram, offset, data),
+            mapper::PpuMapping::Vram(offset) => write_wrapped(&mut self.vram, offset, data),
+            mapper::PpuMapping::Chr(offset) if self.character_rom.is_empty() => write_wrapped(&mut self.chr_ram, offset, data),
+            _ => {} // CHR ROM isn't writable
+        }
+    }
+
+    fn resolve_ppu_mapping(&self, addr: u16, mapping: mapper::PpuMapping) -> mapper::PpuMapping {
+        return match mapping {
+            mapper::PpuMapping::Mirrored => mapper::mirror_nametable(self.mirroring(), addr),
+            _ => mapping,
+        }
+    }
+
+    pub fn mirroring(&self) -> mapper::Mirroring {
+        // the extra VRAM on four screen boards overrides whatever the mapper selects
+        if self.header.four_screen {
+            return mapper::Mirroring::FourScreen;
+        }
+        return match self.mapper.mirroring() {
+            Some(mirroring) => mirroring,
+            None => self.header.hardwired_mirroring(),
+        }
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        return self.mapper.irq_pending();
+    }
+
+    pub fn cpu_clock(&mut self) {
+        self.mapper.cpu_clock();
+    }
+
+    pub fn scanline(&mut self) {
+        self.mapper.scanline();
+    }
+
+    // marks a PRG ROM byte without reading it (e.g. the target of an indirect jump)
+    pub fn log_prg_access(&mut self, addr: u16, access: cdl::PrgAccess) {
+        if let mapper::CpuMapping::PrgRom(offset) = self.mapper.cpu_peek(addr) {
+            if let Some(logger) = self.code_data_logger.as_mut() {
+                logger.log_prg(offset, addr, access);
+            }
+        }
+    }
+
+    pub fn start_code_data_logger(&mut self) {
+        if self.code_data_logger.is_none() {
+            self.code_data_logger = Some(cdl::create_code_data_logger(self.program_rom.len(), self.character_rom.len()));
+        }
+    }
+
+    pub fn stop_code_data_logger(&mut self) -> Option<cdl::CodeDataLogger> {
+        return self.code_data_logger.take();
+    }
+
+    pub fn code_data_logger(&self) -> Option<&cdl::CodeDataLogger> {
+        return self.code_data_logger.as_ref();
+    }
+
+    pub fn code_data_logger_mut(&mut self) -> Option<&mut cdl::CodeDataLogger> {
+        return self.code_data_logger.as_mut();
+    }
+
+    // where a cpu address currently lands in PRG ROM, if it's mapped there
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<u32> {
+        return match self.mapper.cpu_peek(addr) {
+            mapper::CpuMapping::PrgRom(offset) => Some(offset % self.program_rom.len() as u32),
+            _ => None,
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        return &self.header;
+    }
+
+    pub fn warnings(&self) -> &[LoadWarning] {
+        return &self.warnings;
+    }
+
+    pub fn game_info(&self) -> Option<&romdb::GameInfo> {
+        return self.game_info.as_ref();
+    }
+
+    // the disk drive, if this is the disk system rather than a cartridge
+    pub fn fds(&self) -> Option<&fds::Fds> {
+        return self.mapper.fds_ref();
+    }
+
+    pub fn fds_mut(&mut self) -> Option<&mut fds::Fds> {
+        return self.mapper.fds();
+    }
+
+    /* Battery-backed PRG RAM
+     *
+     * Boards with a battery keep PRG RAM alive while the console is off.  The
+     * whole of PRG RAM is kept in a .sav file next to the ROM, loaded when the
+     * cartridge is created and written back when it is flushed or dropped.
+     * Disk system games save to the disk instead, so for them the .sav file
+     * holds an IPS patch of everything written to the disk.
+     */
+    pub fn save_filename(&self) -> Option<&str> {
+        return self.save_filename.as_deref();
+    }
+
+    pub fn set_save_filename(&mut self, filename: &str) {
+        self.save_filename = Some(filename.to_string());
+    }
+
+    pub fn load_battery_ram(&mut self) -> Result<(), CartridgeError> {
+        let filename = match self.save_filename.as_ref() {
+            Some(filename) if Path::new(filename).exists() => filename,
+            _ => return Ok(()), // nothing saved yet
+        };
+        let saved = read_file(filename)?;
+        if let Some(fds) = self.mapper.fds() {
+            if let Err(message) = fds.load_diff(&saved) {
+                return Err(CartridgeError::Io { filename: filename.clone(), message });
+            }
+            return Ok(());
+        }
+        let len = usize::min(saved.len(), self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&saved[..len]);
+        self.save_dirty = false;
+        return Ok(());
+    }
+
+    // writes PRG RAM out if the game has changed it since the last flush
+    pub fn flush_battery_ram(&mut self) -> Result<(), CartridgeError> {
+        let disk_dirty = self.mapper.fds_ref().map_or(false, |fds| fds.is_disk_dirty());
+        let filename = match self.save_filename.as_ref() {
+            Some(filename) if self.save_dirty || disk_dirty => filename,
+            _ => return Ok(()),
+        };
+        let data = match self.mapper.fds() {
+            Some(fds) => fds.save_diff(),
+            None => self.prg_ram.clone(),
+        };
+        let result = File::create(filename).and_then(|mut file| file.write_all(&data));
+        if let Err(e) = result {
+            return Err(CartridgeError::Io { filename: filename.clone(), message: e.to_string() });
+        }
+        self.save_dirty = false;
+        return Ok(());
+    }
+}
+
+impl Drop for Cartridge {
+    // last chance to keep the player's save; use flush_battery_ram to see errors
+    fn drop(&mut self) {
+        let _ = self.flush_battery_ram();
     }
 }
 
-struct Header {
-    name: [u8; 4],
-    prg_rom_chunks: u8, // in 16kb units
-    chr_rom_chunks: u8, // in 8 kb units (0 means the board uses CHR RAM)
-    mapper1: u8,
-    mapper2: u8,
-    prg_ram_size: u8,
-    tv_system1: u8,
-    tv_system2: u8,
-    unused: [u8; 5], 
+// banks past the end of a smaller rom wrap around, as the unconnected address lines would
+fn read_wrapped(memory: &[u8], offset: u32) -> u8 {
+    return if memory.is_empty() {
+        0x00
+    } else {
+        memory[offset as usize % memory.len()]
+    }
 }
 
-pub fn create_cartridge_from_file(filename: &str) -> Option<Box<Cartridge>> {
-    let file_buffer: Vec<u8> = match read_rom_file(filename) {
-        Ok(file_buffer) => file_buffer,
-        Err(e) => { 
-            println!("{}", e);
-            return None
+fn write_wrapped(memory: &mut [u8], offset: u32, data: u8) {
+    if !memory.is_empty() {
+        let len = memory.len();
+        memory[offset as usize % len] = data;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderFormat {
+    Unif,        // built from UNIF chunks rather than read from a header
+    Fds,         // a Famicom Disk System disk image
+    ArchaicINes, // bytes 7-15 are unreliable, only the lower mapper nybble is used
+    INes,
+    Nes20,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    Extended(u8), // NES 2.0 extended console type from byte 13
+}
+
+/*
+ * iNES header: https://wiki.nesdev.com/w/index.php/INES
+ * NES 2.0 header: https://wiki.nesdev.com/w/index.php/NES_2.0
+ *
+ * Both formats share bytes 0-7; NES 2.0 marks itself with bits 2-3 of byte 7
+ * and uses bytes 8-15 for the larger mapper number, ROM and RAM sizes, timing
+ * and console details that iNES had to guess at.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub name: [u8; 4],
+    pub format: HeaderFormat,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mapper_id: u16,
+    pub submapper: u8,
+    pub vertical_mirroring: bool,
+    pub four_screen: bool,
+    pub single_screen: Option<mapper::Mirroring>, // only UNIF can hardwire this
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    pub expansion_device: u8,
+}
+
+impl Header {
+    pub fn hardwired_mirroring(&self) -> mapper::Mirroring {
+        return if self.four_screen {
+            mapper::Mirroring::FourScreen
+        } else if let Some(single_screen) = self.single_screen {
+            single_screen
+        } else if self.vertical_mirroring {
+            mapper::Mirroring::Vertical
+        } else {
+            mapper::Mirroring::Horizontal
         }
+    }
+}
+
+// something suspicious about a ROM that loaded anyway
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadWarning {
+    // garbage such as "DiskDude!" in bytes 7-15 would have changed the mapper id
+    DirtyHeader { text: String, ignored_mapper_id: u16 },
+    // the game database knows better than the header
+    HeaderCorrected { title: String },
+}
+
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            LoadWarning::DirtyHeader { text, ignored_mapper_id } => {
+                write!(f, "Header bytes 7-15 contain garbage (\"{}\"), ignoring the upper mapper nybble (would have been mapper {})", text, ignored_mapper_id)
+            }
+            LoadWarning::HeaderCorrected { title } => write!(f, "Header doesn't match the database entry for {}, using the database", title),
+        }
+    }
+}
+
+// why a ROM couldn't be loaded
+#[derive(Debug, Clone, PartialEq)]
+pub enum CartridgeError {
+    Io { filename: String, message: String },
+    BadMagic,
+    TruncatedHeader { size: usize },
+    TruncatedTrainer { found: usize },
+    TruncatedPrgRom { expected: usize, found: usize },
+    TruncatedChrRom { expected: usize, found: usize },
+    UnsupportedMapper { mapper_id: u16, submapper: u8 },
+    UnsupportedBoard(String),
+    UnsupportedFormat(String),
+    TruncatedChunk { id: String, expected: usize, found: usize },
+    MissingChunk(String),
+    TruncatedDisk { expected: usize, found: usize },
+    BadBios { size: usize },
+    BadPatch { filename: String, message: String },
+    BadArchive { filename: String, message: String },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            CartridgeError::Io { filename, message } => write!(f, "Unable to read '{}': {}", filename, message),
+            CartridgeError::BadMagic => write!(f, "Not an iNES ROM (missing \"NES\\x1A\" signature)"),
+            CartridgeError::TruncatedHeader { size } => write!(f, "File is too short for an iNES header ({} bytes)", size),
+            CartridgeError::TruncatedTrainer { found } => write!(f, "Trainer is truncated: expected {} bytes, found {}", TRAINER_SIZE, found),
+            CartridgeError::TruncatedPrgRom { expected, found } => write!(f, "PRG ROM is truncated: expected {} bytes, found {}", expected, found),
+            CartridgeError::TruncatedChrRom { expected, found } => write!(f, "CHR ROM is truncated: expected {} bytes, found {}", expected, found),
+            CartridgeError::UnsupportedMapper { mapper_id, submapper } => write!(f, "Mapper {} (submapper {}) is not supported", mapper_id, submapper),
+            CartridgeError::UnsupportedBoard(board) => write!(f, "Board '{}' is not supported", board),
+            CartridgeError::UnsupportedFormat(format) => write!(f, "{} files are not supported", format),
+            CartridgeError::TruncatedChunk { id, expected, found } => write!(f, "{} chunk is truncated: expected {} bytes, found {}", id, expected, found),
+            CartridgeError::MissingChunk(id) => write!(f, "Required {} chunk is missing", id),
+            CartridgeError::TruncatedDisk { expected, found } => write!(f, "Disk side is truncated: expected {} bytes, found {}", expected, found),
+            CartridgeError::BadPatch { filename, message } => write!(f, "Unable to apply '{}': {}", filename, message),
+            CartridgeError::BadArchive { filename, message } => write!(f, "Unable to open '{}': {}", filename, message),
+            CartridgeError::BadBios { size } => write!(f, "FDS BIOS should be {} bytes, found {}", fds::FDS_BIOS_SIZE, size),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+// database is checked for games whose iNES headers need correcting, and may be empty
+pub fn create_cartridge_from_file(filename: &str, database: &[romdb::GameInfo]) -> Result<Box<Cartridge>, CartridgeError> {
+    let file_buffer = read_rom_file(filename)?;
+    return create_cartridge_with_save(&file_buffer, filename, database);
+}
+
+// a game inside a zip with several in it, e.g. a set of revisions or translations.
+// Each saves separately: games.zip and "Game (Rev 1).nes" save to "games - Game (Rev 1).sav"
+pub fn create_cartridge_from_archive(filename: &str, entry: &str, database: &[romdb::GameInfo]) -> Result<Box<Cartridge>, CartridgeError> {
+    let file_buffer = read_rom_file_entry(filename, Some(entry))?;
+    let mut cartridge = create_cartridge_from_bytes(&file_buffer, database)?;
+    if cartridge.header.has_battery {
+        cartridge.set_save_filename(&save_filename_for_archive_entry(filename, entry));
+        cartridge.load_battery_ram()?;
+    }
+    return Ok(cartridge);
+}
+
+// applies an IPS, BPS or UPS patch in memory, leaving the ROM file untouched.
+// The patched game saves next to the patch, since its saves may not suit the original
+pub fn create_patched_cartridge_from_file(filename: &str, patch_filename: &str, database: &[romdb::GameInfo]) -> Result<Box<Cartridge>, CartridgeError> {
+    let file_buffer = read_rom_file(filename)?;
+    let patch_buffer = read_rom_file(patch_filename)?;
+    let patched = match patch::apply_patch(&file_buffer, &patch_buffer) {
+        Ok(patched) => patched,
+        Err(message) => return Err(CartridgeError::BadPatch { filename: patch_filename.to_string(), message }),
+    };
+    return create_cartridge_with_save(&patched, patch_filename, database);
+}
+
+fn create_cartridge_with_save(file_buffer: &[u8], filename: &str, database: &[romdb::GameInfo]) -> Result<Box<Cartridge>, CartridgeError> {
+    let mut cartridge = create_cartridge_from_bytes(file_buffer, database)?;
+    if cartridge.header.has_battery {
+        cartridge.set_save_filename(&save_filename_for_rom(filename));
+        cartridge.load_battery_ram()?;
+    }
+    return Ok(cartridge);
+}
+
+// game.nes, game.nes.gz and game.zip all save to game.sav
+pub fn save_filename_for_rom(filename: &str) -> String {
+    return format!("{}.sav", rom_base_name(filename));
+}
+
+pub fn save_filename_for_archive_entry(filename: &str, entry: &str) -> String {
+    let entry_stem = Path::new(entry).file_stem().map_or(entry.into(), |stem| stem.to_string_lossy());
+    return format!("{} - {}.sav", rom_base_name(filename), entry_stem);
+}
+
+// the filename without its ROM or archive extension
+fn rom_base_name(filename: &str) -> String {
+    let path = Path::new(filename);
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    let base = match extension.as_deref() {
+        Some("gz") => path.with_extension("").with_extension(""), // game.nes.gz
+        _ => path.with_extension(""),
     };
-    let header = read_header(&file_buffer);
-    // the mapper id is (upper nybble of mapper2 | lower nybble of mapper1)
-    let mapper_id = ((header.mapper2 >> 4) << 4) | (header.mapper1 >> 4);
-    let mapper = mapper::create_mapper(mapper_id, header.prg_rom_chunks, header.chr_rom_chunks);
+    return base.to_string_lossy().into_owned();
+}
+
+pub fn create_cartridge_from_bytes(file_buffer: &[u8], database: &[romdb::GameInfo]) -> Result<Box<Cartridge>, CartridgeError> {
+    if file_buffer.starts_with(b"UNIF") {
+        return create_cartridge_from_unif(file_buffer, database);
+    }
+    let mut header = read_header(file_buffer)?;
+    let mut warnings = header_warnings(&file_buffer[0..16], &header);
 
-    let has_trainer_block = header.mapper1 & 0x04 > 1;
-    let prg_starting_index = if has_trainer_block { 528 } else { 16 };
+    // the trainer sits between the header and PRG ROM
+    let trainer = if header.has_trainer {
+        if file_buffer.len() < 16 + TRAINER_SIZE {
+            return Err(CartridgeError::TruncatedTrainer { found: file_buffer.len() - 16 });
+        }
+        Some(&file_buffer[16..16 + TRAINER_SIZE])
+    } else {
+        None
+    };
 
     // read in program memory and character memory
-    let mut program_rom: Vec<u8> = Vec::new();
-    let mut character_rom: Vec<u8> = Vec::new();
-    let file_type: u8 = 1; // three types of file types.  only concerned with 1 so far
-    match file_type {
-        0 => { /* placeholder */ }
-        1 => {
-            let program_rom_len = (header.prg_rom_chunks as usize) * PROGRAM_ROM_CHUNK_SIZE;
-            let prg_ending_index = prg_starting_index + program_rom_len;
-            program_rom = file_buffer[prg_starting_index..prg_ending_index].try_into().unwrap();
+    let prg_starting_index = if header.has_trainer { 16 + TRAINER_SIZE } else { 16 };
+    // NES 2.0 exponent sizes can be far beyond anything that fits in memory
+    let prg_ending_index = match prg_starting_index.checked_add(header.prg_rom_size) {
+        Some(end) if end <= file_buffer.len() => end,
+        _ => return Err(CartridgeError::TruncatedPrgRom {
+            expected: header.prg_rom_size,
+            found: file_buffer.len().saturating_sub(prg_starting_index),
+        }),
+    };
+    let program_rom = file_buffer[prg_starting_index..prg_ending_index].to_vec();
+
+    let chr_ending_index = match prg_ending_index.checked_add(header.chr_rom_size) {
+        Some(end) if end <= file_buffer.len() => end,
+        _ => return Err(CartridgeError::TruncatedChrRom {
+            expected: header.chr_rom_size,
+            found: file_buffer.len() - prg_ending_index,
+        }),
+    };
+    let character_rom = file_buffer[prg_ending_index..chr_ending_index].to_vec();
 
-            let character_rom_len = (header.chr_rom_chunks as usize) * CHARACTER_ROM_CHUNK_SIZE;
-            let chr_ending_index = prg_ending_index + character_rom_len;
-            character_rom = file_buffer[prg_ending_index..chr_ending_index].try_into().unwrap();
+    // NES 2.0 headers are trusted, older ones are corrected from the database
+    let game_info = romdb::lookup_in(database, &file_buffer[prg_starting_index..chr_ending_index]).cloned();
+    if let Some(game) = game_info.as_ref() {
+        if header.format != HeaderFormat::Nes20 && correct_header(&mut header, game) {
+            warnings.push(LoadWarning::HeaderCorrected { title: game.title.clone() });
         }
-        2 => { /* placeholder */ }
-        _ => { panic!("ERROR: Unrecognized file type '{}' in create_cartridge_from_file().", file_type)}
     }
+    let mapper = create_header_mapper(&header)?;
+    return assemble_cartridge(header, mapper, program_rom, character_rom, trainer, warnings, game_info);
+}
+
+// UNIF names the board instead of a mapper, and has no header to correct
+fn create_cartridge_from_unif(file_buffer: &[u8], database: &[romdb::GameInfo]) -> Result<Box<Cartridge>, CartridgeError> {
+    let image = unif::parse_unif(file_buffer)?;
+    let (mapper_id, submapper) = match unif::board_mapper(&image.board) {
+        Some(mapper) => mapper,
+        None => return Err(CartridgeError::UnsupportedBoard(image.board)),
+    };
+    let expansion_device = match image.controllers.unwrap_or(0) {
+        ctrl if ctrl & 0x02 > 0 => 0x08, // Zapper
+        ctrl if ctrl & 0x10 > 0 => 0x0B, // Power Pad
+        ctrl if ctrl & 0x08 > 0 => 0x0F, // Arkanoid controller
+        ctrl if ctrl & 0x20 > 0 => 0x02, // Four Score
+        ctrl if ctrl & 0x01 > 0 => 0x01, // standard controllers
+        _ => 0x00,
+    };
+    let header = Header {
+        name: [0x55, 0x4E, 0x49, 0x46],
+        format: HeaderFormat::Unif,
+        prg_rom_size: image.prg_rom.len(),
+        chr_rom_size: image.chr_rom.len(),
+        mapper_id,
+        submapper,
+        vertical_mirroring: image.mirroring == Some(1),
+        four_screen: image.mirroring == Some(4),
+        single_screen: match image.mirroring {
+            Some(2) => Some(mapper::Mirroring::SingleScreenLower),
+            Some(3) => Some(mapper::Mirroring::SingleScreenUpper),
+            _ => None,
+        },
+        has_battery: image.has_battery,
+        has_trainer: false,
+        prg_ram_size: if image.has_battery { 0 } else { PRG_RAM_SIZE },
+        prg_nvram_size: if image.has_battery { PRG_RAM_SIZE } else { 0 },
+        chr_ram_size: if image.chr_rom.is_empty() { CHARACTER_ROM_CHUNK_SIZE } else { 0 },
+        chr_nvram_size: 0,
+        timing: match image.tv_system {
+            Some(1) => Timing::Pal,
+            Some(2) => Timing::MultiRegion,
+            _ => Timing::Ntsc,
+        },
+        console_type: ConsoleType::Nes,
+        misc_roms: 0,
+        expansion_device,
+    };
+    let mut rom = image.prg_rom.clone();
+    rom.extend_from_slice(&image.chr_rom);
+    let game_info = romdb::lookup_in(database, &rom).cloned();
+    let mapper = create_header_mapper(&header)?;
+    return assemble_cartridge(header, mapper, image.prg_rom, image.chr_rom, None, Vec::new(), game_info);
+}
+
+// the disk system's RAM adapter stands in for a cartridge, with the BIOS as its PRG ROM
+pub fn create_fds_cartridge(disk_image: &[u8], bios: &[u8]) -> Result<Box<Cartridge>, CartridgeError> {
+    if bios.len() != fds::FDS_BIOS_SIZE {
+        return Err(CartridgeError::BadBios { size: bios.len() });
+    }
+    let sides = fds::parse_disk_image(disk_image)?;
+    let header = Header {
+        name: [0x46, 0x44, 0x53, 0x1A],
+        format: HeaderFormat::Fds,
+        prg_rom_size: bios.len(),
+        chr_rom_size: 0,
+        mapper_id: 20,
+        submapper: 0,
+        vertical_mirroring: false,
+        four_screen: false,
+        single_screen: None,
+        has_battery: false,
+        has_trainer: false,
+        prg_ram_size: fds::FDS_PRG_RAM_SIZE,
+        prg_nvram_size: 0,
+        chr_ram_size: CHARACTER_ROM_CHUNK_SIZE,
+        chr_nvram_size: 0,
+        timing: Timing::Ntsc,
+        console_type: ConsoleType::Nes,
+        misc_roms: 0,
+        expansion_device: 0,
+    };
+    let mapper = Box::new(fds::create_fds(sides));
+    return assemble_cartridge(header, mapper, bios.to_vec(), Vec::new(), None, Vec::new(), None);
+}
+
+// disk writes are kept as a diff in game.sav rather than changing the image
+pub fn create_fds_cartridge_from_files(disk_filename: &str, bios_filename: &str) -> Result<Box<Cartridge>, CartridgeError> {
+    let disk_image = read_rom_file(disk_filename)?;
+    let bios = read_rom_file(bios_filename)?;
+    let mut cartridge = create_fds_cartridge(&disk_image, &bios)?;
+    cartridge.set_save_filename(&save_filename_for_rom(disk_filename));
+    cartridge.load_battery_ram()?;
+    return Ok(cartridge);
+}
 
-    return Some(Box::new(Cartridge {
+fn create_header_mapper(header: &Header) -> Result<Box<dyn mapper::Mapper>, CartridgeError> {
+    return match mapper::create_mapper(&mapper_config(header)) {
+        Some(mapper) => Ok(mapper),
+        None => Err(CartridgeError::UnsupportedMapper { mapper_id: header.mapper_id, submapper: header.submapper }),
+    }
+}
+
+fn mapper_config(header: &Header) -> mapper::MapperConfig {
+    return mapper::MapperConfig {
+        mapper_id: header.mapper_id,
+        submapper: header.submapper,
+        prg_rom_size: header.prg_rom_size,
+        chr_rom_size: header.chr_rom_size,
+        // the trainer needs somewhere to live even if the header asks for no PRG RAM
+        prg_ram_size: if header.has_trainer {
+            usize::max(header.prg_ram_size + header.prg_nvram_size, PRG_RAM_SIZE)
+        } else {
+            header.prg_ram_size + header.prg_nvram_size
+        },
+        chr_ram_size: header.chr_ram_size + header.chr_nvram_size,
+    }
+}
+
+fn assemble_cartridge(
+    header: Header,
+    mapper: Box<dyn mapper::Mapper>,
+    program_rom: Vec<u8>,
+    character_rom: Vec<u8>,
+    trainer: Option<&[u8]>,
+    warnings: Vec<LoadWarning>,
+    game_info: Option<romdb::GameInfo>,
+) -> Result<Box<Cartridge>, CartridgeError> {
+    let mapper_config = mapper_config(&header);
+    let vram_size = if header.four_screen { FOUR_SCREEN_VRAM_SIZE } else { 0 };
+    let mut prg_ram = vec![0; mapper_config.prg_ram_size];
+    if let Some(trainer) = trainer {
+        prg_ram[TRAINER_OFFSET..TRAINER_OFFSET + TRAINER_SIZE].copy_from_slice(trainer);
+    }
+    return Ok(Box::new(Cartridge {
         header,
         mapper,
         program_rom,
         character_rom,
+        chr_ram: vec![0; mapper_config.chr_ram_size],
+        prg_ram,
+        vram: vec![0; vram_size],
+        code_data_logger: None,
+        warnings,
+        game_info,
+        save_filename: None,
+        save_dirty: false,
     }))
 }
 
-fn read_rom_file(filename: &str) -> Result<Vec<u8>, String> {
+// overwrites what the header got wrong, returning whether anything changed
+fn correct_header(header: &mut Header, game: &romdb::GameInfo) -> bool {
+    let original = header.clone();
+    header.mapper_id = game.mapper_id;
+    header.submapper = game.submapper;
+    if let Some(mirroring) = game.mirroring {
+        header.vertical_mirroring = mirroring == mapper::Mirroring::Vertical;
+        header.four_screen = mirroring == mapper::Mirroring::FourScreen;
+        header.single_screen = None;
+    }
+    header.has_battery = game.has_battery;
+    header.prg_ram_size = game.prg_ram_size;
+    header.prg_nvram_size = game.prg_nvram_size;
+    if header.chr_rom_size == 0 {
+        header.chr_ram_size = game.chr_ram_size;
+    }
+    header.timing = game.timing;
+    return *header != original;
+}
+
+// zip and gzip files are unpacked, taking the first ROM in a zip
+fn read_rom_file(filename: &str) -> Result<Vec<u8>, CartridgeError> {
+    return read_rom_file_entry(filename, None);
+}
+
+fn read_rom_file_entry(filename: &str, entry: Option<&str>) -> Result<Vec<u8>, CartridgeError> {
+    let file_buffer = read_file(filename)?;
+    if !archive::is_archive(&file_buffer) {
+        return Ok(file_buffer);
+    }
+    return match archive::extract(&file_buffer, entry) {
+        Ok(rom) => Ok(rom),
+        Err(message) => Err(CartridgeError::BadArchive { filename: filename.to_string(), message }),
+    };
+}
+
+fn read_file(filename: &str) -> Result<Vec<u8>, CartridgeError> {
     let mut file_buffer = Vec::new();
     let mut file = match File::open(filename) {
         Ok(file) => file,
-        Err(_) => {
-            let error_message = format!("ERROR: File not found: '{}'", filename);
-            return Err(error_message);
-        }
+        Err(e) => return Err(CartridgeError::Io { filename: filename.to_string(), message: e.to_string() }),
     };
-    match file.read_to_end(&mut file_buffer) {
-        Ok(_) => {}
-        Err(e) => {
-            let error_message = format!("{}", e);
-            return Err(error_message);
-        }
+    if let Err(e) = file.read_to_end(&mut file_buffer) {
+        return Err(CartridgeError::Io { filename: filename.to_string(), message: e.to_string() });
     }
     return Ok(file_buffer);
 }
 
-fn read_header(file_buffer: &Vec<u8>) -> Header {
+fn read_header(file_buffer: &[u8]) -> Result<Header, CartridgeError> {
+    if fds::is_disk_image(file_buffer) {
+        // disks need the BIOS too, see create_fds_cartridge
+        return Err(CartridgeError::UnsupportedFormat("Famicom Disk System".to_string()));
+    }
+    if !file_buffer.starts_with(b"NES\x1A") {
+        return Err(CartridgeError::BadMagic);
+    }
+    if file_buffer.len() < 16 {
+        return Err(CartridgeError::TruncatedHeader { size: file_buffer.len() });
+    }
+    let bytes = &file_buffer[0..16];
+    return Ok(match header_format(bytes) {
+        HeaderFormat::Nes20 => read_nes20_header(bytes),
+        HeaderFormat::INes => read_ines_header(bytes),
+        _ => read_archaic_ines_header(bytes),
+    });
+}
+
+// https://wiki.nesdev.com/w/index.php/INES#Variant_comparison
+fn header_format(bytes: &[u8]) -> HeaderFormat {
+    return match bytes[7] & 0x0C {
+        0x08 => HeaderFormat::Nes20,
+        0x00 if bytes[12..16].iter().all(|&byte| byte == 0) => HeaderFormat::INes,
+        _ => HeaderFormat::ArchaicINes,
+    }
+}
+
+// old dumpers wrote their name over bytes 7-15, so only trust bytes 4-6
+fn read_archaic_ines_header(bytes: &[u8]) -> Header {
+    let mut cleaned = [0u8; 16];
+    cleaned[0..7].copy_from_slice(&bytes[0..7]);
+    let mut header = read_ines_header(&cleaned);
+    header.format = HeaderFormat::ArchaicINes;
+    return header;
+}
+
+fn header_warnings(bytes: &[u8], header: &Header) -> Vec<LoadWarning> {
+    let mut warnings = Vec::new();
+    if header.format == HeaderFormat::ArchaicINes && bytes[7..16].iter().any(|&byte| byte != 0) {
+        let text: String = bytes[7..16].iter()
+            .map(|&byte| if byte.is_ascii_graphic() { byte as char } else { '.' })
+            .collect();
+        warnings.push(LoadWarning::DirtyHeader {
+            text,
+            ignored_mapper_id: ((bytes[7] & 0xF0) | (bytes[6] >> 4)) as u16,
+        });
+    }
+    return warnings;
+}
+
+fn read_ines_header(bytes: &[u8]) -> Header {
+    let has_battery = bytes[6] & 0x02 > 0;
+    let chr_rom_size = bytes[5] as usize * CHARACTER_ROM_CHUNK_SIZE;
+    // byte 8 counts PRG RAM in 8kb units, with 0 meaning 8kb for compatibility
+    let prg_ram_size = usize::max(bytes[8] as usize, 1) * PRG_RAM_SIZE;
+    let console_type = if bytes[7] & 0x01 > 0 {
+        ConsoleType::VsSystem { ppu_type: 0, hardware_type: 0 }
+    } else if bytes[7] & 0x02 > 0 {
+        ConsoleType::Playchoice10
+    } else {
+        ConsoleType::Nes
+    };
+    return Header {
+        name: bytes[0..4].try_into().unwrap(),
+        format: HeaderFormat::INes,
+        prg_rom_size: bytes[4] as usize * PROGRAM_ROM_CHUNK_SIZE,
+        chr_rom_size,
+        // the mapper id is (upper nybble of byte 7 | upper nybble of byte 6)
+        mapper_id: ((bytes[7] & 0xF0) | (bytes[6] >> 4)) as u16,
+        submapper: 0,
+        vertical_mirroring: bytes[6] & 0x01 > 0,
+        four_screen: bytes[6] & 0x08 > 0,
+        single_screen: None,
+        has_battery,
+        has_trainer: bytes[6] & 0x04 > 0,
+        prg_ram_size: if has_battery { 0 } else { prg_ram_size },
+        prg_nvram_size: if has_battery { prg_ram_size } else { 0 },
+        chr_ram_size: if chr_rom_size == 0 { CHARACTER_ROM_CHUNK_SIZE } else { 0 },
+        chr_nvram_size: 0,
+        timing: if bytes[9] & 0x01 > 0 { Timing::Pal } else { Timing::Ntsc },
+        console_type,
+        misc_roms: 0,
+        expansion_device: 0,
+    }
+}
+
+fn read_nes20_header(bytes: &[u8]) -> Header {
+    let console_type = match bytes[7] & 0x03 {
+        0 => ConsoleType::Nes,
+        1 => ConsoleType::VsSystem { ppu_type: bytes[13] & 0x0F, hardware_type: bytes[13] >> 4 },
+        2 => ConsoleType::Playchoice10,
+        _ => ConsoleType::Extended(bytes[13] & 0x0F),
+    };
+    let timing = match bytes[12] & 0x03 {
+        0 => Timing::Ntsc,
+        1 => Timing::Pal,
+        2 => Timing::MultiRegion,
+        _ => Timing::Dendy,
+    };
     return Header {
-        name: file_buffer[0..4].try_into().unwrap(),
-        prg_rom_chunks: file_buffer[4],
-        chr_rom_chunks: file_buffer[5],
-        mapper1: file_buffer[6],
-        mapper2: file_buffer[7],
-        prg_ram_size: file_buffer[8],
-        tv_system1: file_buffer[9],
-        tv_system2: file_buffer[10],
-        unused: file_buffer[11..16].try_into().unwrap(),
+        name: bytes[0..4].try_into().unwrap(),
+        format: HeaderFormat::Nes20,
+        prg_rom_size: nes20_rom_size(bytes[4], bytes[9] & 0x0F, PROGRAM_ROM_CHUNK_SIZE),
+        chr_rom_size: nes20_rom_size(bytes[5], bytes[9] >> 4, CHARACTER_ROM_CHUNK_SIZE),
+        mapper_id: (((bytes[8] & 0x0F) as u16) << 8) | (bytes[7] & 0xF0) as u16 | (bytes[6] >> 4) as u16,
+        submapper: bytes[8] >> 4,
+        vertical_mirroring: bytes[6] & 0x01 > 0,
+        four_screen: bytes[6] & 0x08 > 0,
+        single_screen: None,
+        has_battery: bytes[6] & 0x02 > 0,
+        has_trainer: bytes[6] & 0x04 > 0,
+        prg_ram_size: nes20_ram_size(bytes[10] & 0x0F),
+        prg_nvram_size: nes20_ram_size(bytes[10] >> 4),
+        chr_ram_size: nes20_ram_size(bytes[11] & 0x0F),
+        chr_nvram_size: nes20_ram_size(bytes[11] >> 4),
+        timing,
+        console_type,
+        misc_roms: bytes[14] & 0x03,
+        expansion_device: bytes[15] & 0x3F,
+    }
+}
+
+// a size MSB nybble of $F means the LSB byte is EEEEEEMM: 2^E * (MM*2+1) bytes
+fn nes20_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = ((lsb & 0x03) * 2 + 1) as usize;
+        return 2usize.saturating_pow(exponent).saturating_mul(multiplier);
     }
+    return (((msb as usize) << 8) | lsb as usize) * unit;
+}
+
+// RAM sizes are shift counts: 64 << n bytes, with 0 meaning none
+fn nes20_ram_size(shift: u8) -> usize {
+    return if shift == 0 { 0 } else { 64 << shift };
 }
 
 #[cfg(test)]
@@ -120,23 +857,24 @@ mod tests {
     fn verify_header_read() {
         let filename = "./test_files/nestest.nes";
         let file_buffer = read_rom_file(filename).unwrap();
-        let header: Header = read_header(&file_buffer);
+        let header: Header = read_header(&file_buffer).unwrap();
 
         assert_eq!(header.name, [0x4E, 0x45, 0x53, 0x1A]);
-        assert_eq!(header.prg_rom_chunks, 0x01);
-        assert_eq!(header.chr_rom_chunks, 0x01);
-        assert_eq!(header.mapper1, 0x00);
-        assert_eq!(header.mapper2, 0x00);
-        assert_eq!(header.prg_ram_size, 0x00);
-        assert_eq!(header.tv_system1, 0x00);
-        assert_eq!(header.tv_system2, 0x00);
-        assert_eq!(header.unused, [0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.prg_rom_size, PROGRAM_ROM_CHUNK_SIZE);
+        assert_eq!(header.chr_rom_size, CHARACTER_ROM_CHUNK_SIZE);
+        assert_eq!(header.mapper_id, 0);
+        assert_eq!(header.submapper, 0);
+        assert_eq!(header.prg_ram_size, PRG_RAM_SIZE);
+        assert_eq!(header.timing, Timing::Ntsc);
+        assert_eq!(header.console_type, ConsoleType::Nes);
+        assert!(!header.has_trainer);
     }
 
     #[test]
     fn verify_program_rom_read() {
         let filename = "./test_files/nestest.nes";
-        let cartridge: Box<Cartridge> = create_cartridge_from_file(filename).unwrap();
+        let cartridge: Box<Cartridge> = create_cartridge_from_file(filename, &[]).unwrap();
 
         assert_eq!(cartridge.program_rom.len(), PROGRAM_ROM_CHUNK_SIZE);
         assert_eq!(cartridge.program_rom.first(), Some(&0x4C));
@@ -146,10 +884,402 @@ mod tests {
     #[test]
     fn verify_character_rom_read() {
         let filename = "./test_files/nestest.nes";
-        let cartridge: Box<Cartridge> = create_cartridge_from_file(filename).unwrap();
+        let cartridge: Box<Cartridge> = create_cartridge_from_file(filename, &[]).unwrap();
 
         assert_eq!(cartridge.character_rom.len(), CHARACTER_ROM_CHUNK_SIZE);
         assert_eq!(cartridge.character_rom.first(), Some(&0x00));
         assert_eq!(cartridge.character_rom.last(), Some(&0x00));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn prg_ram_and_nametables() {
+        let filename = "./test_files/nestest.nes";
+        let mut cartridge: Box<Cartridge> = create_cartridge_from_file(filename, &[]).unwrap();
+        cartridge.write(0x6123, 0xAB);
+        assert_eq!(cartridge.read(0x6123, cdl::PrgAccess::Data), 0xAB);
+        cartridge.write(0x8000, 0xAB); // ROM isn't writable
+        assert_eq!(cartridge.peek(0x8000), 0x4C);
+
+        // nestest is horizontally mirrored
+        assert_eq!(cartridge.mirroring(), mapper::Mirroring::Horizontal);
+        let mut ciram = [0u8; 2048];
+        cartridge.ppu_write(0x2405, 0x77, &mut ciram);
+        assert_eq!(ciram[0x005], 0x77);
+        assert_eq!(cartridge.ppu_read(0x2005, &ciram, cdl::ChrAccess::Read), 0x77);
+        assert_eq!(cartridge.ppu_read(0x2805, &ciram, cdl::ChrAccess::Read), 0x00);
+    }
+
+    #[test]
+    fn code_data_logging() {
+        let filename = "./test_files/nestest.nes";
+        let mut cartridge: Box<Cartridge> = create_cartridge_from_file(filename, &[]).unwrap();
+        cartridge.read(0xC000, cdl::PrgAccess::Code);
+        cartridge.start_code_data_logger();
+        cartridge.read(0xC000, cdl::PrgAccess::Code);
+        cartridge.read(0xC001, cdl::PrgAccess::Operand);
+        cartridge.read(0x8010, cdl::PrgAccess::Data);
+        cartridge.peek(0x8020);
+        cartridge.ppu_read(0x0100, &[0; 2048], cdl::ChrAccess::Rendered);
+        let logger = cartridge.code_data_logger().unwrap();
+        assert_eq!(logger.prg_flags(0x0000), cdl::PRG_CODE | 0x08);
+        assert_eq!(logger.prg_flags(0x0001), cdl::PRG_OPERAND | 0x08);
+        assert_eq!(logger.prg_flags(0x0010), cdl::PRG_DATA);
+        assert_eq!(logger.prg_flags(0x0020), 0x00);
+        assert_eq!(logger.chr_flags(0x0100), cdl::CHR_RENDERED);
+        assert_eq!(logger.to_fceux_bytes().len(), PROGRAM_ROM_CHUNK_SIZE + CHARACTER_ROM_CHUNK_SIZE);
+    }
+
+    // an iNES image with blank PRG and CHR
+    fn ines_image(prg_rom_chunks: u8, chr_rom_chunks: u8, mapper_id: u8) -> Vec<u8> {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, prg_rom_chunks, chr_rom_chunks, mapper_id << 4, mapper_id & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+        image.resize(16 + prg_rom_chunks as usize * PROGRAM_ROM_CHUNK_SIZE + chr_rom_chunks as usize * CHARACTER_ROM_CHUNK_SIZE, 0);
+        return image;
+    }
+
+    #[test]
+    fn load_errors() {
+        let error = create_cartridge_from_file("./test_files/missing.nes", &[]).err().unwrap();
+        assert!(matches!(error, CartridgeError::Io { .. }));
+        assert!(error.to_string().starts_with("Unable to read './test_files/missing.nes'"));
+
+        assert_eq!(create_cartridge_from_bytes(b"PK\x03\x04", &[]).err(), Some(CartridgeError::BadMagic));
+        assert_eq!(create_cartridge_from_bytes(b"NES\x1A\x01", &[]).err(), Some(CartridgeError::TruncatedHeader { size: 5 }));
+        assert_eq!(create_cartridge_from_bytes(b"UNIF", &[]).err(), Some(CartridgeError::TruncatedHeader { size: 4 }));
+
+        let image = ines_image(2, 1, 0);
+        assert_eq!(create_cartridge_from_bytes(&image[..0x6010], &[]).err(), Some(CartridgeError::TruncatedPrgRom { expected: 0x8000, found: 0x6000 }));
+        assert_eq!(create_cartridge_from_bytes(&image[..0x9010], &[]).err(), Some(CartridgeError::TruncatedChrRom { expected: 0x2000, found: 0x1000 }));
+        assert!(create_cartridge_from_bytes(&image, &[]).is_ok());
+
+        // NES 2.0 exponent-multiplier sizes that don't fit in a usize
+        let mut huge = ines_image(2, 1, 0);
+        huge[7] = 0x08;
+        huge[4] = 0xFF;
+        huge[9] = 0x0F;
+        assert_eq!(create_cartridge_from_bytes(&huge, &[]).err(), Some(CartridgeError::TruncatedPrgRom { expected: usize::MAX, found: 0xA000 }));
+        huge[4] = 0x02;
+        huge[9] = 0xF0;
+        huge[5] = 0xFF;
+        assert_eq!(create_cartridge_from_bytes(&huge, &[]).err(), Some(CartridgeError::TruncatedChrRom { expected: usize::MAX, found: 0x2000 }));
+
+        let error = create_cartridge_from_bytes(&ines_image(2, 1, 255), &[]).err().unwrap();
+        assert_eq!(error, CartridgeError::UnsupportedMapper { mapper_id: 255, submapper: 0 });
+        assert_eq!(error.to_string(), "Mapper 255 (submapper 0) is not supported");
+    }
+
+    #[test]
+    fn nes20_header_read() {
+        let mut image = ines_image(0, 0, 0);
+        image[4] = 0x02;
+        image[6] = 0x12; // battery, mapper low nybble 1
+        image[7] = 0x49; // NES 2.0, Vs. System, mapper middle nybble 4
+        image[8] = 0x31; // submapper 3, mapper high nybble 1
+        image[9] = 0xF0; // CHR size in exponent-multiplier form
+        image[10] = 0x70; // 8k PRG NVRAM
+        image[11] = 0x07; // 8k CHR RAM
+        image[12] = 0x01;
+        image[13] = 0x21;
+        image[14] = 0x02;
+        image[15] = 0x01;
+        image[5] = (13 << 2) | 0x01; // 2^13 * 3 = 24k
+        let header = read_header(&image).unwrap();
+
+        assert_eq!(header.format, HeaderFormat::Nes20);
+        assert_eq!(header.mapper_id, 0x141);
+        assert_eq!(header.submapper, 3);
+        assert_eq!(header.prg_rom_size, 2 * PROGRAM_ROM_CHUNK_SIZE);
+        assert_eq!(header.chr_rom_size, 3 * CHARACTER_ROM_CHUNK_SIZE);
+        assert!(header.has_battery);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert_eq!(header.chr_ram_size, 0x2000);
+        assert_eq!(header.chr_nvram_size, 0);
+        assert_eq!(header.timing, Timing::Pal);
+        assert_eq!(header.console_type, ConsoleType::VsSystem { ppu_type: 1, hardware_type: 2 });
+        assert_eq!(header.misc_roms, 2);
+        assert_eq!(header.expansion_device, 1);
+
+        // without bytes 12-15 the same bytes read as iNES, which only sees the lower two mapper nybbles
+        image[7] &= !0x0C;
+        image[12..16].copy_from_slice(&[0, 0, 0, 0]);
+        let header = read_header(&image).unwrap();
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.mapper_id, 0x41);
+        assert_eq!(header.prg_nvram_size, 0x2000 * 0x31);
+    }
+
+    #[test]
+    fn archaic_header_read() {
+        let mut image = ines_image(2, 1, 1);
+        image[7..16].copy_from_slice(b"DiskDude!");
+        let cartridge = create_cartridge_from_bytes(&image, &[]).unwrap();
+        assert_eq!(cartridge.header().format, HeaderFormat::ArchaicINes);
+        assert_eq!(cartridge.header().mapper_id, 1);
+        assert_eq!(cartridge.header().prg_ram_size, PRG_RAM_SIZE);
+        assert_eq!(cartridge.header().console_type, ConsoleType::Nes);
+        assert_eq!(cartridge.warnings(), &[LoadWarning::DirtyHeader { text: "DiskDude!".to_string(), ignored_mapper_id: 0x41 }]);
+
+        // a dirty byte 7 is enough to be archaic, even with bytes 12-15 clear
+        image[7..16].copy_from_slice(&[0x04, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(read_header(&image).unwrap().format, HeaderFormat::ArchaicINes);
+
+        assert!(create_cartridge_from_bytes(&ines_image(2, 1, 1), &[]).unwrap().warnings().is_empty());
+    }
+
+    #[test]
+    fn trainer_loads_at_7000() {
+        let mut image = ines_image(2, 1, 0);
+        image[6] |= 0x04;
+        let mut trainer = vec![0xEA; TRAINER_SIZE];
+        trainer[0] = 0x4C;
+        trainer[TRAINER_SIZE - 1] = 0x60;
+        image.splice(16..16, trainer);
+        image[16 + TRAINER_SIZE] = 0xA9; // first byte of PRG ROM
+
+        let cartridge = create_cartridge_from_bytes(&image, &[]).unwrap();
+        assert!(cartridge.header().has_trainer);
+        assert_eq!(cartridge.peek(0x6FFF), 0x00);
+        assert_eq!(cartridge.peek(0x7000), 0x4C);
+        assert_eq!(cartridge.peek(0x7001), 0xEA);
+        assert_eq!(cartridge.peek(0x71FF), 0x60);
+        assert_eq!(cartridge.peek(0x7200), 0x00);
+        assert_eq!(cartridge.peek(0x8000), 0xA9);
+
+        assert_eq!(create_cartridge_from_bytes(&image[..0x100], &[]).err(), Some(CartridgeError::TruncatedTrainer { found: 0xF0 }));
+    }
+
+    #[test]
+    fn chr_ram() {
+        // UNROM with no CHR ROM gets 8k of writable pattern memory
+        let mut cartridge = create_cartridge_from_bytes(&ines_image(8, 0, 2), &[]).unwrap();
+        let mut ciram = [0u8; 2048];
+        assert_eq!(cartridge.header().chr_ram_size, CHARACTER_ROM_CHUNK_SIZE);
+        cartridge.ppu_write(0x0000, 0x3C, &mut ciram);
+        cartridge.ppu_write(0x1FFF, 0x7E, &mut ciram);
+        assert_eq!(cartridge.ppu_read(0x0000, &ciram, cdl::ChrAccess::Read), 0x3C);
+        assert_eq!(cartridge.ppu_read(0x1FFF, &ciram, cdl::ChrAccess::Read), 0x7E);
+
+        // NES 2.0 can ask for more, which the mapper banks like CHR ROM
+        let mut image = ines_image(2, 0, 3);
+        image[7] |= 0x08;
+        image[11] = 0x09; // 32k
+        let mut cartridge = create_cartridge_from_bytes(&image, &[]).unwrap();
+        cartridge.ppu_write(0x0123, 0x11, &mut ciram);
+        cartridge.write(0x8000, 0x02);
+        assert_eq!(cartridge.ppu_read(0x0123, &ciram, cdl::ChrAccess::Read), 0x00);
+        cartridge.ppu_write(0x0123, 0x22, &mut ciram);
+        cartridge.write(0x8000, 0x00);
+        assert_eq!(cartridge.ppu_read(0x0123, &ciram, cdl::ChrAccess::Read), 0x11);
+        cartridge.write(0x8000, 0x06); // bank 6 wraps to bank 2
+        assert_eq!(cartridge.ppu_read(0x0123, &ciram, cdl::ChrAccess::Read), 0x22);
+
+        // CHR ROM stays read only
+        let mut cartridge = create_cartridge_from_file("./test_files/nestest.nes", &[]).unwrap();
+        cartridge.ppu_write(0x0000, 0xFF, &mut ciram);
+        assert_eq!(cartridge.ppu_read(0x0000, &ciram, cdl::ChrAccess::Read), 0x00);
+    }
+
+    #[test]
+    fn battery_backed_prg_ram() {
+        let mut image = ines_image(2, 1, 0);
+        image[6] |= 0x02;
+        let rom_filename = "./log/battery_backed_prg_ram.nes";
+        let save_filename = "./log/battery_backed_prg_ram.sav";
+        std::fs::create_dir_all("./log").unwrap();
+        std::fs::write(rom_filename, &image).unwrap();
+        let _ = std::fs::remove_file(save_filename);
+
+        let mut cartridge = create_cartridge_from_file(rom_filename, &[]).unwrap();
+        assert_eq!(cartridge.save_filename(), Some(save_filename));
+        cartridge.flush_battery_ram().unwrap();
+        assert!(!Path::new(save_filename).exists()); // nothing written yet
+        cartridge.write(0x6000, 0x5A);
+        cartridge.write(0x7FFF, 0xA5);
+        cartridge.flush_battery_ram().unwrap();
+        let saved = std::fs::read(save_filename).unwrap();
+        assert_eq!(saved.len(), PRG_RAM_SIZE);
+        assert_eq!((saved[0x0000], saved[0x1FFF]), (0x5A, 0xA5));
+
+        // dropping the cartridge flushes too
+        cartridge.write(0x6001, 0x33);
+        drop(cartridge);
+        let cartridge = create_cartridge_from_file(rom_filename, &[]).unwrap();
+        assert_eq!(cartridge.peek(0x6000), 0x5A);
+        assert_eq!(cartridge.peek(0x6001), 0x33);
+        assert_eq!(cartridge.peek(0x7FFF), 0xA5);
+
+        // no battery, no save file
+        let cartridge = create_cartridge_from_file("./test_files/nestest.nes", &[]).unwrap();
+        assert_eq!(cartridge.save_filename(), None);
+    }
+
+    #[test]
+    fn database_corrects_bad_headers() {
+        let mut image = std::fs::read("./test_files/nestest.nes").unwrap();
+        let database = romdb::parse_database("158B0388|4131307F0F69F2A5C54B7D438328C5B2A5ED0820|0|0|H|0|8192|0|0|NTSC|World|nestest").unwrap();
+        let cartridge = create_cartridge_from_bytes(&image, &database).unwrap();
+        assert_eq!(cartridge.game_info().map(|game| game.title.as_str()), Some("nestest"));
+        assert!(cartridge.warnings().is_empty()); // the header was already right
+
+        image[6] = 0x13; // mapper 1, vertical, battery
+        let cartridge = create_cartridge_from_bytes(&image, &database).unwrap();
+        assert_eq!(cartridge.header().mapper_id, 0);
+        assert!(!cartridge.header().has_battery);
+        assert_eq!(cartridge.mirroring(), mapper::Mirroring::Horizontal);
+        assert_eq!(cartridge.warnings(), &[LoadWarning::HeaderCorrected { title: "nestest".to_string() }]);
+
+        // without a database the header is taken as it is
+        let cartridge = create_cartridge_from_bytes(&image, &[]).unwrap();
+        assert_eq!(cartridge.header().mapper_id, 1);
+        assert_eq!(cartridge.game_info(), None);
+
+        // NES 2.0 headers are left alone
+        image[7] |= 0x08;
+        let cartridge = create_cartridge_from_bytes(&image, &database).unwrap();
+        assert_eq!(cartridge.header().mapper_id, 1);
+        assert!(cartridge.game_info().is_some());
+
+        assert_eq!(create_cartridge_from_bytes(&ines_image(1, 1, 0), &database).unwrap().game_info(), None);
+
+        // a UxROM game dumped with a mapper 0 header
+        let mut image = ines_image(2, 0, 0);
+        image[16..33].copy_from_slice(b"database_corrects");
+        let entry = format!("{:08X}|-|2|0|V|0|0|0|8192|NTSC|USA|Other game\n", romdb::crc32(&image[16..]));
+        let database = romdb::parse_database(&entry).unwrap();
+        let cartridge = create_cartridge_from_bytes(&image, &database).unwrap();
+        assert_eq!(cartridge.header().mapper_id, 2);
+        assert_eq!(cartridge.mirroring(), mapper::Mirroring::Vertical);
+        assert_eq!(cartridge.warnings(), &[LoadWarning::HeaderCorrected { title: "Other game".to_string() }]);
+    }
+
+    // a UNIF file with the given board name and one PRG and CHR chunk
+    fn unif_image(board: &str, prg_rom: &[u8], chr_rom: &[u8], mirroring: u8) -> Vec<u8> {
+        let mut image = b"UNIF".to_vec();
+        image.resize(32, 0);
+        let mut board = board.as_bytes().to_vec();
+        board.push(0);
+        let chunks: [(&[u8], &[u8]); 4] = [(b"MAPR", &board), (b"MIRR", &[mirroring]), (b"PRG0", prg_rom), (b"CHR0", chr_rom)];
+        for (id, data) in chunks.iter() {
+            if !data.is_empty() {
+                image.extend_from_slice(id);
+                image.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                image.extend_from_slice(data);
+            }
+        }
+        return image;
+    }
+
+    #[test]
+    fn unif_cartridge() {
+        let mut prg_rom = vec![0u8; 0x20000];
+        prg_rom[0x1FFFC] = 0x34; // reset vector in the fixed last bank
+        let mut cartridge = create_cartridge_from_bytes(&unif_image("NES-UNROM", &prg_rom, &[], 1), &[]).unwrap();
+        assert_eq!(cartridge.header().format, HeaderFormat::Unif);
+        assert_eq!(cartridge.header().mapper_id, 2);
+        assert_eq!(cartridge.mirroring(), mapper::Mirroring::Vertical);
+        assert_eq!(cartridge.peek(0xFFFC), 0x34);
+
+        // no CHR chunks means CHR RAM
+        let mut ciram = [0u8; 2048];
+        cartridge.ppu_write(0x0010, 0x42, &mut ciram);
+        assert_eq!(cartridge.ppu_read(0x0010, &ciram, cdl::ChrAccess::Read), 0x42);
+
+        let cartridge = create_cartridge_from_bytes(&unif_image("NES-CNROM", &[0; 0x8000], &[0x99; 0x8000], 0), &[]).unwrap();
+        assert_eq!((cartridge.header().mapper_id, cartridge.header().chr_rom_size), (3, 0x8000));
+        assert_eq!(cartridge.mirroring(), mapper::Mirroring::Horizontal);
+
+        let cartridge = create_cartridge_from_bytes(&unif_image("NES-NROM-256", &[0; 0x8000], &[0; 0x2000], 3), &[]).unwrap();
+        assert_eq!(cartridge.mirroring(), mapper::Mirroring::SingleScreenUpper);
+        let cartridge = create_cartridge_from_bytes(&unif_image("UNL-AVE-NINA-06", &[0; 0x8000], &[0; 0x2000], 2), &[]).unwrap();
+        assert_eq!((cartridge.header().mapper_id, cartridge.mirroring()), (79, mapper::Mirroring::SingleScreenLower));
+
+        let error = create_cartridge_from_bytes(&unif_image("BMC-Super24in1SC03", &[0; 0x8000], &[], 0), &[]).err().unwrap();
+        assert_eq!(error, CartridgeError::UnsupportedBoard("BMC-Super24in1SC03".to_string()));
+        assert_eq!(error.to_string(), "Board 'BMC-Super24in1SC03' is not supported");
+    }
+
+    #[test]
+    fn fds_disk_writes_are_saved() {
+        let mut disk = b"FDS\x1A\x01".to_vec();
+        disk.resize(16 + fds::FDS_SIDE_SIZE, 0);
+        std::fs::create_dir_all("./log").unwrap();
+        std::fs::write("./log/fds_disk_writes_are_saved.fds", &disk).unwrap();
+        std::fs::write("./log/fds_disk_writes_are_saved.bin", &[0xEA; fds::FDS_BIOS_SIZE][..]).unwrap();
+        let _ = std::fs::remove_file("./log/fds_disk_writes_are_saved.sav");
+
+        let mut cartridge = create_fds_cartridge_from_files("./log/fds_disk_writes_are_saved.fds", "./log/fds_disk_writes_are_saved.bin").unwrap();
+        assert_eq!(cartridge.header().format, HeaderFormat::Fds);
+        assert_eq!(cartridge.peek(0xFFFF), 0xEA);
+        assert_eq!(cartridge.mirroring(), mapper::Mirroring::Vertical);
+        cartridge.write(0x4025, 0x49); // motor on, write mode, horizontal mirroring
+        assert_eq!(cartridge.mirroring(), mapper::Mirroring::Horizontal);
+        cartridge.write(0x4024, 0x77);
+        for _ in 0..60000 {
+            cartridge.cpu_clock();
+        }
+        assert!(cartridge.fds().unwrap().is_disk_dirty());
+        cartridge.flush_battery_ram().unwrap();
+        let diff = std::fs::read("./log/fds_disk_writes_are_saved.sav").unwrap();
+        assert!(diff.starts_with(b"PATCH") && diff.contains(&0x77));
+
+        let cartridge = create_fds_cartridge_from_files("./log/fds_disk_writes_are_saved.fds", "./log/fds_disk_writes_are_saved.bin").unwrap();
+        assert!(!cartridge.fds().unwrap().is_disk_dirty());
+
+        assert_eq!(create_fds_cartridge(&disk[..1000], &[0; fds::FDS_BIOS_SIZE]).err(), Some(CartridgeError::TruncatedDisk { expected: fds::FDS_SIDE_SIZE, found: 984 }));
+        assert_eq!(create_cartridge_from_bytes(&disk, &[]).err(), Some(CartridgeError::UnsupportedFormat("Famicom Disk System".to_string())));
+    }
+
+    #[test]
+    fn patched_cartridge() {
+        let image = std::fs::read("./test_files/nestest.nes").unwrap();
+        std::fs::create_dir_all("./log").unwrap();
+        // change the first PRG byte and give it a battery
+        std::fs::write("./log/patched_cartridge.ips", b"PATCH\x00\x00\x06\x00\x01\x02\x00\x00\x10\x00\x01\xEAEOF").unwrap();
+        std::fs::write("./log/patched_cartridge.bps", b"BPS1").unwrap();
+
+        let cartridge = create_patched_cartridge_from_file("./test_files/nestest.nes", "./log/patched_cartridge.ips", &[]).unwrap();
+        assert_eq!(cartridge.peek(0xC000), 0xEA);
+        assert!(cartridge.header().has_battery);
+        assert_eq!(cartridge.save_filename(), Some("./log/patched_cartridge.sav"));
+        assert_eq!(std::fs::read("./test_files/nestest.nes").unwrap(), image); // ROM file is untouched
+
+        let error = create_patched_cartridge_from_file("./test_files/nestest.nes", "./log/patched_cartridge.bps", &[]).err().unwrap();
+        assert_eq!(error, CartridgeError::BadPatch { filename: "./log/patched_cartridge.bps".to_string(), message: "Not a BPS patch".to_string() });
+        assert_eq!(error.to_string(), "Unable to apply './log/patched_cartridge.bps': Not a BPS patch");
+    }
+
+    #[test]
+    fn compressed_cartridge() {
+        let image = std::fs::read("./test_files/nestest.nes").unwrap();
+        let mut patched = image.clone();
+        patched[16] = 0xEA;
+        std::fs::create_dir_all("./log").unwrap();
+        std::fs::write("./log/compressed_cartridge.zip", archive::tests::zip_file(&[("readme.txt", b"hi", false), ("nestest.nes", &image, true), ("fixed.nes", &patched, false)])).unwrap();
+        std::fs::write("./log/compressed_cartridge.nes.gz", archive::tests::gzip_file("nestest.nes", &image)).unwrap();
+        std::fs::write("./log/compressed_cartridge_empty.zip", archive::tests::zip_file(&[("readme.txt", b"hi", false)])).unwrap();
+
+        let cartridge = create_cartridge_from_file("./log/compressed_cartridge.zip", &[]).unwrap();
+        assert_eq!(cartridge.peek(0xC000), image[16]); // the first ROM in the zip
+        let cartridge = create_cartridge_from_archive("./log/compressed_cartridge.zip", "fixed.nes", &[]).unwrap();
+        assert_eq!(cartridge.peek(0xC000), 0xEA);
+        let cartridge = create_cartridge_from_file("./log/compressed_cartridge.nes.gz", &[]).unwrap();
+        assert_eq!(cartridge.peek(0xC000), image[16]);
+
+        let error = create_cartridge_from_file("./log/compressed_cartridge_empty.zip", &[]).err().unwrap();
+        assert_eq!(error.to_string(), "Unable to open './log/compressed_cartridge_empty.zip': No .nes, .unf, .unif, .fds or .qd file in the archive");
+        // compressing a ROM keeps its save, and every game in a zip gets its own
+        assert_eq!(save_filename_for_rom("./log/game.nes"), "./log/game.sav");
+        assert_eq!(save_filename_for_rom("./log/game.nes.gz"), "./log/game.sav");
+        assert_eq!(save_filename_for_rom("./log/game.ZIP"), "./log/game.sav");
+        assert_eq!(save_filename_for_archive_entry("./log/games v1.1.zip", "roms/Game (Rev 1).nes"), "./log/games v1.1 - Game (Rev 1).sav");
+        let mut battery = image.clone();
+        battery[6] |= 0x02;
+        *battery.last_mut().unwrap() ^= 0x01; // so the database doesn't take the battery away
+        std::fs::write("./log/compressed_battery.zip", archive::tests::zip_file(&[("a.nes", &battery, true), ("b.nes", &battery, true)])).unwrap();
+        let cartridge = create_cartridge_from_archive("./log/compressed_battery.zip", "b.nes", &[]).unwrap();
+        assert_eq!(cartridge.save_filename(), Some("./log/compressed_battery - b.sav"));
+
+        let error = create_cartridge_from_archive("./log/compressed_cartridge.zip", "missing.nes", &[]).err().unwrap();
+        assert_eq!(error, CartridgeError::BadArchive { filename: "./log/compressed_cartridge.zip".to_string(), message: "'missing.nes' isn't in the archive".to_string() });
+    }
+}
@@ -0,0 +1,207 @@
+#![allow(dead_code)]
+use std::fs::File;
+use std::io::{Read, Write};
+
+/*
+ * Code/Data Logger: records how every byte of PRG and CHR ROM was used while
+ * the game ran, in the layout FCEUX uses for its .cdl files (one flag byte
+ * per PRG ROM byte, followed by one flag byte per CHR ROM byte).
+ *
+ * PRG flags                          CHR flags
+ *   0x01 executed as code              0x01 rendered as a tile
+ *   0x02 read as data                  0x02 read through $2007
+ *   0x0C cpu window it was mapped at
+ *        ($8000/$A000/$C000/$E000)
+ *   0x10 jumped to indirectly
+ *   0x20 read indirectly ((zp),Y / (zp,X))
+ *   0x40 played as PCM audio
+ *
+ * Bit 7 is unused by FCEUX; it is used here to remember that a byte was
+ * fetched as an instruction operand, and is folded into the code bit on export.
+ */
+
+pub const PRG_CODE: u8 = 0x01;
+pub const PRG_DATA: u8 = 0x02;
+pub const PRG_WINDOW_MASK: u8 = 0x0C;
+pub const PRG_INDIRECT_CODE: u8 = 0x10;
+pub const PRG_INDIRECT_DATA: u8 = 0x20;
+pub const PRG_PCM_AUDIO: u8 = 0x40;
+pub const PRG_OPERAND: u8 = 0x80;
+
+pub const CHR_RENDERED: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrgAccess {
+    Code,
+    Operand,
+    Data,
+    IndirectCode,
+    IndirectData,
+    PcmAudio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChrAccess {
+    Rendered,
+    Read,
+}
+
+pub struct CodeDataLogger {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLogger {
+
+    pub fn log_prg(&mut self, offset: u32, cpu_addr: u16, access: PrgAccess) {
+        let offset = offset as usize;
+        if offset >= self.prg.len() {
+            return;
+        }
+        let flags = match access {
+            PrgAccess::Code => PRG_CODE,
+            PrgAccess::Operand => PRG_OPERAND,
+            PrgAccess::Data => PRG_DATA,
+            PrgAccess::IndirectCode => PRG_INDIRECT_CODE,
+            PrgAccess::IndirectData => PRG_DATA | PRG_INDIRECT_DATA,
+            PrgAccess::PcmAudio => PRG_DATA | PRG_PCM_AUDIO,
+        };
+        let window = (((cpu_addr >> 13) & 0x03) as u8) << 2;
+        self.prg[offset] = (self.prg[offset] & !PRG_WINDOW_MASK) | flags | window;
+    }
+
+    pub fn log_chr(&mut self, offset: u32, access: ChrAccess) {
+        let offset = offset as usize;
+        if offset >= self.chr.len() {
+            return;
+        }
+        self.chr[offset] |= match access {
+            ChrAccess::Rendered => CHR_RENDERED,
+            ChrAccess::Read => CHR_READ,
+        };
+    }
+
+    pub fn prg_flags(&self, offset: u32) -> u8 {
+        return self.prg.get(offset as usize).copied().unwrap_or(0);
+    }
+
+    pub fn chr_flags(&self, offset: u32) -> u8 {
+        return self.chr.get(offset as usize).copied().unwrap_or(0);
+    }
+
+    // (code bytes, data bytes, untouched bytes) of PRG ROM
+    pub fn prg_coverage(&self) -> (usize, usize, usize) {
+        let code = self.prg.iter().filter(|f| *f & (PRG_CODE | PRG_OPERAND) != 0).count();
+        let data = self.prg.iter().filter(|f| *f & PRG_DATA != 0).count();
+        let untouched = self.prg.iter().filter(|f| **f == 0).count();
+        return (code, data, untouched);
+    }
+
+    pub fn clear(&mut self) {
+        for flags in self.prg.iter_mut().chain(self.chr.iter_mut()) {
+            *flags = 0;
+        }
+    }
+
+    pub fn to_fceux_bytes(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = self.prg.iter()
+            .map(|f| if f & PRG_OPERAND != 0 { (f & !PRG_OPERAND) | PRG_CODE } else { *f })
+            .collect();
+        result.extend_from_slice(&self.chr);
+        return result;
+    }
+
+    // merges a previously saved log into this one
+    pub fn load_fceux_bytes(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != self.prg.len() + self.chr.len() {
+            return Err(format!("ERROR: CDL size {} doesn't match PRG ({}) + CHR ({}) size", data.len(), self.prg.len(), self.chr.len()));
+        }
+        let (prg, chr) = data.split_at(self.prg.len());
+        for (flags, saved) in self.prg.iter_mut().zip(prg.iter()) {
+            *flags |= saved & !PRG_OPERAND;
+        }
+        for (flags, saved) in self.chr.iter_mut().zip(chr.iter()) {
+            *flags |= saved;
+        }
+        return Ok(());
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let mut file = match File::create(filename) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("ERROR: Unable to create '{}': {}", filename, e)),
+        };
+        return match file.write_all(&self.to_fceux_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("ERROR: Unable to write '{}': {}", filename, e)),
+        }
+    }
+
+    pub fn load(&mut self, filename: &str) -> Result<(), String> {
+        let mut data: Vec<u8> = Vec::new();
+        let mut file = match File::open(filename) {
+            Ok(file) => file,
+            Err(_) => return Err(format!("ERROR: File not found: '{}'", filename)),
+        };
+        if let Err(e) = file.read_to_end(&mut data) {
+            return Err(format!("{}", e));
+        }
+        return self.load_fceux_bytes(&data);
+    }
+}
+
+pub fn create_code_data_logger(prg_size: usize, chr_size: usize) -> CodeDataLogger {
+    return CodeDataLogger {
+        prg: vec![0; prg_size],
+        chr: vec![0; chr_size],
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prg_flags_record_access_and_window() {
+        let mut cdl = create_code_data_logger(0x8000, 0x2000);
+        cdl.log_prg(0x0000, 0x8000, PrgAccess::Code);
+        cdl.log_prg(0x0001, 0x8001, PrgAccess::Operand);
+        cdl.log_prg(0x4010, 0xC010, PrgAccess::Data);
+        cdl.log_prg(0x7FF0, 0xFFF0, PrgAccess::IndirectData);
+        cdl.log_prg(0x6000, 0xE000, PrgAccess::PcmAudio);
+        assert_eq!(cdl.prg_flags(0x0000), PRG_CODE);
+        assert_eq!(cdl.prg_flags(0x0001), PRG_OPERAND);
+        assert_eq!(cdl.prg_flags(0x4010), PRG_DATA | 0x08);
+        assert_eq!(cdl.prg_flags(0x7FF0), PRG_DATA | PRG_INDIRECT_DATA | 0x0C);
+        assert_eq!(cdl.prg_flags(0x6000), PRG_DATA | PRG_PCM_AUDIO | 0x0C);
+        assert_eq!(cdl.prg_coverage(), (2, 3, 0x8000 - 5));
+    }
+
+    #[test]
+    fn chr_flags() {
+        let mut cdl = create_code_data_logger(0x4000, 0x2000);
+        cdl.log_chr(0x0010, ChrAccess::Rendered);
+        cdl.log_chr(0x0010, ChrAccess::Read);
+        cdl.log_chr(0x2000, ChrAccess::Read); // out of range is ignored
+        assert_eq!(cdl.chr_flags(0x0010), CHR_RENDERED | CHR_READ);
+    }
+
+    #[test]
+    fn fceux_round_trip() {
+        let mut cdl = create_code_data_logger(0x4000, 0x2000);
+        cdl.log_prg(0x0000, 0xC000, PrgAccess::Code);
+        cdl.log_prg(0x0001, 0xC001, PrgAccess::Operand);
+        cdl.log_chr(0x0003, ChrAccess::Rendered);
+        let bytes = cdl.to_fceux_bytes();
+        assert_eq!(bytes.len(), 0x6000);
+        assert_eq!(bytes[0], PRG_CODE | 0x08);
+        assert_eq!(bytes[1], PRG_CODE | 0x08); // operands export as code
+        assert_eq!(bytes[0x4003], CHR_RENDERED);
+
+        let mut loaded = create_code_data_logger(0x4000, 0x2000);
+        loaded.load_fceux_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_fceux_bytes(), bytes);
+        assert!(loaded.load_fceux_bytes(&bytes[1..]).is_err());
+    }
+}
@@ -0,0 +1,592 @@
+#![allow(dead_code)]
+use super::cartridge::CartridgeError;
+use super::mapper::{CpuMapping, Mapper, Mirroring};
+use super::patch;
+
+/*
+ * Famicom Disk System: https://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+ *
+ * The RAM adapter plugs into the cartridge slot and provides 32k of PRG RAM at
+ * $6000-$DFFF, the 8k BIOS at $E000-$FFFF, 8k of CHR RAM, a CPU-cycle timer
+ * IRQ and the disk drive interface at $4020-$4033.
+ *
+ * Disk images (.fds/.qd) only hold the blocks stored on each side.  The drive
+ * reads a continuous stream with gaps and CRCs between blocks, so each side is
+ * expanded into that raw stream when loaded, and games read and write it one
+ * byte at a time as the disk spins under the head.
+ */
+pub const FDS_SIDE_SIZE: usize = 65500;
+pub const QD_SIDE_SIZE: usize = 65536;
+pub const FDS_BIOS_SIZE: usize = 0x2000;
+pub const FDS_PRG_RAM_SIZE: usize = 0x8000;
+const FDS_HEADER_SIZE: usize = 16;
+
+const RAW_SIDE_SIZE: usize = 0x12000;    // room for the gaps around every block
+const LEADING_GAP_BYTES: usize = 28300 / 8; // before the first block
+const BLOCK_GAP_BYTES: usize = 976 / 8;     // between blocks
+const BLOCK_START_MARK: u8 = 0x80;
+
+const MOTOR_START_DELAY: u32 = 50000; // cpu cycles for the head to return to the start of the disk
+const BYTE_TRANSFER_DELAY: u32 = 150;  // cpu cycles per byte at 96.4kbit/s
+
+pub struct Fds {
+    // disk sides as raw bit streams, and what they were when loaded
+    sides: Vec<Vec<u8>>,
+    original_sides: Vec<Vec<u8>>,
+    inserted_side: Option<usize>,
+    disk_dirty: bool,
+
+    // timer IRQ
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    timer_irq: bool,
+
+    // $4023
+    disk_registers_enabled: bool,
+
+    // $4025
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    horizontal_mirroring: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+
+    // drive state
+    disk_position: usize,
+    delay: u32,
+    scanning_disk: bool,
+    end_of_head: bool,
+    gap_ended: bool,
+    previous_crc_control: bool,
+    crc: u16,
+    bad_crc: bool,
+    read_data: u8,
+    write_data: u8,
+    transfer_complete: bool,
+    disk_irq: bool,
+}
+
+impl Fds {
+    pub fn side_count(&self) -> usize {
+        return self.sides.len();
+    }
+
+    pub fn inserted_side(&self) -> Option<usize> {
+        return self.inserted_side;
+    }
+
+    // None ejects the disk
+    pub fn insert_disk(&mut self, side: Option<usize>) -> Result<(), String> {
+        if let Some(side) = side {
+            if side >= self.sides.len() {
+                return Err(format!("ERROR: The disk only has {} sides", self.sides.len()));
+            }
+        }
+        self.inserted_side = side;
+        self.end_of_head = true;
+        self.scanning_disk = false;
+        return Ok(());
+    }
+
+    pub fn is_disk_dirty(&self) -> bool {
+        return self.disk_dirty;
+    }
+
+    // everything games have written to the disk, as an IPS patch of the raw sides
+    pub fn save_diff(&mut self) -> Vec<u8> {
+        self.disk_dirty = false;
+        return patch::create_ips(&self.original_sides.concat(), &self.sides.concat());
+    }
+
+    pub fn load_diff(&mut self, diff: &[u8]) -> Result<(), String> {
+        let original = self.original_sides.concat();
+        let patched = patch::apply_ips(&original, diff)?;
+        // a diff for some other disk would leave sides half patched
+        if patched.len() != original.len() {
+            return Err(format!("ERROR: Disk diff is for {} bytes of disk, expected {}", patched.len(), original.len()));
+        }
+        let mut position = 0;
+        for side in self.sides.iter_mut() {
+            let end = position + side.len();
+            side.copy_from_slice(&patched[position..end]);
+            position = end;
+        }
+        self.disk_dirty = false;
+        return Ok(());
+    }
+
+    fn read_register(&self, addr: u16) -> CpuMapping {
+        return match addr {
+            0x4030 => {
+                let mut status = 0x00;
+                status |= if self.timer_irq { 0x01 } else { 0x00 };
+                status |= if self.transfer_complete { 0x02 } else { 0x00 };
+                status |= if self.bad_crc { 0x10 } else { 0x00 };
+                status |= if self.end_of_head { 0x40 } else { 0x00 };
+                CpuMapping::Data(status)
+            }
+            0x4031 => CpuMapping::Data(self.read_data),
+            0x4032 => {
+                let inserted = self.inserted_side.is_some();
+                let mut status = 0x40; // open bus
+                status |= if !inserted { 0x01 } else { 0x00 };
+                status |= if !inserted || !self.scanning_disk { 0x02 } else { 0x00 };
+                status |= if !inserted { 0x04 } else { 0x00 }; // write protected
+                CpuMapping::Data(status)
+            }
+            0x4033 => CpuMapping::Data(0x80), // battery is good
+            _ => CpuMapping::Unmapped,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | data as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | ((data as u16) << 8),
+            0x4022 => {
+                self.irq_repeat = data & 0x01 > 0;
+                self.irq_enabled = data & 0x02 > 0 && self.disk_registers_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers_enabled = data & 0x01 > 0;
+                if !self.disk_registers_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 => {
+                if self.disk_registers_enabled {
+                    self.write_data = data;
+                    self.transfer_complete = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4025 => {
+                if self.disk_registers_enabled {
+                    self.motor_on = data & 0x01 > 0;
+                    self.reset_transfer = data & 0x02 > 0;
+                    self.read_mode = data & 0x04 > 0;
+                    self.horizontal_mirroring = data & 0x08 > 0;
+                    self.crc_control = data & 0x10 > 0;
+                    self.disk_ready = data & 0x40 > 0;
+                    self.disk_irq_enabled = data & 0x80 > 0;
+                    self.disk_irq = false;
+                }
+            }
+            _ => {} // $4026 is the expansion port, which nothing is plugged into
+        }
+    }
+
+    // moves the disk under the head, transferring a byte every BYTE_TRANSFER_DELAY cycles
+    fn clock_drive(&mut self) {
+        let side = match self.inserted_side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning_disk = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning_disk {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = MOTOR_START_DELAY;
+            self.end_of_head = false;
+            self.disk_position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning_disk = true;
+        if self.read_mode {
+            let data = self.sides[side][self.disk_position];
+            if !self.disk_ready {
+                self.gap_ended = false;
+                self.crc = 0;
+            } else if !self.gap_ended {
+                // the start mark isn't handed to the cpu, but is part of the block's CRC
+                if data != 0 {
+                    self.gap_ended = true;
+                    self.crc = fds_crc(0, &[data]);
+                }
+            } else {
+                self.read_data = data;
+                self.transfer_complete = true;
+                self.disk_irq |= self.disk_irq_enabled;
+                self.crc = fds_crc(self.crc, &[data]);
+                if self.crc_control {
+                    self.bad_crc = self.crc != 0;
+                }
+            }
+        } else {
+            let mut data = self.write_data;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                self.disk_irq |= self.disk_irq_enabled;
+            }
+            if !self.disk_ready {
+                data = 0x00;
+                self.crc = 0;
+            } else if !self.crc_control {
+                self.crc = fds_crc(self.crc, &[data]);
+            } else {
+                // the adapter writes the CRC itself, low byte first
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            self.sides[side][self.disk_position] = data;
+            self.disk_dirty = true;
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.disk_position += 1;
+        if self.disk_position >= self.sides[side].len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_TRANSFER_DELAY;
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn map_address(&self, input_addr: u16) -> u32 {
+        return (input_addr & 0x1FFF) as u32;
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        return if addr >= 0xE000 {
+            CpuMapping::PrgRom(self.map_address(addr))
+        } else if addr >= 0x6000 {
+            CpuMapping::PrgRam((addr - 0x6000) as u32)
+        } else if self.disk_registers_enabled {
+            self.read_register(addr)
+        } else {
+            CpuMapping::Unmapped
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> CpuMapping {
+        let mapping = self.cpu_peek(addr);
+        match addr {
+            0x4030 => {
+                self.timer_irq = false;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        return mapping;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        return if (0x6000..0xE000).contains(&addr) {
+            CpuMapping::PrgRam((addr - 0x6000) as u32)
+        } else {
+            self.write_register(addr, data);
+            CpuMapping::Unmapped
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(if self.horizontal_mirroring { Mirroring::Horizontal } else { Mirroring::Vertical });
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.timer_irq || self.disk_irq;
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_enabled {
+            if self.irq_counter == 0 {
+                self.timer_irq = true;
+                self.irq_counter = self.irq_reload;
+                self.irq_enabled = self.irq_repeat;
+            } else {
+                self.irq_counter -= 1;
+            }
+        }
+        self.clock_drive();
+    }
+
+    fn fds(&mut self) -> Option<&mut Fds> {
+        return Some(self);
+    }
+
+    fn fds_ref(&self) -> Option<&Fds> {
+        return Some(self);
+    }
+}
+
+pub fn create_fds(sides: Vec<Vec<u8>>) -> Fds {
+    return Fds {
+        original_sides: sides.clone(),
+        sides,
+        inserted_side: Some(0),
+        disk_dirty: false,
+        irq_reload: 0,
+        irq_counter: 0,
+        irq_repeat: false,
+        irq_enabled: false,
+        timer_irq: false,
+        disk_registers_enabled: true,
+        motor_on: false,
+        reset_transfer: false,
+        read_mode: true,
+        horizontal_mirroring: false,
+        crc_control: false,
+        disk_ready: false,
+        disk_irq_enabled: false,
+        disk_position: 0,
+        delay: 0,
+        scanning_disk: false,
+        end_of_head: true,
+        gap_ended: false,
+        previous_crc_control: false,
+        crc: 0,
+        bad_crc: false,
+        read_data: 0,
+        write_data: 0,
+        transfer_complete: false,
+        disk_irq: false,
+    }
+}
+
+/*
+ * Disk images
+ *
+ * .fds: an optional 16 byte "FDS\x1A" header, then 65500 bytes per side
+ * .qd:  65536 bytes per side, with each block followed by its CRC
+ *
+ * A side is a disk info block (1), file amount block (2), then a file header
+ * (3) and file data (4) block for each file.
+ */
+pub fn is_disk_image(bytes: &[u8]) -> bool {
+    return bytes.starts_with(b"FDS\x1A") || bytes.get(1..15) == Some(b"*NINTENDO-HVC*");
+}
+
+// the raw stream for each side of the disk
+pub fn parse_disk_image(bytes: &[u8]) -> Result<Vec<Vec<u8>>, CartridgeError> {
+    let (data, side_size, has_crcs) = if bytes.starts_with(b"FDS\x1A") {
+        (&bytes[usize::min(FDS_HEADER_SIZE, bytes.len())..], FDS_SIDE_SIZE, false)
+    } else if bytes.len() % FDS_SIDE_SIZE == 0 {
+        (bytes, FDS_SIDE_SIZE, false)
+    } else if bytes.len() % QD_SIDE_SIZE == 0 {
+        (bytes, QD_SIDE_SIZE, true)
+    } else {
+        (bytes, FDS_SIDE_SIZE, false)
+    };
+    if data.len() < side_size {
+        return Err(CartridgeError::TruncatedDisk { expected: side_size, found: data.len() });
+    }
+    return Ok(data.chunks_exact(side_size).map(|side| raw_side(side, has_crcs)).collect());
+}
+
+fn raw_side(side: &[u8], has_crcs: bool) -> Vec<u8> {
+    let crc_size = if has_crcs { 2 } else { 0 };
+    let mut raw = vec![0; LEADING_GAP_BYTES];
+    let mut position = 0;
+    let mut expected_block = 1;
+    let mut file_size = 0;
+    while position < side.len() && side[position] == expected_block {
+        let block_size = match expected_block {
+            1 => 56,
+            2 => 2,
+            3 => 16,
+            _ => 1 + file_size,
+        };
+        let block = &side[position..usize::min(position + block_size, side.len())];
+        if expected_block == 3 && block.len() == 16 {
+            file_size = block[13] as usize | (block[14] as usize) << 8;
+        }
+        raw.push(BLOCK_START_MARK);
+        raw.extend_from_slice(block);
+        let crc = fds_crc(fds_crc(0, &[BLOCK_START_MARK]), block);
+        raw.extend_from_slice(&crc.to_le_bytes());
+        raw.extend(std::iter::repeat(0).take(BLOCK_GAP_BYTES));
+        position += block_size + crc_size;
+        // after the file amount block, file headers and file data alternate
+        expected_block = if expected_block == 4 { 3 } else { expected_block + 1 };
+    }
+    if raw.len() < RAW_SIDE_SIZE {
+        raw.resize(RAW_SIDE_SIZE, 0);
+    }
+    return raw;
+}
+
+// CRC-16 with the polynomial $8408, run from the block start mark to the end of the block
+pub fn fds_crc(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 > 0 { (crc >> 1) ^ 0x8408 } else { crc >> 1 };
+        }
+    }
+    return crc;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one side with a single 4 byte file
+    fn disk_side() -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend_from_slice(b"*NINTENDO-HVC*");
+        side.resize(56, 0);
+        side.extend_from_slice(&[0x02, 0x01]);
+        let mut file_header = vec![0x03, 0x00, 0x00];
+        file_header.extend_from_slice(b"FILE0000");
+        file_header.extend_from_slice(&[0x00, 0x60, 0x04, 0x00, 0x00]);
+        side.extend_from_slice(&file_header);
+        side.extend_from_slice(&[0x04, 0xDE, 0xAD, 0xBE, 0xEF]);
+        side.resize(FDS_SIDE_SIZE, 0);
+        return side;
+    }
+
+    #[test]
+    fn raw_sides() {
+        let mut image = b"FDS\x1A\x02".to_vec();
+        image.resize(FDS_HEADER_SIZE, 0);
+        image.extend(disk_side());
+        image.extend(disk_side());
+        let sides = parse_disk_image(&image).unwrap();
+        assert_eq!(sides.len(), 2);
+        assert_eq!(sides[0].len(), RAW_SIDE_SIZE);
+
+        // gap, start mark, block, CRC, gap
+        let raw = &sides[0];
+        assert!(raw[..LEADING_GAP_BYTES].iter().all(|&byte| byte == 0));
+        assert_eq!(raw[LEADING_GAP_BYTES], BLOCK_START_MARK);
+        assert_eq!(raw[LEADING_GAP_BYTES + 1], 0x01);
+        let block_end = LEADING_GAP_BYTES + 1 + 56;
+        assert_eq!(fds_crc(0, &raw[LEADING_GAP_BYTES..block_end + 2]), 0); // CRC checks out
+        let file_data = LEADING_GAP_BYTES + (1 + 56 + 2 + BLOCK_GAP_BYTES) + (1 + 2 + 2 + BLOCK_GAP_BYTES) + (1 + 16 + 2 + BLOCK_GAP_BYTES);
+        assert_eq!(&raw[file_data..file_data + 6], &[BLOCK_START_MARK, 0x04, 0xDE, 0xAD, 0xBE, 0xEF]);
+
+        // headerless .fds and .qd (whose blocks carry their own CRCs) expand to the same stream
+        assert_eq!(parse_disk_image(&image[FDS_HEADER_SIZE..]).unwrap(), sides);
+        let mut qd = Vec::new();
+        for (start, size) in [(0, 56), (56, 2), (58, 16), (74, 5)].iter() {
+            qd.extend_from_slice(&disk_side()[*start..start + size]);
+            qd.extend_from_slice(&[0xAA, 0xBB]);
+        }
+        qd.resize(QD_SIDE_SIZE, 0);
+        assert_eq!(parse_disk_image(&qd).unwrap()[0], sides[0]);
+
+        assert_eq!(parse_disk_image(&image[..100]).err(), Some(CartridgeError::TruncatedDisk { expected: FDS_SIDE_SIZE, found: 84 }));
+    }
+
+    fn wait_for_byte(fds: &mut Fds) -> u8 {
+        // long enough to get past the gap at the start of the disk
+        for _ in 0..MOTOR_START_DELAY + (LEADING_GAP_BYTES as u32 + 2) * (BYTE_TRANSFER_DELAY + 1) {
+            fds.cpu_clock();
+            if fds.irq_pending() {
+                if let CpuMapping::Data(data) = fds.cpu_read(0x4031) {
+                    return data;
+                }
+            }
+        }
+        panic!("no byte transferred");
+    }
+
+    #[test]
+    fn reading_the_disk() {
+        let mut fds = create_fds(parse_disk_image(&disk_side()).unwrap());
+        assert_eq!(fds.cpu_peek(0x4032), CpuMapping::Data(0x42)); // inserted, not yet spinning
+
+        // motor on, read mode, IRQ on transfer, then wait for the first block
+        fds.cpu_write(0x4025, 0x85);
+        fds.cpu_write(0x4025, 0xC5);
+        assert_eq!(wait_for_byte(&mut fds), 0x01);
+        assert_eq!(fds.cpu_peek(0x4032), CpuMapping::Data(0x40));
+        for expected in b"*NINTENDO-HVC*".iter() {
+            assert_eq!(wait_for_byte(&mut fds), *expected);
+        }
+        for _ in 15..56 {
+            wait_for_byte(&mut fds);
+        }
+        // check the CRC
+        fds.cpu_write(0x4025, 0xD5);
+        wait_for_byte(&mut fds);
+        wait_for_byte(&mut fds);
+        assert_eq!(fds.cpu_read(0x4030), CpuMapping::Data(0x00));
+
+        fds.insert_disk(None).unwrap();
+        assert_eq!(fds.cpu_peek(0x4032), CpuMapping::Data(0x47));
+        assert!(fds.insert_disk(Some(1)).is_err());
+    }
+
+    #[test]
+    fn timer_irq() {
+        let mut fds = create_fds(parse_disk_image(&disk_side()).unwrap());
+        fds.cpu_write(0x4020, 0x02);
+        fds.cpu_write(0x4021, 0x00);
+        fds.cpu_write(0x4022, 0x03); // repeat
+        fds.cpu_clock();
+        fds.cpu_clock();
+        assert!(!fds.irq_pending());
+        fds.cpu_clock();
+        assert!(fds.irq_pending());
+        assert_eq!(fds.cpu_read(0x4030), CpuMapping::Data(0x41));
+        assert!(!fds.irq_pending());
+        for _ in 0..3 {
+            fds.cpu_clock();
+        }
+        assert!(fds.irq_pending());
+
+        // turning the disk registers off stops the timer
+        fds.cpu_write(0x4023, 0x00);
+        assert!(!fds.irq_pending());
+        assert_eq!(fds.cpu_peek(0x4030), CpuMapping::Unmapped);
+    }
+
+    #[test]
+    fn writes_and_diffs() {
+        let sides = parse_disk_image(&disk_side()).unwrap();
+        let mut fds = create_fds(sides.clone());
+        assert!(!fds.is_disk_dirty());
+
+        // motor on, write mode
+        fds.cpu_write(0x4025, 0x41);
+        fds.cpu_write(0x4024, 0x5A);
+        for _ in 0..MOTOR_START_DELAY + 2 {
+            fds.cpu_clock();
+        }
+        assert!(fds.is_disk_dirty());
+        assert_eq!(fds.sides[0][0], 0x5A);
+
+        let diff = fds.save_diff();
+        assert!(!fds.is_disk_dirty());
+        let mut reloaded = create_fds(sides);
+        reloaded.load_diff(&diff).unwrap();
+        assert_eq!(reloaded.sides, fds.sides);
+
+        // a diff that writes past the end of the disk
+        let length = fds.sides[0].len();
+        let mut grown = fds.sides[0].clone();
+        grown.push(0xFF);
+        let diff = patch::create_ips(&fds.sides[0], &grown);
+        assert!(reloaded.load_diff(&diff).unwrap_err().contains(&format!("expected {}", length)));
+    }
+}
@@ -0,0 +1,729 @@
+#![allow(dead_code)]
+extern crate hex;
+
+use std::collections::HashSet;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+
+use super::callstack;
+use super::olc6502;
+
+// GDB remote serial protocol: https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+//
+// Register numbering exposed to the client (see TARGET_XML):
+//   0 a, 1 x, 2 y, 3 p, 4 sp (8 bits each), 5 pc (16 bits, little endian)
+
+const PACKET_SIZE: usize = 0x1000;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const INTERRUPT_CHECK_INTERVAL: u32 = 1000; // instructions between polls for ctrl-c
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\">\
+<feature name=\"org.nesemu.6502.core\">\
+<reg name=\"a\" bitsize=\"8\" type=\"uint8\" regnum=\"0\"/>\
+<reg name=\"x\" bitsize=\"8\" type=\"uint8\"/>\
+<reg name=\"y\" bitsize=\"8\" type=\"uint8\"/>\
+<reg name=\"p\" bitsize=\"8\" type=\"uint8\"/>\
+<reg name=\"sp\" bitsize=\"8\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+</feature>\
+</target>";
+
+enum Action {
+    Reply(String),
+    Step,
+    Continue,
+    Finish,
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    breakpoints: HashSet<u16>,
+    no_ack_mode: bool,
+    stop_signal: u8, // SIGINT after a client interrupt, otherwise SIGTRAP
+}
+
+impl GdbStub {
+
+    // blocks until a client connects to localhost:port, then serves it until it detaches
+    pub fn listen(&mut self, cpu: &mut olc6502::Olc6502, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+        return self.serve(cpu, stream);
+    }
+
+    pub fn serve(&mut self, cpu: &mut olc6502::Olc6502, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        self.no_ack_mode = false;
+        loop {
+            let packet = match read_packet(&mut stream, self.no_ack_mode)? {
+                Some(packet) => packet,
+                None => return Ok(()), // client hung up
+            };
+            match self.handle_packet(cpu, &packet) {
+                Action::Reply(reply) => {
+                    write_packet(&mut stream, &reply)?;
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack_mode = true;
+                    }
+                }
+                Action::Step => {
+                    cpu.step();
+                    self.stop_signal = SIGTRAP;
+                    let reply = self.stop_reply(cpu);
+                    write_packet(&mut stream, &reply)?;
+                }
+                Action::Continue => {
+                    self.run_until_stopped(cpu, &mut || interrupt_requested(&mut stream), None)?;
+                    let reply = self.stop_reply(cpu);
+                    write_packet(&mut stream, &reply)?;
+                }
+                Action::Finish => {
+                    let reply = self.finish(cpu, &mut || interrupt_requested(&mut stream))?;
+                    write_packet(&mut stream, &reply)?;
+                }
+                Action::Detach => {
+                    write_packet(&mut stream, "OK")?;
+                    return Ok(());
+                }
+                Action::Kill => {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, cpu: &mut olc6502::Olc6502, packet: &str) -> Action {
+        let (command, args) = packet.split_at(if packet.is_empty() { 0 } else { 1 });
+        return match command {
+            "?" => Action::Reply(format!("S{:02x}", self.stop_signal)),
+            "g" => Action::Reply(encode_registers(&cpu.registers())),
+            "G" => {
+                match decode_registers(args) {
+                    Some(registers) => {
+                        cpu.set_registers(&registers);
+                        Action::Reply("OK".to_string())
+                    }
+                    None => Action::Reply("E01".to_string()),
+                }
+            }
+            "p" => {
+                match usize::from_str_radix(args, 16) {
+                    Ok(regnum) if regnum <= 5 => {
+                        let encoded = encode_registers(&cpu.registers());
+                        Action::Reply(register_slice(&encoded, regnum).to_string())
+                    }
+                    _ => Action::Reply("E01".to_string()),
+                }
+            }
+            "P" => Action::Reply(write_register(cpu, args)),
+            "m" => Action::Reply(read_memory(cpu, args)),
+            "M" => Action::Reply(write_memory(cpu, args)),
+            "s" => {
+                set_resume_address(cpu, args);
+                Action::Step
+            }
+            "c" => {
+                set_resume_address(cpu, args);
+                Action::Continue
+            }
+            "Z" => Action::Reply(self.update_breakpoint(args, true)),
+            "z" => Action::Reply(self.update_breakpoint(args, false)),
+            "H" => Action::Reply("OK".to_string()),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "q" if packet.starts_with("qRcmd,") => {
+                // finish runs the cpu, so it needs the connection to watch for ctrl-c
+                match decode_monitor_command(&packet["qRcmd,".len()..]).as_deref() {
+                    Some("stepout") | Some("finish") => Action::Finish,
+                    _ => Action::Reply(monitor_command(cpu, &packet["qRcmd,".len()..])),
+                }
+            }
+            "q" | "Q" => Action::Reply(handle_query(packet)),
+            _ => Action::Reply(String::new()), // empty reply means "unsupported"
+        }
+    }
+
+    fn update_breakpoint(&mut self, args: &str, insert: bool) -> String {
+        // args: type,addr,kind.  software (0) and hardware (1) breakpoints are both
+        // handled by checking the program counter, so memory is never patched
+        let fields: Vec<&str> = args.split(',').collect();
+        if fields.len() < 2 || !(fields[0] == "0" || fields[0] == "1") {
+            return String::new();
+        }
+        return match u16::from_str_radix(fields[1], 16) {
+            Ok(addr) => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                "OK".to_string()
+            }
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    // interrupted is polled every INTERRUPT_CHECK_INTERVAL instructions for a ctrl-c from the client.
+    // with until_depth set, also stops once the call stack is shallower than that depth
+    fn run_until_stopped(&mut self, cpu: &mut olc6502::Olc6502, interrupted: &mut dyn FnMut() -> io::Result<bool>, until_depth: Option<usize>) -> io::Result<()> {
+        // always move off the current instruction first, so continuing
+        // from a breakpoint doesn't immediately trigger it again
+        cpu.step();
+        self.stop_signal = SIGTRAP;
+        let mut instructions: u32 = 0;
+        while !cpu.is_program_complete() && !self.breakpoints.contains(&cpu.registers().prog_ctr) {
+            if let Some(depth) = until_depth {
+                if cpu.call_stack().depth() < depth {
+                    break;
+                }
+            }
+            cpu.step();
+            instructions += 1;
+            if instructions % INTERRUPT_CHECK_INTERVAL == 0 && interrupted()? {
+                self.stop_signal = SIGINT;
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    // 'monitor finish': run until the current subroutine or interrupt handler returns
+    fn finish(&mut self, cpu: &mut olc6502::Olc6502, interrupted: &mut dyn FnMut() -> io::Result<bool>) -> io::Result<String> {
+        let depth = cpu.call_stack().depth();
+        if depth == 0 {
+            cpu.step();
+            self.stop_signal = SIGTRAP;
+        } else {
+            self.run_until_stopped(cpu, interrupted, Some(depth))?;
+        }
+        let output = if self.stop_signal == SIGINT {
+            format!("interrupted at ${:04X}\n", cpu.registers().prog_ctr)
+        } else {
+            format!("stopped at ${:04X}\n", cpu.registers().prog_ctr)
+        };
+        return Ok(hex::encode(output));
+    }
+
+    fn stop_reply(&self, cpu: &olc6502::Olc6502) -> String {
+        return if cpu.is_program_complete() {
+            "W00".to_string()
+        } else {
+            format!("S{:02x}", self.stop_signal)
+        }
+    }
+}
+
+pub fn create_gdb_stub() -> GdbStub {
+    return GdbStub {
+        breakpoints: HashSet::new(),
+        no_ack_mode: false,
+        stop_signal: SIGTRAP,
+    };
+}
+
+fn handle_query(packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+        return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE);
+    }
+    if packet.starts_with("qXfer:features:read:target.xml:") {
+        let range = &packet["qXfer:features:read:target.xml:".len()..];
+        return read_target_xml(range);
+    }
+    return match packet {
+        "QStartNoAckMode" => "OK".to_string(),
+        "qAttached" => "1".to_string(),
+        "qC" => "QC1".to_string(),
+        "qfThreadInfo" => "m1".to_string(),
+        "qsThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+// 'monitor <command>' from the gdb prompt
+fn monitor_command(cpu: &mut olc6502::Olc6502, encoded: &str) -> String {
+    let command = match decode_monitor_command(encoded) {
+        Some(command) => command,
+        None => return "E01".to_string(),
+    };
+    let output = match command.as_str() {
+        "backtrace" | "bt" => format_backtrace(cpu),
+        disas if disas.starts_with("disas") => format_disassembly(cpu, &disas["disas".len()..]),
+        _ => format!("unknown monitor command '{}'\n", command),
+    };
+    return hex::encode(output);
+}
+
+fn decode_monitor_command(encoded: &str) -> Option<String> {
+    return match hex::decode(encoded) {
+        Ok(bytes) => Some(String::from_utf8_lossy(&bytes).trim().to_string()),
+        Err(_) => None,
+    }
+}
+
+fn format_backtrace(cpu: &olc6502::Olc6502) -> String {
+    let mut output = format!("#0  {}\n", describe_addr(cpu, cpu.registers().prog_ctr));
+    for (depth, frame) in cpu.call_stack().frames().iter().rev().enumerate() {
+        let kind = match frame.kind {
+            callstack::FrameKind::Subroutine => "JSR",
+            callstack::FrameKind::Nmi => "NMI",
+            callstack::FrameKind::Irq => "IRQ",
+            callstack::FrameKind::Brk => "BRK",
+        };
+        output.push_str(&format!("#{:<2} {}  {} {} from {}\n", depth + 1, describe_addr(cpu, frame.return_addr), kind, describe_addr(cpu, frame.target), describe_addr(cpu, frame.call_site)));
+    }
+    let shown = cpu.call_stack().violations().len();
+    if cpu.call_stack().violation_count() > shown {
+        output.push_str(&format!("warning: {} older shadow stack violations not shown\n", cpu.call_stack().violation_count() - shown));
+    }
+    for violation in cpu.call_stack().violations() {
+        output.push_str(&format!("warning: shadow stack {:?} returning to ${:04X}\n", violation.kind, violation.return_addr));
+    }
+    return output;
+}
+
+fn describe_addr(cpu: &olc6502::Olc6502, addr: u16) -> String {
+    return match cpu.label_for(addr) {
+        Some(label) => format!("${:04X} <{}>", addr, label),
+        None => format!("${:04X}", addr),
+    }
+}
+
+// "disas [addr] [count]", defaulting to the current program counter
+fn format_disassembly(cpu: &olc6502::Olc6502, args: &str) -> String {
+    let fields: Vec<&str> = args.split_whitespace().collect();
+    let mut addr = fields.first()
+        .and_then(|f| u16::from_str_radix(f.trim_start_matches('$'), 16).ok())
+        .unwrap_or(cpu.registers().prog_ctr);
+    let count = fields.get(1).and_then(|f| f.parse::<usize>().ok()).unwrap_or(8);
+    let mut output = String::new();
+    for _ in 0..count {
+        let mut lines = String::new();
+        if let Some(label) = cpu.label_for(addr) {
+            lines.push_str(&format!("{}:\n", label));
+        }
+        let (text, next) = cpu.disassemble(addr);
+        lines.push_str(&format!("  ${:04X}  {}\n", addr, text));
+        // the reply is hex encoded, so stop before it outgrows a packet
+        if (output.len() + lines.len()) * 2 > PACKET_SIZE {
+            break;
+        }
+        output.push_str(&lines);
+        addr = next;
+    }
+    return output;
+}
+
+fn read_target_xml(range: &str) -> String {
+    let fields: Vec<&str> = range.split(',').collect();
+    if fields.len() != 2 {
+        return "E01".to_string();
+    }
+    let offset = usize::from_str_radix(fields[0], 16).unwrap_or(usize::MAX);
+    let length = usize::from_str_radix(fields[1], 16).unwrap_or(0);
+    if offset > TARGET_XML.len() {
+        return "E01".to_string();
+    }
+    let end = usize::min(offset + length, TARGET_XML.len());
+    let marker = if end == TARGET_XML.len() { "l" } else { "m" };
+    return format!("{}{}", marker, &TARGET_XML[offset..end]);
+}
+
+fn encode_registers(registers: &olc6502::Registers) -> String {
+    let bytes = [
+        registers.accumulator,
+        registers.x_reg,
+        registers.y_reg,
+        registers.status_reg,
+        registers.stack_ptr,
+        registers.prog_ctr as u8,
+        (registers.prog_ctr >> 8) as u8,
+    ];
+    return hex::encode(bytes);
+}
+
+fn decode_registers(encoded: &str) -> Option<olc6502::Registers> {
+    let bytes = hex::decode(encoded).ok()?;
+    if bytes.len() != 7 {
+        return None;
+    }
+    return Some(olc6502::Registers {
+        accumulator: bytes[0],
+        x_reg: bytes[1],
+        y_reg: bytes[2],
+        status_reg: bytes[3],
+        stack_ptr: bytes[4],
+        prog_ctr: (bytes[5] as u16) | ((bytes[6] as u16) << 8),
+    });
+}
+
+// the hex digits belonging to a single register within an encoded 'g' reply
+fn register_slice(encoded: &str, regnum: usize) -> &str {
+    return if regnum == 5 {
+        &encoded[10..14]
+    } else {
+        &encoded[regnum * 2..regnum * 2 + 2]
+    }
+}
+
+fn write_register(cpu: &mut olc6502::Olc6502, args: &str) -> String {
+    let fields: Vec<&str> = args.split('=').collect();
+    if fields.len() != 2 {
+        return "E01".to_string();
+    }
+    let regnum = match usize::from_str_radix(fields[0], 16) {
+        Ok(regnum) if regnum <= 5 => regnum,
+        _ => return "E01".to_string(),
+    };
+    let mut encoded = encode_registers(&cpu.registers());
+    let width = if regnum == 5 { 4 } else { 2 };
+    if fields[1].len() != width {
+        return "E01".to_string();
+    }
+    let start = if regnum == 5 { 10 } else { regnum * 2 };
+    encoded.replace_range(start..start + width, fields[1]);
+    return match decode_registers(&encoded) {
+        Some(registers) => {
+            cpu.set_registers(&registers);
+            "OK".to_string()
+        }
+        None => "E01".to_string(),
+    }
+}
+
+fn parse_addr_len(args: &str) -> Option<(u16, usize)> {
+    let fields: Vec<&str> = args.split(',').collect();
+    if fields.len() != 2 {
+        return None;
+    }
+    let addr = u16::from_str_radix(fields[0], 16).ok()?;
+    let len = usize::from_str_radix(fields[1], 16).ok()?;
+    return Some((addr, len));
+}
+
+fn read_memory(cpu: &mut olc6502::Olc6502, args: &str) -> String {
+    return match parse_addr_len(args) {
+        Some((addr, len)) => {
+            // the reply is hex, so more than half a packet won't fit.  gdb asks again for the rest
+            let len = usize::min(len, PACKET_SIZE / 2);
+            let mut data: Vec<u8> = Vec::new();
+            for offset in 0..len {
+                data.push(cpu.bus.peek(u16::wrapping_add(addr, offset as u16)));
+            }
+            hex::encode(data)
+        }
+        None => "E01".to_string(),
+    }
+}
+
+fn write_memory(cpu: &mut olc6502::Olc6502, args: &str) -> String {
+    let fields: Vec<&str> = args.splitn(2, ':').collect();
+    if fields.len() != 2 {
+        return "E01".to_string();
+    }
+    let (addr, len) = match parse_addr_len(fields[0]) {
+        Some(addr_len) => addr_len,
+        None => return "E01".to_string(),
+    };
+    let data = match hex::decode(fields[1]) {
+        Ok(data) if data.len() == len => data,
+        _ => return "E01".to_string(),
+    };
+    for (offset, byte) in data.iter().enumerate() {
+        cpu.bus.write(u16::wrapping_add(addr, offset as u16), *byte);
+    }
+    return "OK".to_string();
+}
+
+fn set_resume_address(cpu: &mut olc6502::Olc6502, args: &str) {
+    if let Ok(addr) = u16::from_str_radix(args, 16) {
+        let mut registers = cpu.registers();
+        registers.prog_ctr = addr;
+        cpu.set_registers(&registers);
+    }
+}
+
+fn checksum(data: &str) -> u8 {
+    return raw_checksum(data.as_bytes());
+}
+
+// covers the bytes exactly as sent between '$' and '#', escapes included
+fn raw_checksum(data: &[u8]) -> u8 {
+    return data.iter().fold(0u8, |sum, b| u8::wrapping_add(sum, *b));
+}
+
+fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    let packet = format!("${}#{:02x}", data, checksum(data));
+    stream.write_all(packet.as_bytes())?;
+    return stream.flush();
+}
+
+// returns None when the connection is closed
+fn read_packet(stream: &mut TcpStream, no_ack_mode: bool) -> io::Result<Option<String>> {
+    let mut byte = [0u8; 1];
+    loop {
+        // skip acks, stray interrupts and noise until the start of a packet
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data: Vec<u8> = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum)?;
+        let expected = u8::from_str_radix(std::str::from_utf8(&sum).unwrap_or(""), 16).ok();
+        let valid = expected == Some(raw_checksum(&data));
+        let packet = String::from_utf8_lossy(&unescape(&data)).into_owned();
+        if no_ack_mode {
+            return Ok(Some(packet));
+        }
+        if valid {
+            stream.write_all(b"+")?;
+            return Ok(Some(packet));
+        }
+        stream.write_all(b"-")?; // ask for a retransmit
+    }
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::new();
+    let mut escaped = false;
+    for byte in data {
+        if escaped {
+            result.push(byte ^ 0x20);
+            escaped = false;
+        } else if *byte == b'}' {
+            escaped = true;
+        } else {
+            result.push(*byte);
+        }
+    }
+    return result;
+}
+
+fn interrupt_requested(stream: &mut TcpStream) -> io::Result<bool> {
+    let mut byte = [0u8; 1];
+    stream.set_nonblocking(true)?;
+    let result = match stream.read(&mut byte) {
+        Ok(1) => byte[0] == 0x03,
+        Ok(_) => false,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+        Err(e) => {
+            stream.set_nonblocking(false)?;
+            return Err(e);
+        }
+    };
+    stream.set_nonblocking(false)?;
+    return Ok(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::symbols;
+    use std::thread;
+
+    fn reply(stub: &mut GdbStub, cpu: &mut olc6502::Olc6502, packet: &str) -> String {
+        return match stub.handle_packet(cpu, packet) {
+            Action::Reply(reply) => reply,
+            _ => panic!("expected a reply to '{}'", packet),
+        }
+    }
+
+    fn create_test_cpu(log_file: &str) -> olc6502::Olc6502 {
+        /* Program listing
+          *=$8000
+          LDX #$08
+          decrement:
+          DEX
+          STX $0200
+          CPX #$03
+          BNE decrement
+          STX $0201
+        */
+        let mut cpu = olc6502::create_olc6502();
+        cpu.set_log_file(log_file);
+        cpu.load_program("A2 08 CA 8E 00 02 E0 03 D0 F8 8E 01 02 EA EA EA".to_string());
+        let mut registers = cpu.registers();
+        registers.prog_ctr = 0x8000;
+        cpu.set_registers(&registers);
+        return cpu;
+    }
+
+    #[test]
+    fn packet_checksum() {
+        assert_eq!(checksum("OK"), 0x9A);
+        assert_eq!(checksum(""), 0x00);
+        // "}]" is the escaped form of '}' and is summed as sent
+        assert_eq!(raw_checksum(b"X0,1:}]"), 0xF9);
+        assert_ne!(raw_checksum(b"X0,1:}]"), raw_checksum(&unescape(b"X0,1:}]")));
+    }
+
+    #[test]
+    fn unescape_binary_data() {
+        assert_eq!(unescape(&[0x41, b'}', 0x5D, 0x42]), vec![0x41, 0x7D, 0x42]);
+    }
+
+    #[test]
+    fn read_and_write_registers() {
+        let mut stub = create_gdb_stub();
+        let mut cpu = create_test_cpu("./log/gdbstub_registers.log");
+        assert_eq!(reply(&mut stub, &mut cpu, "G01020324fd3412"), "OK");
+        assert_eq!(reply(&mut stub, &mut cpu, "g"), "01020324fd3412");
+        assert_eq!(reply(&mut stub, &mut cpu, "p5"), "3412");
+        assert_eq!(reply(&mut stub, &mut cpu, "P1=ea"), "OK");
+        assert_eq!(cpu.registers().x_reg, 0xEA);
+        assert_eq!(reply(&mut stub, &mut cpu, "P5=0080"), "OK");
+        assert_eq!(cpu.registers().prog_ctr, 0x8000);
+        assert_eq!(reply(&mut stub, &mut cpu, "p9"), "E01");
+    }
+
+    #[test]
+    fn read_and_write_memory() {
+        let mut stub = create_gdb_stub();
+        let mut cpu = create_test_cpu("./log/gdbstub_memory.log");
+        assert_eq!(reply(&mut stub, &mut cpu, "m8000,3"), "a208ca");
+        assert_eq!(reply(&mut stub, &mut cpu, "M0010,2:beef"), "OK");
+        assert_eq!(cpu.bus.read(0x0010), 0xBE);
+        assert_eq!(cpu.bus.read(0x0011), 0xEF);
+        assert_eq!(reply(&mut stub, &mut cpu, "M0010,2:be"), "E01");
+        assert_eq!(reply(&mut stub, &mut cpu, "m0,ffffffff").len(), PACKET_SIZE);
+    }
+
+    #[test]
+    fn insert_and_remove_breakpoints() {
+        let mut stub = create_gdb_stub();
+        let mut cpu = create_test_cpu("./log/gdbstub_breakpoints.log");
+        assert_eq!(reply(&mut stub, &mut cpu, "Z0,800a,1"), "OK");
+        assert!(stub.breakpoints.contains(&0x800A));
+        assert_eq!(reply(&mut stub, &mut cpu, "z0,800a,1"), "OK");
+        assert!(stub.breakpoints.is_empty());
+        assert_eq!(reply(&mut stub, &mut cpu, "Z2,0200,1"), ""); // watchpoints unsupported
+    }
+
+    #[test]
+    fn target_description() {
+        let mut stub = create_gdb_stub();
+        let mut cpu = create_test_cpu("./log/gdbstub_target.log");
+        let first = reply(&mut stub, &mut cpu, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &TARGET_XML[0..0x10]));
+        let rest = reply(&mut stub, &mut cpu, &format!("qXfer:features:read:target.xml:10,{:x}", PACKET_SIZE));
+        assert_eq!(rest, format!("l{}", &TARGET_XML[0x10..]));
+    }
+
+    #[test]
+    fn interrupt_stops_with_sigint() {
+        let mut stub = create_gdb_stub();
+        let mut cpu = olc6502::create_olc6502();
+        cpu.set_log_file("./log/gdbstub_interrupt.log");
+        cpu.load_program("4C 00 80".to_string()); // JMP $8000
+        let mut registers = cpu.registers();
+        registers.prog_ctr = 0x8000;
+        cpu.set_registers(&registers);
+        stub.run_until_stopped(&mut cpu, &mut || Ok(true), None).unwrap();
+        assert_eq!(stub.stop_reply(&cpu), "S02");
+        assert_eq!(reply(&mut stub, &mut cpu, "?"), "S02");
+        assert_eq!(reply(&mut stub, &mut cpu, "Z0,8000,1"), "OK");
+        stub.run_until_stopped(&mut cpu, &mut || Ok(false), None).unwrap();
+        assert_eq!(stub.stop_reply(&cpu), "S05");
+    }
+
+    #[test]
+    fn monitor_backtrace() {
+        let mut stub = create_gdb_stub();
+        let mut cpu = olc6502::create_olc6502();
+        cpu.set_log_file("./log/gdbstub_backtrace.log");
+        cpu.load_program("20 04 80 EA A9 01 60".to_string()); // JSR $8004; NOP; LDA #$01; RTS
+        let mut registers = cpu.registers();
+        registers.prog_ctr = 0x8000;
+        cpu.set_registers(&registers);
+        let mut table = symbols::create_symbol_table();
+        table.add_cpu_label(0x8004, "load_one");
+        cpu.set_symbols(table);
+        cpu.step();
+        let output = reply(&mut stub, &mut cpu, &format!("qRcmd,{}", hex::encode("bt")));
+        let output = String::from_utf8(hex::decode(output).unwrap()).unwrap();
+        assert_eq!(output, "#0  $8004 <load_one>\n#1  $8003  JSR $8004 <load_one> from $8000\n");
+        let output = reply(&mut stub, &mut cpu, &format!("qRcmd,{}", hex::encode("disas 8004 2")));
+        let output = String::from_utf8(hex::decode(output).unwrap()).unwrap();
+        assert_eq!(output, "load_one:\n  $8004  LDA #$01\n  $8006  RTS\n");
+        let output = reply(&mut stub, &mut cpu, &format!("qRcmd,{}", hex::encode("disas 8000 99999999")));
+        assert!(output.len() <= PACKET_SIZE);
+        assert!(output.len() > PACKET_SIZE / 2);
+        match stub.handle_packet(&mut cpu, &format!("qRcmd,{}", hex::encode("finish"))) {
+            Action::Finish => {}
+            _ => panic!("expected finish to run the cpu"),
+        }
+        let output = stub.finish(&mut cpu, &mut || Ok(false)).unwrap();
+        assert_eq!(String::from_utf8(hex::decode(output).unwrap()).unwrap(), "stopped at $8003\n");
+    }
+
+    #[test]
+    fn finish_stops_at_breakpoints_and_interrupts() {
+        let mut stub = create_gdb_stub();
+        let mut cpu = olc6502::create_olc6502();
+        cpu.set_log_file("./log/gdbstub_finish.log");
+        cpu.load_program("20 04 80 EA E8 4C 04 80".to_string()); // JSR $8004; NOP; INX; JMP $8004
+        let mut registers = cpu.registers();
+        registers.prog_ctr = 0x8000;
+        cpu.set_registers(&registers);
+        cpu.step();
+        // the subroutine never returns, so only ctrl-c gets control back
+        let output = stub.finish(&mut cpu, &mut || Ok(true)).unwrap();
+        assert!(String::from_utf8(hex::decode(output).unwrap()).unwrap().starts_with("interrupted at $800"));
+        assert_eq!(stub.stop_reply(&cpu), "S02");
+        assert_eq!(reply(&mut stub, &mut cpu, "Z0,8005,1"), "OK");
+        let output = stub.finish(&mut cpu, &mut || Ok(false)).unwrap();
+        assert_eq!(String::from_utf8(hex::decode(output).unwrap()).unwrap(), "stopped at $8005\n");
+        assert_eq!(stub.stop_reply(&cpu), "S05");
+    }
+
+    #[test]
+    fn debug_session_over_tcp() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut stub = create_gdb_stub();
+            let mut cpu = create_test_cpu("./log/gdbstub_session.log");
+            let (stream, _) = listener.accept().unwrap();
+            stub.serve(&mut cpu, stream).unwrap();
+            return cpu.bus.read(0x0200);
+        });
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut exchange = |data: &str| -> String {
+            write_packet(&mut client, data).unwrap();
+            let mut ack = [0u8; 1];
+            client.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            let reply = read_packet(&mut client, false).unwrap().unwrap();
+            return reply;
+        };
+
+        assert_eq!(exchange("s"), "S05");
+        assert_eq!(exchange("p1"), "08");
+        assert_eq!(exchange("Z0,8006,1"), "OK");
+        assert_eq!(exchange("c"), "S05");
+        assert_eq!(exchange("p5"), "0680");
+        assert_eq!(exchange("c"), "S05"); // second time around the loop
+        assert_eq!(exchange("p1"), "06");
+        assert_eq!(exchange("D"), "OK");
+        assert_eq!(server.join().unwrap(), 0x06);
+    }
+}
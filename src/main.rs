@@ -1,11 +1,21 @@
+mod archive;
 mod bus;
+mod callstack;
 mod cartridge;
+mod cdl;
+mod fds;
+mod gdbstub;
 mod mapper;
 mod logline;
 mod nes;
 #[allow(non_snake_case)]
 mod olc2C02;
 mod olc6502;
+mod patch;
+mod profiler;
+mod romdb;
+mod symbols;
+mod unif;
 
 #[macro_use] extern crate lazy_static;
 
@@ -1,11 +1,161 @@
 #![allow(dead_code)]
+use super::fds;
 
 // Mapper documentation: http://wiki.nesdev.com/w/index.php/Mapper
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    Horizontal,        // $2000 = $2400, $2800 = $2C00
+    Vertical,          // $2000 = $2800, $2400 = $2C00
+    SingleScreenLower, // every nametable is the first 1k of CIRAM
+    SingleScreenUpper, // every nametable is the second 1k of CIRAM
+    FourScreen,        // 2k of extra VRAM on the cartridge
+}
+
+// where a cpu access to $4020-$FFFF ends up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuMapping {
+    PrgRom(u32), // offset into PRG ROM
+    PrgRam(u32), // offset into PRG RAM
+    Data(u8),    // value supplied by the mapper itself (registers, internal RAM)
+    Unmapped,    // open bus, or a write consumed by a mapper register
+}
+
+// where a ppu access to $0000-$3EFF ends up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuMapping {
+    Chr(u32),   // offset into CHR ROM/RAM
+    Ciram(u32), // offset into the console's 2k nametable RAM
+    Vram(u32),  // offset into nametable RAM on the cartridge (four screen boards)
+    Data(u8),   // value supplied by the mapper itself
+    Mirrored,   // nametable access resolved with the current mirroring mode
+    Unmapped,
+}
+
+/*
+ * A mapper sees every cpu access to $4020-$FFFF and every ppu access to
+ * $0000-$3EFF and decides where it lands.  The cartridge owns the memory
+ * itself (PRG ROM/RAM, CHR ROM/RAM); mappers only hold bank registers and
+ * whatever internal state the board has.
+ *
+ * The defaults describe a board with no registers: PRG ROM at $8000-$FFFF via
+ * map_address, PRG RAM at $6000-$7FFF, 8k of CHR at $0000-$1FFF and
+ * nametables mirrored as soldered on the board.
+ */
 pub trait Mapper {
+    // PRG ROM offset for a cpu address in $8000-$FFFF
     fn map_address(&self, input_addr: u16) -> u32;
+
+    // cpu read without side effects
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        return if addr >= 0x8000 {
+            CpuMapping::PrgRom(self.map_address(addr))
+        } else if addr >= 0x6000 {
+            CpuMapping::PrgRam((addr & 0x1FFF) as u32)
+        } else {
+            CpuMapping::Unmapped
+        }
+    }
+
+    // cpu read; mappers whose registers react to reads override this
+    fn cpu_read(&mut self, addr: u16) -> CpuMapping {
+        return self.cpu_peek(addr);
+    }
+
+    // returns where the written byte should be stored, if anywhere
+    fn cpu_write(&mut self, addr: u16, _data: u8) -> CpuMapping {
+        return if (0x6000..0x8000).contains(&addr) {
+            CpuMapping::PrgRam((addr & 0x1FFF) as u32)
+        } else {
+            CpuMapping::Unmapped
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        return if addr < 0x2000 {
+            PpuMapping::Chr(addr as u32)
+        } else {
+            PpuMapping::Mirrored
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> PpuMapping {
+        return self.ppu_read(addr);
+    }
+
+    // None means the mirroring is hardwired by the board
+    fn mirroring(&self) -> Option<Mirroring> {
+        return None;
+    }
+
+    // state of the cartridge's /IRQ output
+    fn irq_pending(&self) -> bool {
+        return false;
+    }
+
+    // called once per cpu cycle
+    fn cpu_clock(&mut self) {}
+
+    // called at the end of every scanline the ppu renders
+    fn scanline(&mut self) {}
+
+    // cpu writes to the ppu registers at $2000-$2007, for boards that watch them
+    fn ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    // called after the ppu has read addr, for boards whose banks switch on what was fetched
+    fn ppu_fetched(&mut self, _addr: u16) {}
+
+    // boards without a buffer on the data bus see PRG ROM driving it during
+    // register writes, so the written value is ANDed with the ROM byte
+    fn has_bus_conflicts(&self) -> bool {
+        return false;
+    }
+
+    // the Famicom Disk System's RAM adapter, which has a disk drive to control
+    fn fds(&mut self) -> Option<&mut fds::Fds> {
+        return None;
+    }
+
+    fn fds_ref(&self) -> Option<&fds::Fds> {
+        return None;
+    }
+}
+
+// CIRAM (or cartridge VRAM) offset for a nametable address under the given mirroring
+pub fn mirror_nametable(mirroring: Mirroring, addr: u16) -> PpuMapping {
+    let offset = (addr & 0x03FF) as u32;
+    let table = ((addr >> 10) & 0x03) as u32;
+    return match mirroring {
+        Mirroring::Horizontal => PpuMapping::Ciram(((table >> 1) << 10) | offset),
+        Mirroring::Vertical => PpuMapping::Ciram(((table & 0x01) << 10) | offset),
+        Mirroring::SingleScreenLower => PpuMapping::Ciram(offset),
+        Mirroring::SingleScreenUpper => PpuMapping::Ciram(0x400 | offset),
+        Mirroring::FourScreen => {
+            if table < 2 {
+                PpuMapping::Ciram((table << 10) | offset)
+            } else {
+                PpuMapping::Vram(((table - 2) << 10) | offset)
+            }
+        }
+    }
 }
 
+// what the cartridge header tells us about the board
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MapperConfig {
+    pub mapper_id: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize, // 0 means the board uses CHR RAM
+    pub prg_ram_size: usize,
+    pub chr_ram_size: usize,
+}
+
+const PRG_BANK_8K: usize = 0x2000;
+const PRG_BANK_16K: usize = 0x4000;
+const CHR_BANK_1K: usize = 0x0400;
+const CHR_BANK_8K: usize = 0x2000;
+
 pub struct NROM {
     num_prg_banks: u8,
     num_chr_banks: u8,
@@ -19,19 +169,1814 @@ impl Mapper for NROM {
         } else {
             (input_addr & 0x3FFF) as u32
         }
-    }
-}
-
-pub fn create_mapper(mapper_id: u8, num_prg_banks: u8, num_chr_banks: u8) -> Box<dyn Mapper> {
-    match mapper_id {
-        0 => {
-            return Box::new(NROM {
+    }
+}
+
+// MMC1 (SxROM): https://wiki.nesdev.com/w/index.php/MMC1
+pub struct MMC1 {
+    shift_register: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8,
+
+    cycle: u64,
+    last_write_cycle: Option<u64>,
+    last_chr_a12: bool, // which 4k CHR bank the ppu touched last
+
+    num_prg_banks: usize, // 16k banks
+    chr_is_ram: bool,
+    prg_ram_size: usize,
+}
+
+impl MMC1 {
+    fn load_register(&mut self, addr: u16, data: u8) {
+        match addr & 0xE000 {
+            0x8000 => self.control = data,
+            0xA000 => self.chr_bank0 = data,
+            0xC000 => self.chr_bank1 = data,
+            _ => self.prg_bank = data,
+        }
+    }
+
+    // on CHR RAM boards the upper CHR bank bits drive PRG RAM and PRG ROM lines instead.
+    // in 4k mode the register used is whichever one the ppu last selected with A12
+    fn board_select(&self) -> u8 {
+        return if self.control & 0x10 > 0 && self.last_chr_a12 {
+            self.chr_bank1
+        } else {
+            self.chr_bank0
+        }
+    }
+
+    // SUROM/SXROM: 512k of PRG ROM, upper 256k selected by CHR bank bit 4
+    fn prg_outer_bank(&self) -> usize {
+        return if self.num_prg_banks > 16 {
+            ((self.board_select() >> 4) & 0x01) as usize * 16
+        } else {
+            0
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        // SNROM also disables PRG RAM with CHR bank bit 4
+        let snrom_disable = self.chr_is_ram && self.num_prg_banks <= 16 && self.board_select() & 0x10 > 0;
+        return self.prg_ram_size > 0 && self.prg_bank & 0x10 == 0 && !snrom_disable;
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> u32 {
+        let bank = match self.prg_ram_size {
+            0x4000 => ((self.board_select() >> 3) & 0x01) as usize, // SOROM
+            0x8000 => ((self.board_select() >> 2) & 0x03) as usize, // SXROM
+            _ => 0,
+        };
+        return (bank * 0x2000 + (addr & 0x1FFF) as usize) as u32;
+    }
+}
+
+impl Mapper for MMC1 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let outer = self.prg_outer_bank();
+        let last_bank = usize::min(self.num_prg_banks, 16) - 1;
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let bank = match (self.control >> 2) & 0x03 {
+            0 | 1 => (bank & 0x0E) | ((input_addr as usize >> 14) & 0x01), // 32k mode
+            2 => if input_addr < 0xC000 { 0 } else { bank },                // first bank fixed at $8000
+            _ => if input_addr < 0xC000 { bank } else { last_bank },        // last bank fixed at $C000
+        };
+        return ((outer + bank) * PRG_BANK_16K + (input_addr as usize & 0x3FFF)) as u32;
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        return if addr >= 0x8000 {
+            CpuMapping::PrgRom(self.map_address(addr))
+        } else if addr >= 0x6000 && self.prg_ram_enabled() {
+            CpuMapping::PrgRam(self.prg_ram_offset(addr))
+        } else {
+            CpuMapping::Unmapped
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr < 0x8000 {
+            return if addr >= 0x6000 && self.prg_ram_enabled() {
+                CpuMapping::PrgRam(self.prg_ram_offset(addr))
+            } else {
+                CpuMapping::Unmapped
+            }
+        }
+
+        // the serial port ignores writes on consecutive cycles, so only the
+        // first (dummy) write of a read-modify-write instruction gets through
+        let consecutive = match self.last_write_cycle {
+            Some(last) => self.cycle <= last + 1,
+            None => false,
+        };
+        self.last_write_cycle = Some(self.cycle);
+        if consecutive {
+            return CpuMapping::Unmapped;
+        }
+
+        if data & 0x80 > 0 {
+            self.shift_register = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return CpuMapping::Unmapped;
+        }
+        self.shift_register |= (data & 0x01) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count == 5 {
+            let value = self.shift_register;
+            self.load_register(addr, value);
+            self.shift_register = 0;
+            self.shift_count = 0;
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        self.last_chr_a12 = addr & 0x1000 > 0;
+        let offset = if self.control & 0x10 > 0 {
+            let bank = if addr < 0x1000 { self.chr_bank0 } else { self.chr_bank1 };
+            (bank as u32) * 0x1000 + (addr & 0x0FFF) as u32
+        } else {
+            ((self.chr_bank0 & 0x1E) as u32) * 0x1000 + addr as u32
+        };
+        return PpuMapping::Chr(offset);
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        });
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle += 1;
+    }
+}
+
+// UxROM: 16k switchable at $8000, last bank fixed at $C000
+pub struct UxROM {
+    prg_bank: u8,
+    num_prg_banks: usize,
+    bus_conflicts: bool,
+}
+
+impl Mapper for UxROM {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let bank = if input_addr < 0xC000 {
+            self.prg_bank as usize % self.num_prg_banks
+        } else {
+            self.num_prg_banks - 1
+        };
+        return (bank * PRG_BANK_16K + (input_addr as usize & 0x3FFF)) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr >= 0x8000 {
+            self.prg_bank = data;
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        return self.bus_conflicts;
+    }
+}
+
+// CNROM: fixed PRG, 8k switchable CHR
+pub struct CNROM {
+    chr_bank: u8,
+    num_prg_banks: usize,
+    num_chr_banks: usize,
+    bus_conflicts: bool,
+}
+
+impl Mapper for CNROM {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let mask = if self.num_prg_banks > 1 { 0x7FFF } else { 0x3FFF };
+        return (input_addr & mask) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr >= 0x8000 {
+            self.chr_bank = data;
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let bank = self.chr_bank as usize % usize::max(self.num_chr_banks, 1);
+        return PpuMapping::Chr((bank * CHR_BANK_8K + addr as usize) as u32);
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        return self.bus_conflicts;
+    }
+}
+
+// AxROM: 32k switchable PRG, single screen mirroring selected by bit 4
+pub struct AxROM {
+    bank_select: u8,
+    num_prg_banks: usize, // 32k banks
+    bus_conflicts: bool,
+}
+
+impl Mapper for AxROM {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let bank = (self.bank_select & 0x07) as usize % self.num_prg_banks;
+        return (bank * 2 * PRG_BANK_16K + (input_addr as usize & 0x7FFF)) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr >= 0x8000 {
+            self.bank_select = data;
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(if self.bank_select & 0x10 > 0 {
+            Mirroring::SingleScreenUpper
+        } else {
+            Mirroring::SingleScreenLower
+        });
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        return self.bus_conflicts;
+    }
+}
+
+// MMC3 (TxROM): https://wiki.nesdev.com/w/index.php/MMC3
+pub struct MMC3 {
+    bank_select: u8,
+    bank_registers: [u8; 8],
+    horizontal_mirroring: bool,
+    prg_ram_protect: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_asserted: bool,
+    alternate_irq: bool, // NEC MMC3A behaviour: reloading a zero latch doesn't raise an IRQ
+
+    // A12 edge filter: rises only count after A12 has been low for a few cpu cycles
+    cycle: u64,
+    a12_high: bool,
+    a12_low_since: u64,
+
+    num_prg_banks: usize, // 8k banks
+    num_chr_banks: usize, // 1k banks
+}
+
+const MMC3_A12_FILTER_CYCLES: u64 = 3;
+
+impl MMC3 {
+    fn prg_bank(&self, input_addr: u16) -> usize {
+        let second_last = self.num_prg_banks - 2;
+        let window = ((input_addr >> 13) & 0x03) as usize;
+        let prg_inverted = self.bank_select & 0x40 > 0;
+        let bank = match (window, prg_inverted) {
+            (0, false) | (2, true) => self.bank_registers[6] as usize,
+            (0, true) | (2, false) => second_last,
+            (1, _) => self.bank_registers[7] as usize,
+            _ => self.num_prg_banks - 1,
+        };
+        return (bank & 0x3F) % self.num_prg_banks;
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        // with CHR inversion the 2k banks move to $1000 and the 1k banks to $0000
+        let addr = if self.bank_select & 0x80 > 0 { addr ^ 0x1000 } else { addr };
+        let bank = match addr >> 10 {
+            0 => self.bank_registers[0] & 0xFE,
+            1 => self.bank_registers[0] | 0x01,
+            2 => self.bank_registers[1] & 0xFE,
+            3 => self.bank_registers[1] | 0x01,
+            window => self.bank_registers[(window - 2) as usize],
+        };
+        return bank as usize % self.num_chr_banks;
+    }
+
+    fn track_a12(&mut self, addr: u16) {
+        let a12_high = addr & 0x1000 > 0;
+        if a12_high && !self.a12_high {
+            if self.cycle - self.a12_low_since >= MMC3_A12_FILTER_CYCLES {
+                self.clock_irq_counter();
+            }
+        } else if !a12_high && self.a12_high {
+            self.a12_low_since = self.cycle;
+        }
+        self.a12_high = a12_high;
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let previous = self.irq_counter;
+        let reloaded = self.irq_reload;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+        } else {
+            self.irq_counter -= 1;
+        }
+        self.irq_reload = false;
+
+        let triggers = !self.alternate_irq || previous != 0 || reloaded;
+        if self.irq_counter == 0 && self.irq_enabled && triggers {
+            self.irq_asserted = true;
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        return self.prg_ram_protect & 0x80 > 0;
+    }
+}
+
+impl Mapper for MMC3 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        return (self.prg_bank(input_addr) * PRG_BANK_8K + (input_addr as usize & 0x1FFF)) as u32;
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        return if addr >= 0x8000 {
+            CpuMapping::PrgRom(self.map_address(addr))
+        } else if addr >= 0x6000 && self.prg_ram_enabled() {
+            CpuMapping::PrgRam((addr & 0x1FFF) as u32)
+        } else {
+            CpuMapping::Unmapped
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr < 0x8000 {
+            let writable = self.prg_ram_enabled() && self.prg_ram_protect & 0x40 == 0;
+            return if addr >= 0x6000 && writable {
+                CpuMapping::PrgRam((addr & 0x1FFF) as u32)
+            } else {
+                CpuMapping::Unmapped
+            }
+        }
+
+        let even = addr & 0x01 == 0;
+        match (addr & 0xE000, even) {
+            (0x8000, true) => self.bank_select = data,
+            (0x8000, false) => self.bank_registers[(self.bank_select & 0x07) as usize] = data,
+            (0xA000, true) => self.horizontal_mirroring = data & 0x01 > 0,
+            (0xA000, false) => self.prg_ram_protect = data,
+            (0xC000, true) => self.irq_latch = data,
+            (0xC000, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                // disabling also acknowledges a pending IRQ
+                self.irq_enabled = false;
+                self.irq_asserted = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        self.track_a12(addr);
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        return PpuMapping::Chr((self.chr_bank(addr) * CHR_BANK_1K + (addr as usize & 0x03FF)) as u32);
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(if self.horizontal_mirroring { Mirroring::Horizontal } else { Mirroring::Vertical });
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq_asserted;
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle += 1;
+    }
+}
+
+// MMC2 (PxROM) and MMC4 (FxROM): CHR banks switch when the ppu fetches tile $FD or $FE
+// https://wiki.nesdev.com/w/index.php/MMC2
+pub struct MMC2 {
+    mmc4: bool,
+    prg_bank: u8,
+    chr_banks: [[u8; 2]; 2], // [pattern table][latch]
+    latches: [usize; 2],     // 0 = $FD, 1 = $FE
+    horizontal_mirroring: bool,
+    num_prg_banks: usize, // 8k banks
+    num_chr_banks: usize, // 4k banks
+}
+
+impl MMC2 {
+    // window 3 is the last 8k bank, window 2 the one before it and so on.
+    // Counted modulo the bank count so ROMs smaller than 32k mirror instead of underflowing
+    fn last_banks(&self, window: usize) -> usize {
+        return (self.num_prg_banks * 4 + window - 4) % self.num_prg_banks;
+    }
+}
+
+impl Mapper for MMC2 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let offset = input_addr as usize & 0x1FFF;
+        let window = ((input_addr >> 13) & 0x03) as usize;
+        let bank = if self.mmc4 {
+            // 16k switchable at $8000, last 16k fixed
+            if window < 2 { (self.prg_bank as usize & 0x0F) * 2 + window } else { self.last_banks(window) }
+        } else {
+            // 8k switchable at $8000, last three 8k banks fixed
+            if window == 0 { self.prg_bank as usize & 0x0F } else { self.last_banks(window) }
+        };
+        return ((bank % self.num_prg_banks) * PRG_BANK_8K + offset) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        match addr & 0xF000 {
+            0x6000 | 0x7000 => return CpuMapping::PrgRam((addr & 0x1FFF) as u32),
+            0xA000 => self.prg_bank = data,
+            0xB000 => self.chr_banks[0][0] = data & 0x1F,
+            0xC000 => self.chr_banks[0][1] = data & 0x1F,
+            0xD000 => self.chr_banks[1][0] = data & 0x1F,
+            0xE000 => self.chr_banks[1][1] = data & 0x1F,
+            0xF000 => self.horizontal_mirroring = data & 0x01 > 0,
+            _ => {}
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let table = (addr >> 12) as usize;
+        let bank = self.chr_banks[table][self.latches[table]] as usize % self.num_chr_banks;
+        return PpuMapping::Chr((bank * 0x1000 + (addr as usize & 0x0FFF)) as u32);
+    }
+
+    // the latch flips after the fetch, so the tile that triggers it still
+    // comes from the old bank
+    fn ppu_fetched(&mut self, addr: u16) {
+        if addr >= 0x2000 {
+            return;
+        }
+        let table = (addr >> 12) as usize;
+        // MMC2 only watches the first byte of the tile in the left pattern table
+        let trigger = if !self.mmc4 && table == 0 { addr } else { addr & 0x1FF8 };
+        match trigger & 0x0FFF {
+            0x0FD8 => self.latches[table] = 0,
+            0x0FE8 => self.latches[table] = 1,
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(if self.horizontal_mirroring { Mirroring::Horizontal } else { Mirroring::Vertical });
+    }
+}
+
+/*
+ * MMC5 (ExROM): https://wiki.nesdev.com/w/index.php/MMC5
+ *
+ * The MMC5 can't see the ppu's internal state, so it works out what the ppu
+ * is doing from the fetches it makes: three reads of the same nametable
+ * address (the two dummy fetches at the end of a line plus the first fetch of
+ * the next) start a scanline, and counting reads from there tells background
+ * fetches apart from sprite fetches.  It also watches cpu writes to PPUCTRL
+ * and PPUMASK for the sprite size and whether rendering is enabled.
+ */
+pub struct MMC5 {
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    prg_registers: [u8; 5], // $5113-$5117
+    chr_registers_a: [u16; 8], // $5120-$5127, sprites in 8x16 mode
+    chr_registers_b: [u16; 4], // $5128-$512B, background in 8x16 mode
+    chr_upper_bits: u8,
+    last_chr_set_b: bool,
+    exram: [u8; 0x400],
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicands: [u8; 2],
+
+    // what the ppu is up to
+    sprite_8x16: bool,
+    rendering_enabled: bool,
+    in_frame: bool,
+    scanline: u8,
+    last_read_addr: u16,
+    repeated_reads: u8,
+    fetch_count: usize, // ppu reads since the scanline started
+    cycles_since_read: u8,
+    ex_tile: u8,        // ExRAM byte for the background tile being fetched
+    split_tile: bool,   // the background tile being fetched is in the split region
+}
+
+const MMC5_SPRITE_FETCHES: std::ops::Range<usize> = 128..160;
+const MMC5_PREFETCHES: std::ops::Range<usize> = 160..168;
+
+impl MMC5 {
+    // (register, size in 8k banks) selected for a cpu address in $8000-$FFFF
+    fn prg_register(&self, addr: u16) -> (usize, usize) {
+        let window = ((addr >> 13) & 0x03) as usize;
+        return match (self.prg_mode, window) {
+            (0, _) => (4, 4),
+            (1, 0) | (1, 1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0) | (2, 1) => (2, 2),
+            (2, 2) => (3, 1),
+            (2, _) => (4, 1),
+            (_, window) => (window + 1, 1),
+        }
+    }
+
+    fn prg_mapping(&self, addr: u16) -> CpuMapping {
+        if addr < 0x8000 {
+            let bank = (self.prg_registers[0] & 0x0F) as usize;
+            return CpuMapping::PrgRam((bank * PRG_BANK_8K + (addr as usize & 0x1FFF)) as u32);
+        }
+        let (register, size) = self.prg_register(addr);
+        let value = self.prg_registers[register];
+        let window = ((addr as usize - 0x8000) / PRG_BANK_8K) % size;
+        let offset = addr as usize & 0x1FFF;
+        // $5117 always selects ROM, the others select ROM when bit 7 is set
+        return if register == 4 || value & 0x80 > 0 {
+            let bank = (value & 0x7F) as usize & !(size - 1);
+            CpuMapping::PrgRom(((bank + window) * PRG_BANK_8K + offset) as u32)
+        } else {
+            let bank = (value & 0x0F) as usize & !(size - 1);
+            CpuMapping::PrgRam(((bank + window) * PRG_BANK_8K + offset) as u32)
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        return self.prg_ram_protect == [0x02, 0x01];
+    }
+
+    fn chr_mapping(&self, addr: u16, use_set_b: bool) -> PpuMapping {
+        let size = 8 >> self.chr_mode; // in 1k banks
+        let bytes = size * CHR_BANK_1K;
+        let slot = (addr >> 10) as usize;
+        let bank = if use_set_b {
+            // set B only covers $0000-$0FFF and is mirrored at $1000
+            let slot = slot & 0x03;
+            let size_b = usize::min(size, 4);
+            self.chr_registers_b[(slot / size_b) * size_b + size_b - 1]
+        } else {
+            self.chr_registers_a[(slot / size) * size + size - 1]
+        };
+        return PpuMapping::Chr((bank as usize * bytes + (addr as usize % bytes)) as u32);
+    }
+
+    fn nametable_mapping(&self, addr: u16) -> PpuMapping {
+        let table = (addr >> 10) & 0x03;
+        let offset = (addr & 0x03FF) as usize;
+        return match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            0 => PpuMapping::Ciram(offset as u32),
+            1 => PpuMapping::Ciram((0x400 | offset) as u32),
+            2 => PpuMapping::Data(if self.exram_mode <= 1 { self.exram[offset] } else { 0x00 }),
+            _ => {
+                if offset >= 0x3C0 {
+                    PpuMapping::Data(self.fill_attribute * 0x55)
+                } else {
+                    PpuMapping::Data(self.fill_tile)
+                }
+            }
+        }
+    }
+
+    fn detect_scanline(&mut self, addr: u16) {
+        if addr == self.last_read_addr {
+            self.repeated_reads += 1;
+        } else {
+            self.repeated_reads = 0;
+        }
+        self.last_read_addr = addr;
+        if self.repeated_reads != 2 || addr < 0x2000 {
+            return;
+        }
+
+        self.fetch_count = 0;
+        if !self.in_frame {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        } else {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare && self.irq_compare != 0 {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    // background tile number (0-33) of the fetch in progress, or None for sprite fetches
+    fn background_tile(&self, fetch: usize) -> Option<(usize, bool)> {
+        return if fetch < MMC5_SPRITE_FETCHES.start {
+            Some((fetch / 4 + 2, false))
+        } else if MMC5_PREFETCHES.contains(&fetch) {
+            Some(((fetch - MMC5_PREFETCHES.start) / 4, true))
+        } else {
+            None
+        }
+    }
+
+    fn in_split(&self, tile: usize) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return false;
+        }
+        let threshold = (self.split_control & 0x1F) as usize;
+        return if self.split_control & 0x40 > 0 { tile >= threshold } else { tile < threshold };
+    }
+
+    fn split_y(&self, next_line: bool) -> usize {
+        let line = self.scanline as usize + if next_line { 1 } else { 0 };
+        return (self.split_scroll as usize + line) % 240;
+    }
+
+    // the ppu's background fetches, with ExRAM substituted for the vertical split
+    // and extended attribute modes
+    fn rendering_fetch(&mut self, addr: u16, fetch: usize) -> Option<PpuMapping> {
+        let (tile, next_line) = self.background_tile(fetch)?;
+        let step = fetch % 4;
+        if step == 0 {
+            self.split_tile = self.in_split(tile);
+        }
+        if self.split_tile {
+            let y = self.split_y(next_line);
+            return Some(match step {
+                0 => PpuMapping::Data(self.exram[(y / 8) * 32 + (tile & 0x1F)]),
+                1 => {
+                    let attribute = self.exram[0x3C0 + (y / 32) * 8 + (tile & 0x1F) / 4];
+                    let shift = ((y & 0x10) >> 2) | (tile & 0x02);
+                    PpuMapping::Data(((attribute >> shift) & 0x03) * 0x55)
+                }
+                _ => PpuMapping::Chr((self.split_bank as usize * 0x1000 + ((addr as usize & 0x0FF8) | (y & 0x07))) as u32),
+            });
+        }
+        if self.exram_mode != 1 {
+            return None;
+        }
+        return match step {
+            0 => {
+                self.ex_tile = self.exram[(addr & 0x03FF) as usize];
+                None
+            }
+            1 => Some(PpuMapping::Data((self.ex_tile >> 6) * 0x55)),
+            _ => {
+                let bank = (((self.chr_upper_bits & 0x03) as usize) << 6) | (self.ex_tile & 0x3F) as usize;
+                Some(PpuMapping::Chr((bank * 0x1000 + (addr as usize & 0x0FFF)) as u32))
+            }
+        }
+    }
+}
+
+impl Mapper for MMC5 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        return match self.prg_mapping(input_addr) {
+            CpuMapping::PrgRom(offset) => offset,
+            _ => 0,
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        return match addr {
+            0x5204 => CpuMapping::Data(((self.irq_pending as u8) << 7) | ((self.in_frame as u8) << 6)),
+            0x5205 => CpuMapping::Data((self.multiplicands[0] as u16 * self.multiplicands[1] as u16) as u8),
+            0x5206 => CpuMapping::Data(((self.multiplicands[0] as u16 * self.multiplicands[1] as u16) >> 8) as u8),
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => CpuMapping::Data(self.exram[(addr & 0x03FF) as usize]),
+            0x6000..=0xFFFF => self.prg_mapping(addr),
+            _ => CpuMapping::Unmapped,
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> CpuMapping {
+        let mapping = self.cpu_peek(addr);
+        if addr == 0x5204 {
+            self.irq_pending = false;
+        }
+        return mapping;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        match addr {
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.prg_ram_protect[0] = data & 0x03,
+            0x5103 => self.prg_ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0x03,
+            0x5113..=0x5117 => self.prg_registers[(addr - 0x5113) as usize] = data,
+            0x5120..=0x5127 => {
+                self.chr_registers_a[(addr - 0x5120) as usize] = ((self.chr_upper_bits as u16) << 8) | data as u16;
+                self.last_chr_set_b = false;
+            }
+            0x5128..=0x512B => {
+                self.chr_registers_b[(addr - 0x5128) as usize] = ((self.chr_upper_bits as u16) << 8) | data as u16;
+                self.last_chr_set_b = true;
+            }
+            0x5130 => self.chr_upper_bits = data & 0x03,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & 0x80 > 0,
+            0x5205 => self.multiplicands[0] = data,
+            0x5206 => self.multiplicands[1] = data,
+            0x5C00..=0x5FFF => {
+                // in the nametable modes ExRAM can only be written while the ppu is rendering
+                let offset = (addr & 0x03FF) as usize;
+                match self.exram_mode {
+                    0 | 1 => self.exram[offset] = if self.in_frame { data } else { 0x00 },
+                    2 => self.exram[offset] = data,
+                    _ => {}
+                }
+            }
+            0x6000..=0xFFFF if self.prg_ram_writable() => {
+                if let CpuMapping::PrgRam(offset) = self.prg_mapping(addr) {
+                    return CpuMapping::PrgRam(offset);
+                }
+            }
+            _ => {}
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        self.cycles_since_read = 0;
+        self.detect_scanline(addr);
+        let fetch = self.fetch_count;
+        self.fetch_count += 1;
+
+        let rendering = self.in_frame && self.rendering_enabled;
+        if rendering {
+            if let Some(mapping) = self.rendering_fetch(addr, fetch) {
+                return mapping;
+            }
+        }
+        if addr >= 0x2000 {
+            return self.nametable_mapping(addr);
+        }
+        let use_set_b = if self.sprite_8x16 && rendering {
+            !MMC5_SPRITE_FETCHES.contains(&fetch)
+        } else {
+            self.last_chr_set_b
+        };
+        return self.chr_mapping(addr, use_set_b);
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> PpuMapping {
+        if addr < 0x2000 {
+            return self.chr_mapping(addr, self.last_chr_set_b);
+        }
+        return match (self.nametable_mapping >> (((addr >> 10) & 0x03) * 2)) & 0x03 {
+            0 | 1 => self.nametable_mapping(addr),
+            2 => {
+                if self.exram_mode <= 1 {
+                    self.exram[(addr & 0x03FF) as usize] = data;
+                }
+                PpuMapping::Unmapped
+            }
+            _ => PpuMapping::Unmapped,
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        match addr & 0x0007 {
+            0 => self.sprite_8x16 = data & 0x20 > 0,
+            1 => {
+                self.rendering_enabled = data & 0x18 > 0;
+                if !self.rendering_enabled {
+                    self.in_frame = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq_pending && self.irq_enabled;
+    }
+
+    // the ppu stops fetching during vblank, which ends the frame
+    fn cpu_clock(&mut self) {
+        if self.cycles_since_read < 3 {
+            self.cycles_since_read += 1;
+        }
+        if self.cycles_since_read >= 3 {
+            self.in_frame = false;
+            self.repeated_reads = 0;
+        }
+    }
+}
+
+/*
+ * Konami VRC family: https://wiki.nesdev.com/w/index.php/VRC2_and_VRC4
+ *
+ * Boards wire the chips' two register select pins to different cpu address
+ * lines, so each variant is described by the address bits feeding them.
+ * Without a submapper to tell the variants apart, both wirings a mapper
+ * number is used for are decoded at once, which works since games only
+ * ever write to one set of addresses.
+ */
+
+// the IRQ counter shared by VRC4, VRC6 and VRC7
+struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    asserted: bool,
+}
+
+const VRC_PRESCALER_RELOAD: i16 = 341;
+
+impl VrcIrq {
+    fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0x01 > 0;
+        self.enabled = data & 0x02 > 0;
+        self.cycle_mode = data & 0x04 > 0;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = VRC_PRESCALER_RELOAD;
+        }
+        self.asserted = false;
+    }
+
+    fn acknowledge(&mut self) {
+        self.asserted = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    // scanline mode divides the cpu clock by 113.667 (341 / 3) to approximate scanlines
+    fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if !self.cycle_mode {
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return;
+            }
+            self.prescaler += VRC_PRESCALER_RELOAD;
+        }
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.asserted = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+fn create_vrc_irq() -> VrcIrq {
+    return VrcIrq {
+        latch: 0,
+        counter: 0,
+        prescaler: VRC_PRESCALER_RELOAD,
+        enabled: false,
+        enable_after_ack: false,
+        cycle_mode: false,
+        asserted: false,
+    };
+}
+
+// the common 2 bit mirroring register: vertical, horizontal, single screen lower, upper
+fn register_mirroring(data: u8) -> Mirroring {
+    return match data & 0x03 {
+        0 => Mirroring::Vertical,
+        1 => Mirroring::Horizontal,
+        2 => Mirroring::SingleScreenLower,
+        _ => Mirroring::SingleScreenUpper,
+    }
+}
+
+// VRC2 and VRC4 (mappers 21, 22, 23 and 25)
+pub struct VRC4 {
+    vrc2: bool,
+    select_lines: (u16, u16), // address bits wired to the chip's A0 and A1
+    chr_shift: u8,            // VRC2a ignores the low bit of CHR bank numbers
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    mirroring: u8,
+    chr_banks: [u16; 8],
+    irq: VrcIrq,
+    num_prg_banks: usize, // 8k banks
+}
+
+impl VRC4 {
+    fn register_select(&self, addr: u16) -> u16 {
+        let a0 = if addr & self.select_lines.0 > 0 { 1 } else { 0 };
+        let a1 = if addr & self.select_lines.1 > 0 { 2 } else { 0 };
+        return a0 | a1;
+    }
+}
+
+impl Mapper for VRC4 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let second_last = self.num_prg_banks - 2;
+        let bank = match ((input_addr >> 13) & 0x03, self.prg_swap) {
+            (0, false) | (2, true) => self.prg_banks[0] as usize & 0x1F,
+            (0, true) | (2, false) => second_last,
+            (1, _) => self.prg_banks[1] as usize & 0x1F,
+            _ => self.num_prg_banks - 1,
+        };
+        return ((bank % self.num_prg_banks) * PRG_BANK_8K + (input_addr as usize & 0x1FFF)) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr < 0x8000 {
+            return if addr >= 0x6000 { CpuMapping::PrgRam((addr & 0x1FFF) as u32) } else { CpuMapping::Unmapped };
+        }
+        let select = self.register_select(addr);
+        match (addr & 0xF000, select) {
+            (0x8000, _) => self.prg_banks[0] = data,
+            (0x9000, 0) | (0x9000, 1) => self.mirroring = if self.vrc2 { data & 0x01 } else { data & 0x03 },
+            (0x9000, 2) => {
+                if !self.vrc2 {
+                    self.prg_swap = data & 0x02 > 0;
+                }
+            }
+            (0x9000, _) => {} // $9003 has no register
+            (0xA000, _) => self.prg_banks[1] = data,
+            (0xB000..=0xE000, _) => {
+                let register = (((addr - 0xB000) >> 12) * 2 + (select >> 1)) as usize;
+                let bank = self.chr_banks[register];
+                self.chr_banks[register] = if select & 0x01 == 0 {
+                    (bank & 0x1F0) | (data & 0x0F) as u16
+                } else {
+                    (bank & 0x00F) | (((data & 0x1F) as u16) << 4)
+                };
+            }
+            (_, 0) if !self.vrc2 => self.irq.latch = (self.irq.latch & 0xF0) | (data & 0x0F),
+            (_, 1) if !self.vrc2 => self.irq.latch = (self.irq.latch & 0x0F) | (data << 4),
+            (_, 2) if !self.vrc2 => self.irq.write_control(data),
+            (_, 3) if !self.vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let bank = (self.chr_banks[(addr >> 10) as usize] >> self.chr_shift) as usize;
+        return PpuMapping::Chr((bank * CHR_BANK_1K + (addr as usize & 0x03FF)) as u32);
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(register_mirroring(self.mirroring));
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq.asserted;
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+}
+
+// VRC6 (mappers 24 and 26, which swap A0 and A1)
+pub struct VRC6 {
+    swapped_lines: bool,
+    prg_16k_bank: u8,
+    prg_8k_bank: u8,
+    banking_control: u8,
+    chr_banks: [u8; 8],
+    irq: VrcIrq,
+    num_prg_banks: usize, // 8k banks
+}
+
+impl Mapper for VRC6 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let offset = input_addr as usize & 0x1FFF;
+        let bank = match input_addr {
+            0x8000..=0xBFFF => (self.prg_16k_bank as usize & 0x0F) * 2 + ((input_addr as usize >> 13) & 0x01),
+            0xC000..=0xDFFF => self.prg_8k_bank as usize & 0x1F,
+            _ => self.num_prg_banks - 1,
+        };
+        return ((bank % self.num_prg_banks) * PRG_BANK_8K + offset) as u32;
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        return match addr {
+            0x8000..=0xFFFF => CpuMapping::PrgRom(self.map_address(addr)),
+            0x6000..=0x7FFF if self.banking_control & 0x80 > 0 => CpuMapping::PrgRam((addr & 0x1FFF) as u32),
+            _ => CpuMapping::Unmapped,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr < 0x8000 {
+            return self.cpu_peek(addr);
+        }
+        let select = if self.swapped_lines {
+            ((addr & 0x01) << 1) | ((addr >> 1) & 0x01)
+        } else {
+            addr & 0x03
+        };
+        match (addr & 0xF000, select) {
+            (0x8000, _) => self.prg_16k_bank = data,
+            (0xB000, 3) => self.banking_control = data,
+            (0xC000, _) => self.prg_8k_bank = data,
+            (0xD000, register) => self.chr_banks[register as usize] = data,
+            (0xE000, register) => self.chr_banks[4 + register as usize] = data,
+            (0xF000, 0) => self.irq.latch = data,
+            (0xF000, 1) => self.irq.write_control(data),
+            (0xF000, 2) => self.irq.acknowledge(),
+            _ => {} // $9000-$B002 are the expansion audio channels
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let slot = (addr >> 10) as usize;
+        let a10 = slot & 0x01;
+        let bank = match self.banking_control & 0x03 {
+            0 => self.chr_banks[slot] as usize,
+            1 => (self.chr_banks[slot / 2] as usize & 0xFE) | a10,
+            _ => {
+                if slot < 4 {
+                    self.chr_banks[slot] as usize
+                } else {
+                    (self.chr_banks[4 + (slot - 4) / 2] as usize & 0xFE) | a10
+                }
+            }
+        };
+        return PpuMapping::Chr((bank * CHR_BANK_1K + (addr as usize & 0x03FF)) as u32);
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(register_mirroring(self.banking_control >> 2));
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq.asserted;
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+}
+
+// VRC7 (mapper 85); VRC7a selects registers with A4, VRC7b with A3
+pub struct VRC7 {
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    control: u8,
+    irq: VrcIrq,
+    num_prg_banks: usize, // 8k banks
+}
+
+impl Mapper for VRC7 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let window = ((input_addr >> 13) & 0x03) as usize;
+        let bank = if window < 3 { self.prg_banks[window] as usize & 0x3F } else { self.num_prg_banks - 1 };
+        return ((bank % self.num_prg_banks) * PRG_BANK_8K + (input_addr as usize & 0x1FFF)) as u32;
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        return match addr {
+            0x8000..=0xFFFF => CpuMapping::PrgRom(self.map_address(addr)),
+            0x6000..=0x7FFF if self.control & 0x80 > 0 => CpuMapping::PrgRam((addr & 0x1FFF) as u32),
+            _ => CpuMapping::Unmapped,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr < 0x8000 {
+            return self.cpu_peek(addr);
+        }
+        let select = if addr & 0x0018 > 0 { 1 } else { 0 };
+        match (addr & 0xF000, select) {
+            (0x8000, register) => self.prg_banks[register] = data,
+            (0x9000, 0) => self.prg_banks[2] = data,
+            (0xA000..=0xD000, register) => self.chr_banks[(((addr - 0xA000) >> 12) * 2) as usize + register] = data,
+            (0xE000, 0) => self.control = data,
+            (0xE000, _) => self.irq.latch = data,
+            (0xF000, 0) => self.irq.write_control(data),
+            (0xF000, _) => self.irq.acknowledge(),
+            _ => {} // $9010 and $9030 drive the FM synthesizer
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let bank = self.chr_banks[(addr >> 10) as usize] as usize;
+        return PpuMapping::Chr((bank * CHR_BANK_1K + (addr as usize & 0x03FF)) as u32);
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(register_mirroring(self.control));
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq.asserted;
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+}
+
+// (VRC2, select lines, CHR shift) for the VRC2/VRC4 mapper numbers
+fn vrc4_variant(mapper_id: u16, submapper: u8) -> (bool, (u16, u16), u8) {
+    return match (mapper_id, submapper) {
+        (21, 1) => (false, (0x0002, 0x0004), 0), // VRC4a
+        (21, 2) => (false, (0x0040, 0x0080), 0), // VRC4c
+        (21, _) => (false, (0x0042, 0x0084), 0),
+        (22, _) => (true, (0x0002, 0x0001), 1),  // VRC2a
+        (23, 1) => (false, (0x0001, 0x0002), 0), // VRC4f
+        (23, 2) => (false, (0x0004, 0x0008), 0), // VRC4e
+        (23, 3) => (true, (0x0001, 0x0002), 0),  // VRC2b
+        (23, _) => (false, (0x0005, 0x000A), 0),
+        (25, 1) => (false, (0x0002, 0x0001), 0), // VRC4b
+        (25, 2) => (false, (0x0008, 0x0004), 0), // VRC4d
+        (25, 3) => (true, (0x0002, 0x0001), 0),  // VRC2c
+        (_, _) => (false, (0x000A, 0x0005), 0),
+    }
+}
+
+// Sunsoft FME-7, 5A and 5B: https://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+pub struct FME7 {
+    command: u8,
+    chr_banks: [u8; 8],
+    prg_banks: [u8; 4], // $6000, $8000, $A000, $C000
+    mirroring: u8,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_asserted: bool,
+    num_prg_banks: usize, // 8k banks
+}
+
+impl Mapper for FME7 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let window = ((input_addr >> 13) & 0x03) as usize;
+        let bank = if window < 3 { self.prg_banks[window + 1] as usize & 0x3F } else { self.num_prg_banks - 1 };
+        return ((bank % self.num_prg_banks) * PRG_BANK_8K + (input_addr as usize & 0x1FFF)) as u32;
+    }
+
+    // $6000 holds either a ROM bank or (bits 6 and 7 set) PRG RAM
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        if addr >= 0x8000 {
+            return CpuMapping::PrgRom(self.map_address(addr));
+        } else if addr < 0x6000 {
+            return CpuMapping::Unmapped;
+        }
+        let select = self.prg_banks[0];
+        let offset = (select & 0x3F) as usize * PRG_BANK_8K + (addr as usize & 0x1FFF);
+        return match select & 0xC0 {
+            0xC0 => CpuMapping::PrgRam((addr & 0x1FFF) as u32),
+            0x40 => CpuMapping::Unmapped,
+            _ => CpuMapping::PrgRom(offset as u32),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.prg_banks[0] & 0xC0 == 0xC0 {
+                    return CpuMapping::PrgRam((addr & 0x1FFF) as u32);
+                }
+            }
+            0x8000..=0x9FFF => self.command = data & 0x0F,
+            0xA000..=0xBFFF => {
+                match self.command {
+                    0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+                    0x8..=0xB => self.prg_banks[(self.command - 0x8) as usize] = data,
+                    0xC => self.mirroring = data & 0x03,
+                    0xD => {
+                        self.irq_enabled = data & 0x01 > 0;
+                        self.irq_counter_enabled = data & 0x80 > 0;
+                        self.irq_asserted = false;
+                    }
+                    0xE => self.irq_counter = (self.irq_counter & 0xFF00) | data as u16,
+                    _ => self.irq_counter = (self.irq_counter & 0x00FF) | ((data as u16) << 8),
+                }
+            }
+            _ => {} // $C000-$FFFF drive the 5B's audio
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let bank = self.chr_banks[(addr >> 10) as usize] as usize;
+        return PpuMapping::Chr((bank * CHR_BANK_1K + (addr as usize & 0x03FF)) as u32);
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(register_mirroring(self.mirroring));
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq_asserted;
+    }
+
+    // the counter decrements every cpu cycle and fires when it wraps past zero
+    fn cpu_clock(&mut self) {
+        if !self.irq_counter_enabled {
+            return;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0xFFFF && self.irq_enabled {
+            self.irq_asserted = true;
+        }
+    }
+}
+
+// Namco 163: https://wiki.nesdev.com/w/index.php/INES_Mapper_019
+pub struct N163 {
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    prg_banks: [u8; 3],
+    chr_ram_disabled: [bool; 2], // CIRAM can't replace CHR at $0000 / $1000 when set
+    write_protect: u8,
+    internal_ram: [u8; 0x80], // the sound chip's RAM, also used for saves
+    ram_address: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_asserted: bool,
+    num_prg_banks: usize, // 8k banks
+}
+
+impl N163 {
+    // banks $E0-$FF select a page of CIRAM instead of CHR ROM
+    fn bank_mapping(bank: u8, addr: u16, ciram_allowed: bool) -> PpuMapping {
+        return if bank >= 0xE0 && ciram_allowed {
+            PpuMapping::Ciram((((bank & 0x01) as u32) << 10) | (addr & 0x03FF) as u32)
+        } else {
+            PpuMapping::Chr((bank as usize * CHR_BANK_1K + (addr as usize & 0x03FF)) as u32)
+        }
+    }
+
+    // bit 7 of the address port auto-increments it on every data port access
+    fn increment_ram_address(&mut self) {
+        if self.ram_address & 0x80 > 0 {
+            self.ram_address = 0x80 | (self.ram_address.wrapping_add(1) & 0x7F);
+        }
+    }
+
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let segment = (addr - 0x6000) >> 11;
+        return self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << segment) == 0;
+    }
+}
+
+impl Mapper for N163 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let window = ((input_addr >> 13) & 0x03) as usize;
+        let bank = if window < 3 { self.prg_banks[window] as usize & 0x3F } else { self.num_prg_banks - 1 };
+        return ((bank % self.num_prg_banks) * PRG_BANK_8K + (input_addr as usize & 0x1FFF)) as u32;
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuMapping {
+        return match addr {
+            0x4800..=0x4FFF => CpuMapping::Data(self.internal_ram[(self.ram_address & 0x7F) as usize]),
+            0x5000..=0x57FF => CpuMapping::Data(self.irq_counter as u8),
+            0x5800..=0x5FFF => CpuMapping::Data(((self.irq_enabled as u8) << 7) | (self.irq_counter >> 8) as u8),
+            0x6000..=0x7FFF => CpuMapping::PrgRam((addr & 0x1FFF) as u32),
+            0x8000..=0xFFFF => CpuMapping::PrgRom(self.map_address(addr)),
+            _ => CpuMapping::Unmapped,
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> CpuMapping {
+        let mapping = self.cpu_peek(addr);
+        if (0x4800..0x5000).contains(&addr) {
+            self.increment_ram_address();
+        }
+        return mapping;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        match addr {
+            0x4800..=0x4FFF => {
+                self.internal_ram[(self.ram_address & 0x7F) as usize] = data;
+                self.increment_ram_address();
+            }
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | data as u16;
+                self.irq_asserted = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (((data & 0x7F) as u16) << 8);
+                self.irq_enabled = data & 0x80 > 0;
+                self.irq_asserted = false;
+            }
+            0x6000..=0x7FFF => {
+                if self.prg_ram_writable(addr) {
+                    return CpuMapping::PrgRam((addr & 0x1FFF) as u32);
+                }
+            }
+            0x8000..=0xBFFF => self.chr_banks[((addr - 0x8000) >> 11) as usize] = data,
+            0xC000..=0xDFFF => self.nametable_banks[((addr - 0xC000) >> 11) as usize] = data,
+            0xE000..=0xE7FF => self.prg_banks[0] = data, // bit 6 disables sound
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = data;
+                self.chr_ram_disabled = [data & 0x40 > 0, data & 0x80 > 0];
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = data,
+            _ => {
+                // $F800 is both the PRG RAM write protect and the internal RAM address port
+                self.write_protect = data;
+                self.ram_address = data;
+            }
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        let slot = ((addr >> 10) & 0x07) as usize;
+        return if addr < 0x2000 {
+            let ciram_allowed = !self.chr_ram_disabled[slot / 4];
+            N163::bank_mapping(self.chr_banks[slot], addr, ciram_allowed)
+        } else {
+            N163::bank_mapping(self.nametable_banks[slot & 0x03], addr, true)
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq_asserted;
+    }
+
+    // the counter counts up every cpu cycle and stops at $7FFF
+    fn cpu_clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                self.irq_asserted = true;
+            }
+        }
+    }
+}
+
+// extracts a (shift, mask) bit field from a latched register
+fn latch_field(value: u8, field: (u8, u8)) -> usize {
+    return ((value >> field.0) & field.1) as usize;
+}
+
+// boards with one latch selecting a 32k PRG bank and an 8k CHR bank:
+// GxROM (66), Color Dreams (11), BNROM (34), NINA-03/06 (79) and Jaleco JF-11/14 (140)
+pub struct Latch32k {
+    register: (u16, u16), // writes land in the latch when addr & .0 == .1
+    prg_field: (u8, u8),
+    chr_field: (u8, u8),
+    latch: u8,
+    num_prg_banks: usize, // 32k banks
+    num_chr_banks: usize,
+    bus_conflicts: bool,
+}
+
+impl Mapper for Latch32k {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let bank = latch_field(self.latch, self.prg_field) % self.num_prg_banks;
+        return (bank * 2 * PRG_BANK_16K + (input_addr as usize & 0x7FFF)) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr & self.register.0 == self.register.1 {
+            self.latch = data;
+            return CpuMapping::Unmapped;
+        }
+        return if (0x6000..0x8000).contains(&addr) { CpuMapping::PrgRam((addr & 0x1FFF) as u32) } else { CpuMapping::Unmapped };
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let bank = latch_field(self.latch, self.chr_field) % usize::max(self.num_chr_banks, 1);
+        return PpuMapping::Chr((bank * CHR_BANK_8K + addr as usize) as u32);
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        return self.bus_conflicts;
+    }
+}
+
+// boards with one latch selecting a 16k PRG bank at $8000 (last bank fixed), an
+// 8k CHR bank and the mirroring: Irem 74HC161 (78) and Bandai 74161 (152)
+pub struct Latch16k {
+    prg_field: (u8, u8),
+    chr_field: (u8, u8),
+    mirroring_bit: u8,
+    mirroring_modes: (Mirroring, Mirroring), // bit clear, bit set
+    latch: u8,
+    num_prg_banks: usize, // 16k banks
+    num_chr_banks: usize,
+    bus_conflicts: bool,
+}
+
+impl Mapper for Latch16k {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let bank = if input_addr < 0xC000 {
+            latch_field(self.latch, self.prg_field) % self.num_prg_banks
+        } else {
+            self.num_prg_banks - 1
+        };
+        return (bank * PRG_BANK_16K + (input_addr as usize & 0x3FFF)) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        if addr >= 0x8000 {
+            self.latch = data;
+            return CpuMapping::Unmapped;
+        }
+        return if addr >= 0x6000 { CpuMapping::PrgRam((addr & 0x1FFF) as u32) } else { CpuMapping::Unmapped };
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let bank = latch_field(self.latch, self.chr_field) % usize::max(self.num_chr_banks, 1);
+        return PpuMapping::Chr((bank * CHR_BANK_8K + addr as usize) as u32);
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return Some(if self.latch & self.mirroring_bit > 0 { self.mirroring_modes.1 } else { self.mirroring_modes.0 });
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        return self.bus_conflicts;
+    }
+}
+
+// NINA-001 (34): registers at $7FFD-$7FFF, which also write through to PRG RAM
+pub struct NINA001 {
+    prg_bank: u8,
+    chr_banks: [u8; 2],
+    num_prg_banks: usize, // 32k banks
+    num_chr_banks: usize, // 4k banks
+}
+
+impl Mapper for NINA001 {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let bank = (self.prg_bank & 0x01) as usize % self.num_prg_banks;
+        return (bank * 2 * PRG_BANK_16K + (input_addr as usize & 0x7FFF)) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        match addr {
+            0x7FFD => self.prg_bank = data,
+            0x7FFE => self.chr_banks[0] = data & 0x0F,
+            0x7FFF => self.chr_banks[1] = data & 0x0F,
+            _ => {}
+        }
+        return if (0x6000..0x8000).contains(&addr) { CpuMapping::PrgRam((addr & 0x1FFF) as u32) } else { CpuMapping::Unmapped };
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> PpuMapping {
+        if addr >= 0x2000 {
+            return PpuMapping::Mirrored;
+        }
+        let bank = self.chr_banks[(addr >> 12) as usize] as usize % usize::max(self.num_chr_banks, 1);
+        return PpuMapping::Chr((bank * 0x1000 + (addr as usize & 0x0FFF)) as u32);
+    }
+}
+
+// Camerica/Codemasters (71): UxROM-like banking at $C000, and on the Fire Hawk
+// board (submapper 1) single screen mirroring selected at $9000
+pub struct Camerica {
+    prg_bank: u8,
+    mirroring_control: bool, // $9000-$9FFF selects the single screen
+    single_screen: Option<Mirroring>, // None until the game picks one, then overrides the header
+    num_prg_banks: usize, // 16k banks
+}
+
+impl Mapper for Camerica {
+
+    fn map_address(&self, input_addr: u16) -> u32 {
+        let bank = if input_addr < 0xC000 {
+            self.prg_bank as usize % self.num_prg_banks
+        } else {
+            self.num_prg_banks - 1
+        };
+        return (bank * PRG_BANK_16K + (input_addr as usize & 0x3FFF)) as u32;
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> CpuMapping {
+        match addr {
+            0x9000..=0x9FFF if self.mirroring_control => {
+                self.single_screen = Some(if data & 0x10 > 0 { Mirroring::SingleScreenUpper } else { Mirroring::SingleScreenLower });
+            }
+            0xC000..=0xFFFF => self.prg_bank = data,
+            _ => {}
+        }
+        return CpuMapping::Unmapped;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        return self.single_screen;
+    }
+}
+
+// None if the board isn't supported
+pub fn create_mapper(config: &MapperConfig) -> Option<Box<dyn Mapper>> {
+    let num_prg_banks = usize::max(config.prg_rom_size / PRG_BANK_16K, 1);
+    // boards without CHR ROM bank their CHR RAM the same way
+    let chr_size = if config.chr_rom_size > 0 { config.chr_rom_size } else { config.chr_ram_size };
+    let num_chr_banks = chr_size / CHR_BANK_8K;
+    // NES 2.0 submapper 2 marks UxROM, CNROM and AxROM boards with bus conflicts; submapper 0
+    // leaves them off, since plenty of dumps in the wild rely on emulators ignoring them.
+    // the other discrete latches always have them
+    let bus_conflicts = match config.mapper_id {
+        2 | 3 | 7 => config.submapper == 2,
+        _ => true,
+    };
+    match config.mapper_id {
+        0 => {
+            return Some(Box::new(NROM {
+                num_prg_banks: num_prg_banks as u8,
+                num_chr_banks: num_chr_banks as u8,
+            }));
+        }
+        1 => {
+            return Some(Box::new(MMC1 {
+                shift_register: 0,
+                shift_count: 0,
+                control: 0x0C, // power on with the last bank fixed at $C000
+                chr_bank0: 0,
+                chr_bank1: 0,
+                prg_bank: 0,
+                cycle: 0,
+                last_write_cycle: None,
+                last_chr_a12: false,
+                num_prg_banks,
+                chr_is_ram: config.chr_rom_size == 0,
+                prg_ram_size: config.prg_ram_size,
+            }));
+        }
+        2 => {
+            return Some(Box::new(UxROM {
+                prg_bank: 0,
+                num_prg_banks,
+                bus_conflicts,
+            }));
+        }
+        3 => {
+            return Some(Box::new(CNROM {
+                chr_bank: 0,
+                num_prg_banks,
+                num_chr_banks,
+                bus_conflicts,
+            }));
+        }
+        7 => {
+            return Some(Box::new(AxROM {
+                bank_select: 0,
+                num_prg_banks: usize::max(num_prg_banks / 2, 1),
+                bus_conflicts,
+            }));
+        }
+        4 => {
+            return Some(Box::new(MMC3 {
+                bank_select: 0,
+                bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+                horizontal_mirroring: false,
+                prg_ram_protect: 0x80,
+                irq_latch: 0,
+                irq_counter: 0,
+                irq_reload: false,
+                irq_enabled: false,
+                irq_asserted: false,
+                alternate_irq: config.submapper == 4,
+                cycle: 0,
+                a12_high: false,
+                a12_low_since: 0,
+                num_prg_banks: num_prg_banks * 2,
+                num_chr_banks: usize::max(chr_size / CHR_BANK_1K, 8),
+            }));
+        }
+        5 => {
+            return Some(Box::new(MMC5 {
+                prg_mode: 3,
+                chr_mode: 0,
+                prg_ram_protect: [0; 2],
+                exram_mode: 0,
+                nametable_mapping: 0,
+                fill_tile: 0,
+                fill_attribute: 0,
+                prg_registers: [0, 0, 0, 0, 0xFF],
+                chr_registers_a: [0; 8],
+                chr_registers_b: [0; 4],
+                chr_upper_bits: 0,
+                last_chr_set_b: false,
+                exram: [0; 0x400],
+                split_control: 0,
+                split_scroll: 0,
+                split_bank: 0,
+                irq_compare: 0,
+                irq_enabled: false,
+                irq_pending: false,
+                multiplicands: [0xFF; 2],
+                sprite_8x16: false,
+                rendering_enabled: true,
+                in_frame: false,
+                scanline: 0,
+                last_read_addr: 0,
+                repeated_reads: 0,
+                fetch_count: 0,
+                cycles_since_read: 0,
+                ex_tile: 0,
+                split_tile: false,
+            }));
+        }
+        9 | 10 => {
+            return Some(Box::new(MMC2 {
+                mmc4: config.mapper_id == 10,
+                prg_bank: 0,
+                chr_banks: [[0; 2]; 2],
+                latches: [1, 1],
+                horizontal_mirroring: false,
+                num_prg_banks: num_prg_banks * 2,
+                num_chr_banks: usize::max(chr_size / 0x1000, 1),
+            }));
+        }
+        21 | 22 | 23 | 25 => {
+            let (vrc2, select_lines, chr_shift) = vrc4_variant(config.mapper_id, config.submapper);
+            return Some(Box::new(VRC4 {
+                vrc2,
+                select_lines,
+                chr_shift,
+                prg_banks: [0, 1],
+                prg_swap: false,
+                mirroring: 0,
+                chr_banks: [0; 8],
+                irq: create_vrc_irq(),
+                num_prg_banks: num_prg_banks * 2,
+            }));
+        }
+        24 | 26 => {
+            return Some(Box::new(VRC6 {
+                swapped_lines: config.mapper_id == 26,
+                prg_16k_bank: 0,
+                prg_8k_bank: 0,
+                banking_control: 0,
+                chr_banks: [0; 8],
+                irq: create_vrc_irq(),
+                num_prg_banks: num_prg_banks * 2,
+            }));
+        }
+        19 => {
+            return Some(Box::new(N163 {
+                chr_banks: [0; 8],
+                nametable_banks: [0xE0, 0xE1, 0xE0, 0xE1],
+                prg_banks: [0; 3],
+                chr_ram_disabled: [false; 2],
+                write_protect: 0,
+                internal_ram: [0; 0x80],
+                ram_address: 0,
+                irq_counter: 0,
+                irq_enabled: false,
+                irq_asserted: false,
+                num_prg_banks: num_prg_banks * 2,
+            }));
+        }
+        69 => {
+            return Some(Box::new(FME7 {
+                command: 0,
+                chr_banks: [0; 8],
+                prg_banks: [0; 4],
+                mirroring: 0,
+                irq_enabled: false,
+                irq_counter_enabled: false,
+                irq_counter: 0,
+                irq_asserted: false,
+                num_prg_banks: num_prg_banks * 2,
+            }));
+        }
+        85 => {
+            return Some(Box::new(VRC7 {
+                prg_banks: [0; 3],
+                chr_banks: [0; 8],
+                control: 0,
+                irq: create_vrc_irq(),
+                num_prg_banks: num_prg_banks * 2,
+            }));
+        }
+        11 | 66 | 79 | 140 => {
+            // (register, PRG field, CHR field)
+            let (register, prg_field, chr_field) = match config.mapper_id {
+                11 => ((0x8000, 0x8000), (0, 0x03), (4, 0x0F)),
+                66 => ((0x8000, 0x8000), (4, 0x03), (0, 0x03)),
+                79 => ((0xE100, 0x4100), (3, 0x01), (0, 0x07)),
+                _ => ((0xE000, 0x6000), (4, 0x03), (0, 0x0F)),
+            };
+            return Some(Box::new(Latch32k {
+                register,
+                prg_field,
+                chr_field,
+                latch: 0,
+                num_prg_banks: usize::max(num_prg_banks / 2, 1),
+                num_chr_banks,
+                bus_conflicts,
+            }));
+        }
+        34 => {
+            // BNROM has at most 8k of CHR, NINA-001 switches 4k CHR banks
+            let nina001 = config.submapper == 1 || (config.submapper == 0 && num_chr_banks > 1);
+            if nina001 {
+                return Some(Box::new(NINA001 {
+                    prg_bank: 0,
+                    chr_banks: [0, 1],
+                    num_prg_banks: usize::max(num_prg_banks / 2, 1),
+                    num_chr_banks: chr_size / 0x1000,
+                }));
+            }
+            return Some(Box::new(Latch32k {
+                register: (0x8000, 0x8000),
+                prg_field: (0, 0xFF),
+                chr_field: (0, 0x00),
+                latch: 0,
+                num_prg_banks: usize::max(num_prg_banks / 2, 1),
+                num_chr_banks,
+                bus_conflicts,
+            }));
+        }
+        71 => {
+            return Some(Box::new(Camerica {
+                prg_bank: 0,
+                // submapper 1 (Fire Hawk) is known to have the register; without a submapper
+                // it's enabled too, since other games never write there
+                mirroring_control: config.submapper <= 1,
+                single_screen: if config.submapper == 1 { Some(Mirroring::SingleScreenLower) } else { None },
+                num_prg_banks,
+            }));
+        }
+        78 | 152 => {
+            let (prg_field, chr_field, mirroring_bit, mirroring_modes) = match (config.mapper_id, config.submapper) {
+                (152, _) => ((4, 0x07), (0, 0x0F), 0x80, (Mirroring::SingleScreenLower, Mirroring::SingleScreenUpper)),
+                (_, 1) => ((0, 0x07), (4, 0x0F), 0x08, (Mirroring::SingleScreenLower, Mirroring::SingleScreenUpper)), // JF-16
+                (_, _) => ((0, 0x07), (4, 0x0F), 0x08, (Mirroring::Horizontal, Mirroring::Vertical)),            // Holy Diver
+            };
+            return Some(Box::new(Latch16k {
+                prg_field,
+                chr_field,
+                mirroring_bit,
+                mirroring_modes,
+                latch: 0,
                 num_prg_banks,
                 num_chr_banks,
-            });
+                bus_conflicts,
+            }));
         }
         _ => {
-            panic!("Unimplemented mapper id {}", mapper_id)
+            return None;
         }
     }
 }
@@ -40,12 +1985,44 @@ pub fn create_mapper(mapper_id: u8, num_prg_banks: u8, num_chr_banks: u8) -> Box
 mod tests {
     use super::*;
 
+    fn config(mapper_id: u16, num_prg_banks: usize, num_chr_banks: usize) -> MapperConfig {
+        return MapperConfig {
+            mapper_id,
+            submapper: 0,
+            prg_rom_size: num_prg_banks * PRG_BANK_16K,
+            chr_rom_size: num_chr_banks * CHR_BANK_8K,
+            prg_ram_size: 0x2000,
+            chr_ram_size: if num_chr_banks == 0 { CHR_BANK_8K } else { 0 },
+        };
+    }
+
+    // feeds a value through a serial port one bit per write, on separate cycles
+    fn serial_write(mapper: &mut Box<dyn Mapper>, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.cpu_write(addr, (value >> bit) & 0x01);
+            mapper.cpu_clock();
+            mapper.cpu_clock();
+        }
+    }
+
+    #[test]
+    fn nametable_mirroring() {
+        assert_eq!(mirror_nametable(Mirroring::Horizontal, 0x2000), PpuMapping::Ciram(0x000));
+        assert_eq!(mirror_nametable(Mirroring::Horizontal, 0x2405), PpuMapping::Ciram(0x005));
+        assert_eq!(mirror_nametable(Mirroring::Horizontal, 0x2805), PpuMapping::Ciram(0x405));
+        assert_eq!(mirror_nametable(Mirroring::Vertical, 0x2405), PpuMapping::Ciram(0x405));
+        assert_eq!(mirror_nametable(Mirroring::Vertical, 0x2805), PpuMapping::Ciram(0x005));
+        assert_eq!(mirror_nametable(Mirroring::SingleScreenUpper, 0x2C05), PpuMapping::Ciram(0x405));
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x2C05), PpuMapping::Vram(0x405));
+        assert_eq!(mirror_nametable(Mirroring::Vertical, 0x3405), PpuMapping::Ciram(0x405)); // $3000-$3EFF mirrors $2000
+    }
+
     mod nrom {
         use super::*;
 
         #[test]
         fn map_16k() {
-            let nrom = create_mapper(0, 1, 1);
+            let nrom = create_mapper(&config(0, 1, 1)).unwrap();
             assert_eq!(nrom.map_address(0x0000), 0x0000);
             assert_eq!(nrom.map_address(0x1000), 0x1000);
             assert_eq!(nrom.map_address(0x2000), 0x2000);
@@ -66,7 +2043,7 @@ mod tests {
 
         #[test]
         fn map_32k() {
-            let nrom = create_mapper(0, 2, 1);
+            let nrom = create_mapper(&config(0, 2, 1)).unwrap();
             assert_eq!(nrom.map_address(0x0000), 0x0000);
             assert_eq!(nrom.map_address(0x1000), 0x1000);
             assert_eq!(nrom.map_address(0x2000), 0x2000);
@@ -84,5 +2061,910 @@ mod tests {
             assert_eq!(nrom.map_address(0xE000), 0x6000);
             assert_eq!(nrom.map_address(0xF000), 0x7000);
         }
+
+        #[test]
+        fn default_cpu_and_ppu_mapping() {
+            let mut nrom = create_mapper(&config(0, 1, 1)).unwrap();
+            assert_eq!(nrom.cpu_read(0xC123), CpuMapping::PrgRom(0x0123));
+            assert_eq!(nrom.cpu_read(0x6010), CpuMapping::PrgRam(0x0010));
+            assert_eq!(nrom.cpu_read(0x5000), CpuMapping::Unmapped);
+            assert_eq!(nrom.cpu_write(0x7FFF, 0x12), CpuMapping::PrgRam(0x1FFF));
+            assert_eq!(nrom.cpu_write(0x8000, 0x12), CpuMapping::Unmapped);
+            assert_eq!(nrom.ppu_read(0x1ABC), PpuMapping::Chr(0x1ABC));
+            assert_eq!(nrom.ppu_read(0x2400), PpuMapping::Mirrored);
+            assert_eq!(nrom.mirroring(), None);
+            assert!(!nrom.irq_pending());
+        }
+    }
+
+    mod mmc1 {
+        use super::*;
+
+        #[test]
+        fn power_on_fixes_last_bank() {
+            let mapper = create_mapper(&config(1, 8, 2)).unwrap();
+            assert_eq!(mapper.map_address(0x8000), 0x00000);
+            assert_eq!(mapper.map_address(0xC000), 0x1C000);
+            assert_eq!(mapper.map_address(0xFFFF), 0x1FFFF);
+        }
+
+        #[test]
+        fn prg_bank_modes() {
+            let mut mapper = create_mapper(&config(1, 8, 2)).unwrap();
+            serial_write(&mut mapper, 0xE000, 0x05);
+            assert_eq!(mapper.map_address(0x8000), 0x14000);
+            assert_eq!(mapper.map_address(0xC000), 0x1C000);
+
+            serial_write(&mut mapper, 0x8000, 0x08); // fix first bank at $8000
+            assert_eq!(mapper.map_address(0x8000), 0x00000);
+            assert_eq!(mapper.map_address(0xC000), 0x14000);
+
+            serial_write(&mut mapper, 0x8000, 0x00); // 32k mode ignores the low bit
+            assert_eq!(mapper.map_address(0x8000), 0x10000);
+            assert_eq!(mapper.map_address(0xC000), 0x14000);
+        }
+
+        #[test]
+        fn reset_bit_and_consecutive_writes() {
+            let mut mapper = create_mapper(&config(1, 8, 2)).unwrap();
+            serial_write(&mut mapper, 0x8000, 0x02); // vertical, 32k mode
+            assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+
+            // a partially shifted value is discarded by the reset bit, which also fixes the last bank
+            mapper.cpu_write(0x8000, 0x01);
+            mapper.cpu_clock();
+            mapper.cpu_clock();
+            mapper.cpu_write(0x8000, 0x80);
+            mapper.cpu_clock();
+            mapper.cpu_clock();
+            assert_eq!(mapper.map_address(0xC000), 0x1C000);
+
+            // the second write of a read-modify-write pair lands on the same cycle and is ignored
+            for bit in 0..5 {
+                mapper.cpu_write(0x8000, 0x00);
+                mapper.cpu_write(0x8000, (0x03 >> bit) & 0x01);
+                mapper.cpu_clock();
+                mapper.cpu_clock();
+            }
+            assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenLower));
+            serial_write(&mut mapper, 0x8000, 0x03);
+            assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        }
+
+        #[test]
+        fn chr_banks() {
+            let mut mapper = create_mapper(&config(1, 8, 4)).unwrap();
+            serial_write(&mut mapper, 0xA000, 0x03);
+            serial_write(&mut mapper, 0xC000, 0x05);
+            assert_eq!(mapper.ppu_read(0x0010), PpuMapping::Chr(0x2010)); // 8k mode ignores the low bit
+            assert_eq!(mapper.ppu_read(0x1010), PpuMapping::Chr(0x3010));
+
+            serial_write(&mut mapper, 0x8000, 0x1C); // 4k mode
+            assert_eq!(mapper.ppu_read(0x0010), PpuMapping::Chr(0x3010));
+            assert_eq!(mapper.ppu_read(0x1010), PpuMapping::Chr(0x5010));
+            assert_eq!(mapper.ppu_read(0x2000), PpuMapping::Mirrored);
+        }
+
+        #[test]
+        fn prg_ram_enable() {
+            let mut mapper = create_mapper(&config(1, 8, 2)).unwrap();
+            assert_eq!(mapper.cpu_read(0x6123), CpuMapping::PrgRam(0x0123));
+            serial_write(&mut mapper, 0xE000, 0x10);
+            assert_eq!(mapper.cpu_read(0x6123), CpuMapping::Unmapped);
+            assert_eq!(mapper.cpu_write(0x6123, 0x00), CpuMapping::Unmapped);
+        }
+
+        #[test]
+        fn surom_outer_bank() {
+            let mut mapper = create_mapper(&config(1, 32, 0)).unwrap();
+            assert_eq!(mapper.map_address(0xC000), 0x3C000);
+            serial_write(&mut mapper, 0xA000, 0x10);
+            assert_eq!(mapper.map_address(0x8000), 0x40000);
+            assert_eq!(mapper.map_address(0xC000), 0x7C000);
+        }
+
+        #[test]
+        fn sxrom_prg_ram_banks() {
+            let mut mapper = create_mapper(&MapperConfig { prg_ram_size: 0x8000, ..config(1, 32, 0) }).unwrap();
+            serial_write(&mut mapper, 0xA000, 0x0C);
+            assert_eq!(mapper.cpu_read(0x6001), CpuMapping::PrgRam(0x6001));
+        }
+
+        #[test]
+        fn sorom_prg_ram_banks() {
+            let mut mapper = create_mapper(&MapperConfig { prg_ram_size: 0x4000, ..config(1, 16, 0) }).unwrap();
+            serial_write(&mut mapper, 0xA000, 0x08);
+            assert_eq!(mapper.cpu_read(0x6001), CpuMapping::PrgRam(0x2001));
+        }
+
+        #[test]
+        fn snrom_disables_prg_ram() {
+            let mut mapper = create_mapper(&config(1, 16, 0)).unwrap();
+            assert_eq!(mapper.cpu_read(0x7000), CpuMapping::PrgRam(0x1000));
+            serial_write(&mut mapper, 0xA000, 0x10);
+            assert_eq!(mapper.cpu_read(0x7000), CpuMapping::Unmapped);
+        }
+    }
+
+    mod discrete {
+        use super::*;
+
+        #[test]
+        fn uxrom() {
+            let mut mapper = create_mapper(&config(2, 8, 0)).unwrap();
+            assert_eq!(mapper.map_address(0x8000), 0x00000);
+            assert_eq!(mapper.map_address(0xC000), 0x1C000);
+            mapper.cpu_write(0x8000, 0x03);
+            assert_eq!(mapper.map_address(0x8123), 0x0C123);
+            assert_eq!(mapper.map_address(0xFFFF), 0x1FFFF);
+            mapper.cpu_write(0xFFFF, 0x09); // wraps to the banks that exist
+            assert_eq!(mapper.map_address(0x8000), 0x04000);
+            assert!(!mapper.has_bus_conflicts());
+        }
+
+        #[test]
+        fn cnrom() {
+            let mut mapper = create_mapper(&config(3, 1, 4)).unwrap();
+            assert_eq!(mapper.map_address(0xC123), 0x0123);
+            assert_eq!(mapper.ppu_read(0x0123), PpuMapping::Chr(0x0123));
+            mapper.cpu_write(0x8000, 0x02);
+            assert_eq!(mapper.ppu_read(0x1123), PpuMapping::Chr(0x5123));
+            assert_eq!(mapper.ppu_read(0x2000), PpuMapping::Mirrored);
+            assert_eq!(mapper.cpu_write(0x6000, 0x00), CpuMapping::Unmapped);
+        }
+
+        #[test]
+        fn axrom() {
+            let mut mapper = create_mapper(&config(7, 16, 0)).unwrap();
+            assert_eq!(mapper.map_address(0xC000), 0x04000);
+            assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenLower));
+            mapper.cpu_write(0x8000, 0x13);
+            assert_eq!(mapper.map_address(0x8000), 0x18000);
+            assert_eq!(mapper.map_address(0xFFFF), 0x1FFFF);
+            assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenUpper));
+        }
+
+        #[test]
+        fn bus_conflicts_from_submapper() {
+            let mapper = create_mapper(&MapperConfig { submapper: 2, ..config(3, 1, 1) }).unwrap();
+            assert!(mapper.has_bus_conflicts());
+            let mapper = create_mapper(&MapperConfig { submapper: 1, ..config(7, 2, 0) }).unwrap();
+            assert!(!mapper.has_bus_conflicts());
+            // submapper 2 means something else on other boards
+            let mapper = create_mapper(&MapperConfig { submapper: 2, ..config(78, 8, 8) }).unwrap();
+            assert!(mapper.has_bus_conflicts());
+            let mapper = create_mapper(&config(66, 2, 2)).unwrap();
+            assert!(mapper.has_bus_conflicts());
+            let mapper = create_mapper(&config(11, 2, 2)).unwrap();
+            assert!(mapper.has_bus_conflicts());
+        }
+    }
+
+    mod mmc3 {
+        use super::*;
+
+        fn write_register(mapper: &mut Box<dyn Mapper>, addr: u16, data: u8) {
+            mapper.cpu_write(addr, data);
+        }
+
+        // the fetches of one rendered scanline: background from $0000, sprites from $1000
+        fn render_scanline(mapper: &mut Box<dyn Mapper>) {
+            mapper.ppu_read(0x0000);
+            for _ in 0..10 {
+                mapper.cpu_clock();
+            }
+            mapper.ppu_read(0x1FF0);
+            for _ in 0..10 {
+                mapper.cpu_clock();
+            }
+        }
+
+        #[test]
+        fn prg_banks() {
+            let mut mapper = create_mapper(&config(4, 8, 8)).unwrap(); // 16 8k banks
+            write_register(&mut mapper, 0x8000, 0x06);
+            write_register(&mut mapper, 0x8001, 0x03);
+            write_register(&mut mapper, 0x8000, 0x07);
+            write_register(&mut mapper, 0x8001, 0x05);
+            assert_eq!(mapper.map_address(0x8000), 0x06000);
+            assert_eq!(mapper.map_address(0xA000), 0x0A000);
+            assert_eq!(mapper.map_address(0xC000), 0x1C000);
+            assert_eq!(mapper.map_address(0xE000), 0x1E000);
+
+            write_register(&mut mapper, 0x8000, 0x46); // PRG inversion
+            assert_eq!(mapper.map_address(0x8000), 0x1C000);
+            assert_eq!(mapper.map_address(0xC000), 0x06000);
+            assert_eq!(mapper.map_address(0xE123), 0x1E123);
+        }
+
+        #[test]
+        fn chr_banks() {
+            let mut mapper = create_mapper(&config(4, 2, 8)).unwrap(); // 64 1k banks
+            for (register, bank) in [0x11u8, 0x20, 0x30, 0x31, 0x32, 0x33].iter().enumerate() {
+                write_register(&mut mapper, 0x8000, register as u8);
+                write_register(&mut mapper, 0x8001, *bank);
+            }
+            assert_eq!(mapper.ppu_read(0x0010), PpuMapping::Chr(0x4010)); // low bit of R0 ignored
+            assert_eq!(mapper.ppu_read(0x0410), PpuMapping::Chr(0x4410));
+            assert_eq!(mapper.ppu_read(0x0810), PpuMapping::Chr(0x8010));
+            assert_eq!(mapper.ppu_read(0x1C10), PpuMapping::Chr(0xCC10));
+
+            write_register(&mut mapper, 0x8000, 0x80); // CHR inversion
+            assert_eq!(mapper.ppu_read(0x0010), PpuMapping::Chr(0xC010));
+            assert_eq!(mapper.ppu_read(0x1810), PpuMapping::Chr(0x8010));
+        }
+
+        #[test]
+        fn mirroring_and_prg_ram_protect() {
+            let mut mapper = create_mapper(&config(4, 2, 8)).unwrap();
+            assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+            write_register(&mut mapper, 0xA000, 0x01);
+            assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+
+            assert_eq!(mapper.cpu_write(0x6000, 0x00), CpuMapping::PrgRam(0x0000));
+            write_register(&mut mapper, 0xA001, 0xC0); // enabled, write protected
+            assert_eq!(mapper.cpu_read(0x6000), CpuMapping::PrgRam(0x0000));
+            assert_eq!(mapper.cpu_write(0x6000, 0x00), CpuMapping::Unmapped);
+            write_register(&mut mapper, 0xA001, 0x00);
+            assert_eq!(mapper.cpu_read(0x6000), CpuMapping::Unmapped);
+        }
+
+        #[test]
+        fn scanline_irq() {
+            let mut mapper = create_mapper(&config(4, 2, 8)).unwrap();
+            write_register(&mut mapper, 0xC000, 0x02);
+            write_register(&mut mapper, 0xC001, 0x00);
+            write_register(&mut mapper, 0xE001, 0x00);
+            render_scanline(&mut mapper); // reload to 2
+            render_scanline(&mut mapper);
+            assert!(!mapper.irq_pending());
+            render_scanline(&mut mapper);
+            assert!(mapper.irq_pending());
+            write_register(&mut mapper, 0xE000, 0x00); // acknowledge
+            assert!(!mapper.irq_pending());
+        }
+
+        #[test]
+        fn a12_filter() {
+            let mut mapper = create_mapper(&config(4, 2, 8)).unwrap();
+            write_register(&mut mapper, 0xC000, 0x00);
+            write_register(&mut mapper, 0xE001, 0x00);
+            for _ in 0..MMC3_A12_FILTER_CYCLES {
+                mapper.cpu_clock();
+            }
+            mapper.ppu_read(0x1000);
+            assert!(mapper.irq_pending());
+            write_register(&mut mapper, 0xE000, 0x00);
+            write_register(&mut mapper, 0xE001, 0x00);
+
+            // A12 toggling faster than the filter (8x16 sprites, $2006 writes) isn't a new scanline
+            mapper.ppu_read(0x0000);
+            mapper.cpu_clock();
+            mapper.ppu_read(0x1000);
+            assert!(!mapper.irq_pending());
+        }
+
+        #[test]
+        fn sharp_and_nec_reload() {
+            // with a latch of 0, Sharp chips raise an IRQ every scanline, NEC chips only once
+            let mut sharp = create_mapper(&config(4, 2, 8)).unwrap();
+            let mut nec = create_mapper(&MapperConfig { submapper: 4, ..config(4, 2, 8) }).unwrap();
+            for mapper in [&mut sharp, &mut nec].iter_mut() {
+                write_register(mapper, 0xC000, 0x00);
+                write_register(mapper, 0xC001, 0x00);
+                write_register(mapper, 0xE001, 0x00);
+                render_scanline(mapper);
+                assert!(mapper.irq_pending());
+                write_register(mapper, 0xE000, 0x00);
+                write_register(mapper, 0xE001, 0x00);
+                render_scanline(mapper);
+            }
+            assert!(sharp.irq_pending());
+            assert!(!nec.irq_pending());
+        }
+    }
+
+    mod mmc2 {
+        use super::*;
+
+        fn fetch(mapper: &mut Box<dyn Mapper>, addr: u16) -> PpuMapping {
+            let mapping = mapper.ppu_read(addr);
+            mapper.ppu_fetched(addr);
+            return mapping;
+        }
+
+        #[test]
+        fn mmc2_prg_banks() {
+            let mut mapper = create_mapper(&config(9, 8, 16)).unwrap(); // 16 8k banks
+            mapper.cpu_write(0xA000, 0x05);
+            assert_eq!(mapper.map_address(0x8123), 0x0A123);
+            assert_eq!(mapper.map_address(0xA000), 0x1A000);
+            assert_eq!(mapper.map_address(0xC000), 0x1C000);
+            assert_eq!(mapper.map_address(0xE000), 0x1E000);
+        }
+
+        #[test]
+        fn mmc4_prg_banks() {
+            let mut mapper = create_mapper(&config(10, 8, 16)).unwrap();
+            mapper.cpu_write(0xA000, 0x03);
+            assert_eq!(mapper.map_address(0x8123), 0x0C123);
+            assert_eq!(mapper.map_address(0xA123), 0x0E123);
+            assert_eq!(mapper.map_address(0xC000), 0x1C000);
+        }
+
+        #[test]
+        fn mmc2_small_prg_rom() {
+            // 16k of PRG ROM is two 8k banks, mirrored into the fixed windows
+            let mapper = create_mapper(&config(9, 1, 16)).unwrap();
+            assert_eq!(mapper.map_address(0xA000), 0x2000);
+            assert_eq!(mapper.map_address(0xC000), 0x0000);
+            assert_eq!(mapper.map_address(0xE000), 0x2000);
+            let mapper = create_mapper(&config(10, 1, 16)).unwrap();
+            assert_eq!(mapper.map_address(0xC000), 0x0000);
+            assert_eq!(mapper.map_address(0xE000), 0x2000);
+        }
+
+        #[test]
+        fn latches_switch_after_fetch() {
+            let mut mapper = create_mapper(&config(9, 8, 16)).unwrap();
+            mapper.cpu_write(0xB000, 0x01); // $0000 when latch 0 = $FD
+            mapper.cpu_write(0xC000, 0x02); // $0000 when latch 0 = $FE
+            mapper.cpu_write(0xD000, 0x03);
+            mapper.cpu_write(0xE000, 0x04);
+            assert_eq!(fetch(&mut mapper, 0x0010), PpuMapping::Chr(0x2010));
+            assert_eq!(fetch(&mut mapper, 0x1010), PpuMapping::Chr(0x4010));
+
+            assert_eq!(fetch(&mut mapper, 0x0FD8), PpuMapping::Chr(0x2FD8)); // still the old bank
+            assert_eq!(fetch(&mut mapper, 0x0010), PpuMapping::Chr(0x1010));
+            fetch(&mut mapper, 0x0FD9); // MMC2 only watches the exact address on the left
+            assert_eq!(fetch(&mut mapper, 0x0010), PpuMapping::Chr(0x1010));
+
+            fetch(&mut mapper, 0x1FDD);
+            assert_eq!(fetch(&mut mapper, 0x1010), PpuMapping::Chr(0x3010));
+            fetch(&mut mapper, 0x1FE8);
+            assert_eq!(fetch(&mut mapper, 0x1010), PpuMapping::Chr(0x4010));
+        }
+
+        #[test]
+        fn mmc4_latch_ranges() {
+            let mut mapper = create_mapper(&config(10, 8, 16)).unwrap();
+            mapper.cpu_write(0xB000, 0x01);
+            mapper.cpu_write(0xC000, 0x02);
+            fetch(&mut mapper, 0x0FDB);
+            assert_eq!(fetch(&mut mapper, 0x0010), PpuMapping::Chr(0x1010));
+            mapper.cpu_write(0xF000, 0x01);
+            assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        }
+    }
+
+    mod mmc5 {
+        use super::*;
+
+        // the dummy nametable fetches that end a line plus the first fetch of the next
+        fn start_scanline(mapper: &mut Box<dyn Mapper>) -> PpuMapping {
+            mapper.ppu_read(0x2002);
+            mapper.ppu_read(0x2002);
+            return mapper.ppu_read(0x2002);
+        }
+
+        fn end_frame(mapper: &mut Box<dyn Mapper>) {
+            for _ in 0..3 {
+                mapper.cpu_clock();
+            }
+        }
+
+        #[test]
+        fn prg_modes() {
+            let mut mapper = create_mapper(&config(5, 16, 16)).unwrap(); // 32 8k banks
+            assert_eq!(mapper.map_address(0xE123), 0xFE123); // $5117 = $FF at power on
+            mapper.cpu_write(0x5114, 0x81);
+            mapper.cpu_write(0x5115, 0x82);
+            mapper.cpu_write(0x5116, 0x03);
+            mapper.cpu_write(0x5117, 0x07);
+            assert_eq!(mapper.cpu_read(0x8123), CpuMapping::PrgRom(0x02123));
+            assert_eq!(mapper.cpu_read(0xA123), CpuMapping::PrgRom(0x04123));
+            assert_eq!(mapper.cpu_read(0xC123), CpuMapping::PrgRam(0x06123)); // bit 7 clear selects RAM
+            assert_eq!(mapper.cpu_read(0xE123), CpuMapping::PrgRom(0x0E123));
+
+            mapper.cpu_write(0x5100, 0x02); // 16k + 8k + 8k
+            assert_eq!(mapper.cpu_read(0x8123), CpuMapping::PrgRom(0x04123));
+            assert_eq!(mapper.cpu_read(0xA123), CpuMapping::PrgRom(0x06123));
+            mapper.cpu_write(0x5100, 0x01); // 16k + 16k
+            assert_eq!(mapper.cpu_read(0xC123), CpuMapping::PrgRom(0x0C123));
+            mapper.cpu_write(0x5100, 0x00); // 32k
+            assert_eq!(mapper.cpu_read(0x8123), CpuMapping::PrgRom(0x08123));
+            assert_eq!(mapper.cpu_read(0xE123), CpuMapping::PrgRom(0x0E123));
+        }
+
+        #[test]
+        fn prg_ram_banks_and_protect() {
+            let mut mapper = create_mapper(&config(5, 16, 16)).unwrap();
+            mapper.cpu_write(0x5113, 0x01);
+            assert_eq!(mapper.cpu_read(0x6123), CpuMapping::PrgRam(0x2123));
+            assert_eq!(mapper.cpu_write(0x6123, 0x00), CpuMapping::Unmapped);
+            mapper.cpu_write(0x5102, 0x02);
+            mapper.cpu_write(0x5103, 0x01);
+            assert_eq!(mapper.cpu_write(0x6123, 0x00), CpuMapping::PrgRam(0x2123));
+            assert_eq!(mapper.cpu_write(0xE000, 0x00), CpuMapping::Unmapped); // ROM
+        }
+
+        #[test]
+        fn chr_modes_and_8x16_sets() {
+            let mut mapper = create_mapper(&config(5, 16, 32)).unwrap(); // 256 1k banks
+            mapper.cpu_write(0x5101, 0x03); // 1k banks
+            for register in 0..8 {
+                mapper.cpu_write(0x5120 + register, 0x10 + register as u8);
+            }
+            assert_eq!(mapper.ppu_read(0x0C10), PpuMapping::Chr(0x4C10));
+            mapper.cpu_write(0x5130, 0x01);
+            mapper.cpu_write(0x512B, 0x20);
+            // set B was written last, so it's used for everything outside 8x16 rendering
+            assert_eq!(mapper.ppu_read(0x1C10), PpuMapping::Chr(0x48010));
+
+            mapper.cpu_write(0x5101, 0x00); // 8k banks
+            mapper.cpu_write(0x5130, 0x00);
+            mapper.cpu_write(0x5127, 0x02);
+            assert_eq!(mapper.ppu_read(0x1C10), PpuMapping::Chr(0x5C10));
+
+            // while rendering 8x16 sprites, background fetches use set B and sprite fetches set A
+            mapper.cpu_write(0x512B, 0x03);
+            mapper.ppu_register_write(0x2000, 0x20);
+            mapper.ppu_read(0x0000); // something other than a nametable read before the line starts
+            start_scanline(&mut mapper);
+            mapper.ppu_read(0x23C0);
+            assert_eq!(mapper.ppu_read(0x0010), PpuMapping::Chr(0x6010));
+            for fetch in 3..MMC5_SPRITE_FETCHES.start {
+                mapper.ppu_read(0x2000 + fetch as u16);
+            }
+            mapper.ppu_read(0x2000);
+            mapper.ppu_read(0x2000);
+            assert_eq!(mapper.ppu_read(0x1FF0), PpuMapping::Chr(0x5FF0));
+        }
+
+        #[test]
+        fn nametables_fill_and_exram() {
+            let mut mapper = create_mapper(&config(5, 16, 16)).unwrap();
+            mapper.cpu_write(0x5105, 0b11_10_01_00);
+            mapper.cpu_write(0x5106, 0x42);
+            mapper.cpu_write(0x5107, 0x02);
+            assert_eq!(mapper.ppu_read(0x2010), PpuMapping::Ciram(0x010));
+            assert_eq!(mapper.ppu_read(0x2410), PpuMapping::Ciram(0x410));
+            assert_eq!(mapper.ppu_write(0x2810, 0x99), PpuMapping::Unmapped);
+            assert_eq!(mapper.ppu_read(0x2810), PpuMapping::Data(0x99));
+            assert_eq!(mapper.ppu_read(0x2C10), PpuMapping::Data(0x42));
+            assert_eq!(mapper.ppu_read(0x2FC0), PpuMapping::Data(0xAA));
+
+            // outside of rendering the cpu can only write zeroes in the nametable modes
+            mapper.cpu_write(0x5C10, 0x55);
+            assert_eq!(mapper.ppu_read(0x2810), PpuMapping::Data(0x00));
+            assert_eq!(mapper.cpu_read(0x5C10), CpuMapping::Unmapped);
+            mapper.cpu_write(0x5104, 0x02);
+            mapper.cpu_write(0x5C10, 0x55);
+            assert_eq!(mapper.cpu_read(0x5C10), CpuMapping::Data(0x55));
+            mapper.cpu_write(0x5104, 0x03);
+            mapper.cpu_write(0x5C10, 0x66);
+            assert_eq!(mapper.cpu_read(0x5C10), CpuMapping::Data(0x55));
+        }
+
+        #[test]
+        fn scanline_irq() {
+            let mut mapper = create_mapper(&config(5, 16, 16)).unwrap();
+            mapper.cpu_write(0x5203, 0x03);
+            mapper.cpu_write(0x5204, 0x80);
+            for line in 0..3 {
+                start_scanline(&mut mapper);
+                assert_eq!(mapper.irq_pending(), false, "line {}", line);
+                mapper.ppu_read(0x0000);
+            }
+            assert_eq!(mapper.cpu_peek(0x5204), CpuMapping::Data(0x40)); // in frame
+            start_scanline(&mut mapper);
+            assert!(mapper.irq_pending());
+            assert_eq!(mapper.cpu_read(0x5204), CpuMapping::Data(0xC0)); // reading acknowledges
+            assert!(!mapper.irq_pending());
+
+            end_frame(&mut mapper);
+            assert_eq!(mapper.cpu_peek(0x5204), CpuMapping::Data(0x00));
+        }
+
+        #[test]
+        fn multiplier() {
+            let mut mapper = create_mapper(&config(5, 16, 16)).unwrap();
+            mapper.cpu_write(0x5205, 0xC8);
+            mapper.cpu_write(0x5206, 0x0F);
+            assert_eq!(mapper.cpu_read(0x5205), CpuMapping::Data(0xB8));
+            assert_eq!(mapper.cpu_read(0x5206), CpuMapping::Data(0x0B));
+        }
+
+        #[test]
+        fn extended_attributes() {
+            let mut mapper = create_mapper(&config(5, 16, 32)).unwrap();
+            mapper.cpu_write(0x5104, 0x01);
+            mapper.ppu_read(0x0000);
+            start_scanline(&mut mapper);
+            mapper.ppu_read(0x0000);
+            mapper.cpu_write(0x5C05, 0b10_000011); // palette 2, 4k bank 3
+            mapper.cpu_write(0x5130, 0x01);
+
+            start_scanline(&mut mapper); // tile 2 of the line
+            mapper.ppu_read(0x23C0);
+            mapper.ppu_read(0x0010);
+            mapper.ppu_read(0x0018);
+            assert_eq!(mapper.ppu_read(0x2005), PpuMapping::Ciram(0x005)); // tile 3
+            assert_eq!(mapper.ppu_read(0x23C1), PpuMapping::Data(0xAA));
+            assert_eq!(mapper.ppu_read(0x1120), PpuMapping::Chr(0x43120));
+        }
+
+        #[test]
+        fn vertical_split() {
+            let mut mapper = create_mapper(&config(5, 16, 32)).unwrap();
+            mapper.cpu_write(0x5200, 0x82); // tiles left of 2 come from the split
+            mapper.cpu_write(0x5201, 0x08); // split starts at tile row 1
+            mapper.cpu_write(0x5202, 0x04);
+            mapper.ppu_read(0x0000);
+            start_scanline(&mut mapper);
+            mapper.cpu_write(0x5C20, 0x77); // row 1, tile 0
+            mapper.cpu_write(0x5FC0, 0x03);
+
+            // the last background fetches of a line are tiles 0 and 1 of the next
+            mapper.ppu_read(0x23C0);
+            for _ in 2..MMC5_PREFETCHES.start {
+                mapper.ppu_read(0x0000);
+            }
+            assert_eq!(mapper.ppu_read(0x2000), PpuMapping::Data(0x77));
+            assert_eq!(mapper.ppu_read(0x23C0), PpuMapping::Data(0xFF));
+            assert_eq!(mapper.ppu_read(0x0770), PpuMapping::Chr(0x4771)); // fine y 1 of the split
+        }
+    }
+
+    mod vrc {
+        use super::*;
+
+        #[test]
+        fn vrc4_prg_and_swap_mode() {
+            let mut mapper = create_mapper(&config(21, 8, 16)).unwrap(); // 16 8k banks
+            mapper.cpu_write(0x8000, 0x03);
+            mapper.cpu_write(0xA000, 0x05);
+            assert_eq!(mapper.map_address(0x8123), 0x06123);
+            assert_eq!(mapper.map_address(0xA123), 0x0A123);
+            assert_eq!(mapper.map_address(0xC123), 0x1C123);
+            assert_eq!(mapper.map_address(0xE123), 0x1E123);
+            mapper.cpu_write(0x9004, 0x02); // $9002 on VRC4a
+            assert_eq!(mapper.map_address(0x8123), 0x1C123);
+            assert_eq!(mapper.map_address(0xC123), 0x06123);
+            mapper.cpu_write(0x9006, 0x00); // $9003 isn't the swap register
+            assert_eq!(mapper.map_address(0x8123), 0x1C123);
+        }
+
+        #[test]
+        fn address_line_variants() {
+            // the high nibble of CHR bank 1 is register $B003, wired differently on each board
+            let boards: [(u16, u8, u16); 7] = [
+                (21, 1, 0xB006), (21, 2, 0xB0C0), (23, 1, 0xB003), (23, 2, 0xB00C),
+                (25, 1, 0xB003), (25, 2, 0xB00C), (21, 0, 0xB0C0),
+            ];
+            for (mapper_id, submapper, register) in boards.iter() {
+                let mut mapper = create_mapper(&MapperConfig { submapper: *submapper, ..config(*mapper_id, 8, 16) }).unwrap();
+                mapper.cpu_write(*register, 0x01);
+                assert_eq!(mapper.ppu_read(0x0410), PpuMapping::Chr(0x10 * 0x400 + 0x010), "mapper {} submapper {}", mapper_id, submapper);
+            }
+        }
+
+        #[test]
+        fn vrc2a_chr_and_mirroring() {
+            let mut mapper = create_mapper(&config(22, 8, 16)).unwrap();
+            mapper.cpu_write(0xB000, 0x06); // low nibble of CHR bank 0, shifted right on VRC2a
+            assert_eq!(mapper.ppu_read(0x0010), PpuMapping::Chr(0x0C10));
+            mapper.cpu_write(0x9000, 0x03); // only one mirroring bit
+            assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+            mapper.cpu_write(0xF000, 0x0F); // no IRQ hardware
+            mapper.cpu_write(0xF002, 0x06);
+            mapper.cpu_clock();
+            assert!(!mapper.irq_pending());
+        }
+
+        #[test]
+        fn irq_cycle_mode() {
+            let mut mapper = create_mapper(&config(23, 8, 16)).unwrap();
+            mapper.cpu_write(0xF000, 0x0C); // latch $FC
+            mapper.cpu_write(0xF001, 0x0F);
+            mapper.cpu_write(0xF002, 0x07); // cycle mode, enabled, re-enable after ack
+            for _ in 0..3 {
+                mapper.cpu_clock();
+            }
+            assert!(!mapper.irq_pending());
+            mapper.cpu_clock();
+            assert!(mapper.irq_pending());
+            mapper.cpu_write(0xF003, 0x00);
+            assert!(!mapper.irq_pending());
+            for _ in 0..4 {
+                mapper.cpu_clock();
+            }
+            assert!(mapper.irq_pending());
+        }
+
+        #[test]
+        fn irq_scanline_mode() {
+            let mut mapper = create_mapper(&config(25, 8, 16)).unwrap();
+            mapper.cpu_write(0xF000, 0x0E); // latch $FE, two scanlines
+            mapper.cpu_write(0xF002, 0x0F);
+            mapper.cpu_write(0xF004, 0x02); // $F002 on VRC4d: scanline mode, enabled
+            for _ in 0..227 {
+                mapper.cpu_clock();
+            }
+            assert!(!mapper.irq_pending());
+            mapper.cpu_clock();
+            assert!(mapper.irq_pending());
+            mapper.cpu_write(0xF00C, 0x00); // acknowledge, stays disabled
+            for _ in 0..1000 {
+                mapper.cpu_clock();
+            }
+            assert!(!mapper.irq_pending());
+        }
+
+        #[test]
+        fn vrc6_banking() {
+            let mut mapper = create_mapper(&config(26, 8, 32)).unwrap();
+            mapper.cpu_write(0x8000, 0x02);
+            mapper.cpu_write(0xC000, 0x07);
+            assert_eq!(mapper.map_address(0x8123), 0x08123);
+            assert_eq!(mapper.map_address(0xA123), 0x0A123);
+            assert_eq!(mapper.map_address(0xC123), 0x0E123);
+            assert_eq!(mapper.map_address(0xE123), 0x1E123);
+
+            mapper.cpu_write(0xD002, 0x11); // R1 on mapper 26
+            mapper.cpu_write(0xE003, 0x22); // R7
+            assert_eq!(mapper.ppu_read(0x0410), PpuMapping::Chr(0x11 * 0x400 + 0x010));
+            assert_eq!(mapper.ppu_read(0x1C10), PpuMapping::Chr(0x22 * 0x400 + 0x010));
+
+            mapper.cpu_write(0xB003, 0xA5); // 2k CHR banks, horizontal, PRG RAM enabled
+            assert_eq!(mapper.ppu_read(0x0C10), PpuMapping::Chr(0x11 * 0x400 + 0x010));
+            assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+            assert_eq!(mapper.cpu_read(0x6010), CpuMapping::PrgRam(0x0010));
+
+            mapper.cpu_write(0xF000, 0xFF);
+            mapper.cpu_write(0xF002, 0x06);
+            mapper.cpu_clock();
+            assert!(mapper.irq_pending());
+        }
+
+        #[test]
+        fn vrc7_banking() {
+            let mut mapper = create_mapper(&config(85, 8, 32)).unwrap();
+            mapper.cpu_write(0x8000, 0x01);
+            mapper.cpu_write(0x8010, 0x02); // VRC7a
+            mapper.cpu_write(0x9000, 0x03);
+            assert_eq!(mapper.map_address(0x8123), 0x02123);
+            assert_eq!(mapper.map_address(0xA123), 0x04123);
+            assert_eq!(mapper.map_address(0xC123), 0x06123);
+            assert_eq!(mapper.map_address(0xE123), 0x1E123);
+
+            mapper.cpu_write(0xA008, 0x09); // VRC7b
+            mapper.cpu_write(0xD010, 0x0A);
+            assert_eq!(mapper.ppu_read(0x0410), PpuMapping::Chr(0x2410));
+            assert_eq!(mapper.ppu_read(0x1C10), PpuMapping::Chr(0x2810));
+
+            assert_eq!(mapper.cpu_read(0x6000), CpuMapping::Unmapped);
+            mapper.cpu_write(0xE000, 0x83);
+            assert_eq!(mapper.cpu_read(0x6000), CpuMapping::PrgRam(0x0000));
+            assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenUpper));
+        }
+    }
+
+    mod fme7 {
+        use super::*;
+
+        fn command(mapper: &mut Box<dyn Mapper>, command: u8, parameter: u8) {
+            mapper.cpu_write(0x8000, command);
+            mapper.cpu_write(0xA000, parameter);
+        }
+
+        #[test]
+        fn banking() {
+            let mut mapper = create_mapper(&config(69, 8, 32)).unwrap(); // 16 8k banks
+            command(&mut mapper, 0x9, 0x02);
+            command(&mut mapper, 0xA, 0x03);
+            command(&mut mapper, 0xB, 0x04);
+            assert_eq!(mapper.map_address(0x8123), 0x04123);
+            assert_eq!(mapper.map_address(0xA123), 0x06123);
+            assert_eq!(mapper.map_address(0xC123), 0x08123);
+            assert_eq!(mapper.map_address(0xE123), 0x1E123);
+            command(&mut mapper, 0x5, 0x33);
+            assert_eq!(mapper.ppu_read(0x1410), PpuMapping::Chr(0x33 * 0x400 + 0x010));
+            command(&mut mapper, 0xC, 0x01);
+            assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        }
+
+        #[test]
+        fn rom_or_ram_at_6000() {
+            let mut mapper = create_mapper(&config(69, 8, 32)).unwrap();
+            command(&mut mapper, 0x8, 0x05);
+            assert_eq!(mapper.cpu_read(0x6123), CpuMapping::PrgRom(0x0A123));
+            assert_eq!(mapper.cpu_write(0x6123, 0x00), CpuMapping::Unmapped);
+            command(&mut mapper, 0x8, 0x40); // RAM selected but disabled
+            assert_eq!(mapper.cpu_read(0x6123), CpuMapping::Unmapped);
+            command(&mut mapper, 0x8, 0xC0);
+            assert_eq!(mapper.cpu_read(0x6123), CpuMapping::PrgRam(0x0123));
+            assert_eq!(mapper.cpu_write(0x6123, 0x00), CpuMapping::PrgRam(0x0123));
+        }
+
+        #[test]
+        fn cycle_irq() {
+            let mut mapper = create_mapper(&config(69, 8, 32)).unwrap();
+            command(&mut mapper, 0xE, 0x02);
+            command(&mut mapper, 0xF, 0x00);
+            command(&mut mapper, 0xD, 0x81);
+            mapper.cpu_clock();
+            mapper.cpu_clock();
+            assert!(!mapper.irq_pending());
+            mapper.cpu_clock(); // wraps from 0 to $FFFF
+            assert!(mapper.irq_pending());
+            command(&mut mapper, 0xD, 0x80); // acknowledge, keep counting with IRQs off
+            for _ in 0..0x10000 {
+                mapper.cpu_clock();
+            }
+            assert!(!mapper.irq_pending());
+        }
+    }
+
+    mod n163 {
+        use super::*;
+
+        #[test]
+        fn prg_banks_and_write_protect() {
+            let mut mapper = create_mapper(&config(19, 8, 32)).unwrap();
+            mapper.cpu_write(0xE000, 0x42); // bit 6 is the sound disable
+            mapper.cpu_write(0xE800, 0x03);
+            mapper.cpu_write(0xF000, 0x04);
+            assert_eq!(mapper.map_address(0x8123), 0x04123);
+            assert_eq!(mapper.map_address(0xA123), 0x06123);
+            assert_eq!(mapper.map_address(0xC123), 0x08123);
+            assert_eq!(mapper.map_address(0xE123), 0x1E123);
+
+            assert_eq!(mapper.cpu_write(0x6000, 0x00), CpuMapping::Unmapped);
+            mapper.cpu_write(0xF800, 0x42); // writes enabled except $6800-$6FFF
+            assert_eq!(mapper.cpu_write(0x6000, 0x00), CpuMapping::PrgRam(0x0000));
+            assert_eq!(mapper.cpu_write(0x6800, 0x00), CpuMapping::Unmapped);
+        }
+
+        #[test]
+        fn chr_and_nametables() {
+            let mut mapper = create_mapper(&config(19, 8, 32)).unwrap();
+            mapper.cpu_write(0x8800, 0x12);
+            mapper.cpu_write(0xB800, 0xE1);
+            assert_eq!(mapper.ppu_read(0x0410), PpuMapping::Chr(0x12 * 0x400 + 0x010));
+            assert_eq!(mapper.ppu_read(0x1C10), PpuMapping::Ciram(0x410));
+            mapper.cpu_write(0xE800, 0x80); // CIRAM can't replace CHR at $1000-$1FFF
+            assert_eq!(mapper.ppu_read(0x1C10), PpuMapping::Chr(0xE1 * 0x400 + 0x010));
+
+            // power on with vertical mirroring, then map CHR ROM in as a nametable
+            assert_eq!(mapper.ppu_read(0x2410), PpuMapping::Ciram(0x410));
+            assert_eq!(mapper.ppu_read(0x2810), PpuMapping::Ciram(0x010));
+            mapper.cpu_write(0xD800, 0x20);
+            assert_eq!(mapper.ppu_read(0x2C10), PpuMapping::Chr(0x8010));
+            assert_eq!(mapper.ppu_read(0x3C10), PpuMapping::Chr(0x8010));
+        }
+
+        #[test]
+        fn internal_ram() {
+            let mut mapper = create_mapper(&config(19, 8, 32)).unwrap();
+            mapper.cpu_write(0xF800, 0x90); // address $10 with auto-increment
+            mapper.cpu_write(0x4800, 0xAA);
+            mapper.cpu_write(0x4800, 0xBB);
+            mapper.cpu_write(0xF800, 0x10);
+            assert_eq!(mapper.cpu_read(0x4800), CpuMapping::Data(0xAA));
+            assert_eq!(mapper.cpu_read(0x4800), CpuMapping::Data(0xAA));
+            mapper.cpu_write(0xF800, 0x11);
+            assert_eq!(mapper.cpu_read(0x4800), CpuMapping::Data(0xBB));
+        }
+
+        #[test]
+        fn irq_counter() {
+            let mut mapper = create_mapper(&config(19, 8, 32)).unwrap();
+            mapper.cpu_write(0x5000, 0xFD);
+            mapper.cpu_write(0x5800, 0xFF);
+            mapper.cpu_clock();
+            assert!(!mapper.irq_pending());
+            mapper.cpu_clock();
+            assert!(mapper.irq_pending());
+            assert_eq!(mapper.cpu_read(0x5800), CpuMapping::Data(0xFF));
+            mapper.cpu_clock(); // stays at $7FFF
+            assert_eq!(mapper.cpu_read(0x5000), CpuMapping::Data(0xFF));
+            mapper.cpu_write(0x5000, 0x00);
+            assert!(!mapper.irq_pending());
+        }
+    }
+
+    mod latches {
+        use super::*;
+
+        #[test]
+        fn gxrom_and_color_dreams() {
+            let mut gxrom = create_mapper(&config(66, 8, 4)).unwrap();
+            gxrom.cpu_write(0x8000, 0x32);
+            assert_eq!(gxrom.map_address(0x8123), 0x18123);
+            assert_eq!(gxrom.ppu_read(0x0123), PpuMapping::Chr(0x4123));
+
+            let mut color_dreams = create_mapper(&config(11, 8, 16)).unwrap();
+            color_dreams.cpu_write(0xFFFF, 0x52);
+            assert_eq!(color_dreams.map_address(0xC123), 0x14123);
+            assert_eq!(color_dreams.ppu_read(0x1123), PpuMapping::Chr(0xB123));
+        }
+
+        #[test]
+        fn bnrom_and_nina001() {
+            let mut bnrom = create_mapper(&config(34, 8, 0)).unwrap();
+            bnrom.cpu_write(0x8000, 0x03);
+            assert_eq!(bnrom.map_address(0x8123), 0x18123);
+            assert_eq!(bnrom.ppu_read(0x1123), PpuMapping::Chr(0x1123));
+            assert_eq!(bnrom.cpu_write(0x7FFD, 0x00), CpuMapping::PrgRam(0x1FFD));
+
+            let mut nina = create_mapper(&config(34, 4, 4)).unwrap();
+            assert_eq!(nina.cpu_write(0x7FFD, 0x01), CpuMapping::PrgRam(0x1FFD)); // also lands in RAM
+            nina.cpu_write(0x7FFE, 0x05);
+            nina.cpu_write(0x7FFF, 0x02);
+            assert_eq!(nina.map_address(0x8123), 0x08123);
+            assert_eq!(nina.ppu_read(0x0123), PpuMapping::Chr(0x5123));
+            assert_eq!(nina.ppu_read(0x1123), PpuMapping::Chr(0x2123));
+
+            // the submapper overrides the CHR size guess
+            let bnrom = create_mapper(&MapperConfig { submapper: 2, ..config(34, 4, 4) }).unwrap();
+            assert!(bnrom.has_bus_conflicts());
+        }
+
+        #[test]
+        fn camerica() {
+            let mut mapper = create_mapper(&config(71, 8, 0)).unwrap();
+            mapper.cpu_write(0xC000, 0x03);
+            assert_eq!(mapper.map_address(0x8123), 0x0C123);
+            assert_eq!(mapper.map_address(0xC123), 0x1C123);
+            assert_eq!(mapper.mirroring(), None);
+            mapper.cpu_write(0x9000, 0x10);
+            assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenUpper));
+
+            let mut fixed = create_mapper(&MapperConfig { submapper: 2, ..config(71, 8, 0) }).unwrap();
+            fixed.cpu_write(0x9000, 0x10);
+            assert_eq!(fixed.mirroring(), None);
+
+            let mut fire_hawk = create_mapper(&MapperConfig { submapper: 1, ..config(71, 8, 0) }).unwrap();
+            assert_eq!(fire_hawk.mirroring(), Some(Mirroring::SingleScreenLower));
+            fire_hawk.cpu_write(0x9000, 0x10);
+            assert_eq!(fire_hawk.mirroring(), Some(Mirroring::SingleScreenUpper));
+        }
+
+        #[test]
+        fn nina03_and_jaleco() {
+            let mut nina = create_mapper(&config(79, 4, 8)).unwrap();
+            nina.cpu_write(0x8000, 0x0F); // not a register
+            nina.cpu_write(0x4100, 0x0D);
+            assert_eq!(nina.map_address(0x8123), 0x08123);
+            assert_eq!(nina.ppu_read(0x0123), PpuMapping::Chr(0xA123));
+            nina.cpu_write(0x4000 | 0x0200, 0x00); // A8 clear
+            assert_eq!(nina.ppu_read(0x0123), PpuMapping::Chr(0xA123));
+
+            let mut jaleco = create_mapper(&config(140, 8, 16)).unwrap();
+            assert_eq!(jaleco.cpu_write(0x6000, 0x23), CpuMapping::Unmapped);
+            assert_eq!(jaleco.map_address(0x8123), 0x10123);
+            assert_eq!(jaleco.ppu_read(0x0123), PpuMapping::Chr(0x6123));
+        }
+
+        #[test]
+        fn irem_and_bandai() {
+            let mut holy_diver = create_mapper(&config(78, 8, 16)).unwrap();
+            holy_diver.cpu_write(0x8000, 0x5B);
+            assert_eq!(holy_diver.map_address(0x8123), 0x0C123);
+            assert_eq!(holy_diver.map_address(0xC123), 0x1C123);
+            assert_eq!(holy_diver.ppu_read(0x0123), PpuMapping::Chr(0xA123));
+            assert_eq!(holy_diver.mirroring(), Some(Mirroring::Vertical));
+
+            let mut cosmo_carrier = create_mapper(&MapperConfig { submapper: 1, ..config(78, 8, 16) }).unwrap();
+            cosmo_carrier.cpu_write(0x8000, 0x08);
+            assert_eq!(cosmo_carrier.mirroring(), Some(Mirroring::SingleScreenUpper));
+
+            let mut bandai = create_mapper(&config(152, 8, 16)).unwrap();
+            bandai.cpu_write(0x8000, 0xA3);
+            assert_eq!(bandai.map_address(0x8123), 0x08123);
+            assert_eq!(bandai.ppu_read(0x0123), PpuMapping::Chr(0x6123));
+            assert_eq!(bandai.mirroring(), Some(Mirroring::SingleScreenUpper));
+        }
     }
-}
\ No newline at end of file
+}
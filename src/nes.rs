@@ -1,17 +1,123 @@
 #![allow(dead_code)]
 use super::cartridge;
+use super::gdbstub;
+use super::romdb;
+use super::symbols;
 use super::olc2C02;
 
+// how often battery-backed RAM is flushed to the .sav file, about every 5 seconds
+const SAVE_INTERVAL_FRAMES: u32 = 300;
+
 pub struct Nes {
     ppu: olc2C02::Olc2C02,
     system_clock_counter: u32,
+    frame_count: u32,
+    last_save_error: Option<cartridge::CartridgeError>, // from the periodic flush in clock
+    game_database: Vec<romdb::GameInfo>, // corrects bad headers in roms loaded after it
 }
 
 impl Nes {
     // System Interface
-    pub fn load_rom(&mut self, filename: &str) {
-        let cartridge = cartridge::create_cartridge_from_file(filename).unwrap();
+    // returns anything the front-end should warn the user about, e.g. a dirty header
+    pub fn load_rom(&mut self, filename: &str) -> Result<Vec<cartridge::LoadWarning>, cartridge::CartridgeError> {
+        self.unload_rom()?;
+        let cartridge = cartridge::create_cartridge_from_file(filename, &self.game_database)?;
+        return Ok(self.connect_cartridge(cartridge, filename));
+    }
+
+    // picks one game out of a zip holding several
+    pub fn load_rom_from_archive(&mut self, filename: &str, entry: &str) -> Result<Vec<cartridge::LoadWarning>, cartridge::CartridgeError> {
+        self.unload_rom()?;
+        let cartridge = cartridge::create_cartridge_from_archive(filename, entry, &self.game_database)?;
+        return Ok(self.connect_cartridge(cartridge, filename));
+    }
+
+    // runs a translation or hack without writing a patched ROM to disk
+    pub fn load_patched_rom(&mut self, filename: &str, patch_filename: &str) -> Result<Vec<cartridge::LoadWarning>, cartridge::CartridgeError> {
+        self.unload_rom()?;
+        let cartridge = cartridge::create_patched_cartridge_from_file(filename, patch_filename, &self.game_database)?;
+        return Ok(self.connect_cartridge(cartridge, filename));
+    }
+
+    fn connect_cartridge(&mut self, cartridge: Box<cartridge::Cartridge>, filename: &str) -> Vec<cartridge::LoadWarning> {
+        let warnings = cartridge.warnings().to_vec();
         self.ppu.cpu.bus.connect_cartridge(cartridge);
+        self.ppu.cpu.set_symbols(symbols::load_symbols_for_rom(filename));
+        return warnings;
+    }
+
+    // Famicom Disk System: a disk image plus the BIOS from a real RAM adapter
+    pub fn load_disk(&mut self, disk_filename: &str, bios_filename: &str) -> Result<(), cartridge::CartridgeError> {
+        self.unload_rom()?;
+        let cartridge = cartridge::create_fds_cartridge_from_files(disk_filename, bios_filename)?;
+        self.connect_cartridge(cartridge, disk_filename);
+        return Ok(());
+    }
+
+    // number of disk sides, 0 if no disk is loaded
+    pub fn disk_sides(&self) -> usize {
+        return self.ppu.cpu.bus.cartridge().and_then(|cart| cart.fds()).map_or(0, |fds| fds.side_count());
+    }
+
+    pub fn inserted_disk_side(&self) -> Option<usize> {
+        return self.ppu.cpu.bus.cartridge().and_then(|cart| cart.fds()).and_then(|fds| fds.inserted_side());
+    }
+
+    // sides are numbered from 0: disk 1 side A, disk 1 side B, disk 2 side A...
+    pub fn insert_disk(&mut self, side: usize) -> Result<(), String> {
+        return match self.ppu.cpu.bus.cartridge_mut().and_then(|cart| cart.fds_mut()) {
+            Some(fds) => fds.insert_disk(Some(side)),
+            None => Err("ERROR: No disk system loaded".to_string()),
+        }
+    }
+
+    pub fn eject_disk(&mut self) -> Result<(), String> {
+        return match self.ppu.cpu.bus.cartridge_mut().and_then(|cart| cart.fds_mut()) {
+            Some(fds) => fds.insert_disk(None),
+            None => Err("ERROR: No disk system loaded".to_string()),
+        }
+    }
+
+    // removes the cartridge, writing out its battery-backed RAM.  If that
+    // fails the cartridge stays connected so the save isn't lost
+    pub fn unload_rom(&mut self) -> Result<(), cartridge::CartridgeError> {
+        self.flush_battery_ram()?;
+        self.ppu.cpu.bus.disconnect_cartridge();
+        self.last_save_error = None;
+        return Ok(());
+    }
+
+    // title and region of the loaded game, if it's in the database
+    pub fn game_info(&self) -> Option<&romdb::GameInfo> {
+        return self.ppu.cpu.bus.cartridge().and_then(|cart| cart.game_info());
+    }
+
+    // replaces any database loaded before, returning how many games it has.  See romdb.rs for the format
+    pub fn load_game_database(&mut self, filename: &str) -> Result<usize, String> {
+        self.game_database = romdb::load_database_file(filename)?;
+        return Ok(self.game_database.len());
+    }
+
+    pub fn flush_battery_ram(&mut self) -> Result<(), cartridge::CartridgeError> {
+        return match self.ppu.cpu.bus.cartridge_mut() {
+            Some(cartridge) => cartridge.flush_battery_ram(),
+            None => Ok(()),
+        }
+    }
+
+    // why the last periodic save failed, cleared once one succeeds
+    pub fn last_save_error(&self) -> Option<&cartridge::CartridgeError> {
+        return self.last_save_error.as_ref();
+    }
+
+    fn autosave(&mut self) {
+        self.last_save_error = self.flush_battery_ram().err();
+    }
+
+    pub fn load_symbols(&mut self, filename: &str) -> Result<(), String> {
+        let symbols = symbols::load_symbol_file(filename)?;
+        self.ppu.cpu.set_symbols(symbols);
+        return Ok(());
     }
 
     pub fn reset() {
@@ -25,9 +131,58 @@ impl Nes {
         if self.system_clock_counter % 3 == 0 {
             self.ppu.cpu.clock();
         }
+        if self.ppu.take_frame_complete() {
+            self.ppu.cpu.end_profiler_frame();
+            self.frame_count += 1;
+            if self.frame_count % SAVE_INTERVAL_FRAMES == 0 {
+                self.autosave();
+            }
+        }
         self.system_clock_counter += 1;
     }
 
+    // Code/Data Logger
+    pub fn start_code_data_logger(&mut self) {
+        if let Some(cart) = self.ppu.cpu.bus.cartridge_mut() {
+            cart.start_code_data_logger();
+        }
+    }
+
+    pub fn save_code_data_log(&self, filename: &str) -> Result<(), String> {
+        return match self.ppu.cpu.bus.cartridge().and_then(|cart| cart.code_data_logger()) {
+            Some(logger) => logger.save(filename),
+            None => Err("ERROR: The code/data logger isn't running".to_string()),
+        }
+    }
+
+    // continues logging on top of a previously saved .cdl file
+    pub fn load_code_data_log(&mut self, filename: &str) -> Result<(), String> {
+        self.start_code_data_logger();
+        return match self.ppu.cpu.bus.cartridge_mut().and_then(|cart| cart.code_data_logger_mut()) {
+            Some(logger) => logger.load(filename),
+            None => Err("ERROR: No cartridge loaded".to_string()),
+        }
+    }
+
+    // Profiler
+    pub fn start_profiler(&mut self) {
+        self.ppu.cpu.start_profiler();
+    }
+
+    pub fn profiler_report(&self, cumulative: bool) -> Option<String> {
+        return self.ppu.cpu.profiler_report(cumulative);
+    }
+
+    pub fn write_folded_stacks(&self, filename: &str) -> Result<(), String> {
+        return self.ppu.cpu.write_folded_stacks(filename);
+    }
+
+    // Debugger Interface
+    pub fn run_gdb_stub(&mut self, port: u16) -> std::io::Result<()> {
+        let mut stub = gdbstub::create_gdb_stub();
+        return stub.listen(&mut self.ppu.cpu, port);
+    }
+
     // test functions
     fn load_program(&mut self, program: String) {
         return self.ppu.cpu.load_program(program);
@@ -37,7 +192,7 @@ impl Nes {
         self.ppu.cpu.run_program();
     }
 
-    fn read_cpu_address(self, addr: u16) -> u8 {
+    fn read_cpu_address(&mut self, addr: u16) -> u8 {
         return self.ppu.cpu.bus.read(addr);
     }
 
@@ -50,6 +205,9 @@ pub fn create_nes() -> Nes {
     let nes = Nes {
         ppu: olc2C02::create_olc2C02(),
         system_clock_counter: 0,
+        frame_count: 0,
+        last_save_error: None,
+        game_database: Vec::new(),
     };
     return nes;
 }
@@ -59,6 +217,7 @@ mod tests {
     use super::*;
     use std::fs::File;
     use std::io::{prelude::*, BufReader};
+    use super::super::cdl;
     use super::super::logline;
 
     #[test]
@@ -89,16 +248,57 @@ mod tests {
     #[test]
     fn read_from_cartridge() {
         let mut nes = create_nes();
-        nes.load_rom("./test_files/nestest.nes");
+        nes.load_rom("./test_files/nestest.nes").unwrap();
         let result = nes.read_cpu_address(0x8000);
         assert_eq!(result, 0x4C);
     }
 
+    #[test]
+    fn game_database_is_replaced() {
+        let mut nes = create_nes();
+        nes.load_rom("./test_files/nestest.nes").unwrap();
+        assert_eq!(nes.game_info(), None);
+
+        std::fs::create_dir_all("./log").unwrap();
+        std::fs::write("./log/game_database_is_replaced.txt", "158B0388|-|0|0|H|0|8192|0|0|NTSC|World|nestest\n").unwrap();
+        assert_eq!(nes.load_game_database("./log/game_database_is_replaced.txt"), Ok(1));
+        assert_eq!(nes.load_game_database("./log/game_database_is_replaced.txt"), Ok(1));
+        assert_eq!(nes.game_database.len(), 1);
+        nes.load_rom("./test_files/nestest.nes").unwrap();
+        assert_eq!(nes.game_info().map(|game| game.title.as_str()), Some("nestest"));
+
+        // a failed load keeps the old database
+        assert!(nes.load_game_database("./log/missing_game_database.txt").is_err());
+        assert_eq!(nes.game_database.len(), 1);
+    }
+
+    #[test]
+    fn code_data_log_from_nestest() {
+        let mut nes = create_nes();
+        nes.ppu.cpu.set_log_file("./log/code_data_log_from_nestest.log");
+        nes.load_rom("./test_files/nestest.nes").unwrap();
+        nes.start_code_data_logger();
+        nes.ppu.cpu.run_automation();
+        nes.save_code_data_log("./log/nestest.cdl").unwrap();
+
+        let mut reloaded = create_nes();
+        reloaded.load_rom("./test_files/nestest.nes").unwrap();
+        reloaded.load_code_data_log("./log/nestest.cdl").unwrap();
+        let logger = reloaded.ppu.cpu.bus.cartridge().unwrap().code_data_logger().unwrap();
+        // C000: JMP $C5F5, executed first
+        assert_eq!(logger.prg_flags(0x0000) & cdl::PRG_CODE, cdl::PRG_CODE);
+        assert_eq!(logger.prg_flags(0x0001) & cdl::PRG_CODE, cdl::PRG_CODE);
+        assert_eq!(logger.prg_flags(0x0002) & cdl::PRG_CODE, cdl::PRG_CODE);
+        let (code, _, untouched) = logger.prg_coverage();
+        assert!(code > 0);
+        assert!(untouched > 0);
+    }
+
     #[test]
     fn nestest_regular_opcodes() {
         let mut nes = create_nes();
         nes.ppu.cpu.set_log_file("./log/nestest_regular_opcodes.log");
-        nes.load_rom("./test_files/nestest.nes");
+        nes.load_rom("./test_files/nestest.nes").unwrap();
         nes.ppu.cpu.run_automation();
         
         let our_file = File::open("./log/nestest_regular_opcodes.log").unwrap();
@@ -120,4 +320,83 @@ mod tests {
         }
         assert_eq!(current_line, 5004); // 5004 is the line where undocumented opcodes start being tested
     }
+
+    #[test]
+    fn load_rom_reports_errors() {
+        let mut nes = create_nes();
+        let error = nes.load_rom("./test_files/missing.nes").err().unwrap();
+        assert!(matches!(error, cartridge::CartridgeError::Io { .. }));
+        assert!(nes.ppu.cpu.bus.cartridge().is_none());
+
+        assert_eq!(nes.load_rom("./test_files/nestest.nes"), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn unload_rom_writes_save() {
+        // nestest with the battery bit set, and a changed CHR byte so the
+        // game database doesn't correct the header
+        let mut image = std::fs::read("./test_files/nestest.nes").unwrap();
+        image[6] |= 0x02;
+        *image.last_mut().unwrap() ^= 0xFF;
+        std::fs::create_dir_all("./log").unwrap();
+        std::fs::write("./log/unload_rom_writes_save.nes", &image).unwrap();
+        let _ = std::fs::remove_file("./log/unload_rom_writes_save.sav");
+
+        let mut nes = create_nes();
+        nes.load_rom("./log/unload_rom_writes_save.nes").unwrap();
+        nes.write_cpu_address(0x6010, 0x99);
+        nes.unload_rom().unwrap();
+        assert!(nes.ppu.cpu.bus.cartridge().is_none());
+        assert_eq!(std::fs::read("./log/unload_rom_writes_save.sav").unwrap()[0x10], 0x99);
+
+        // a save that can't be written keeps the cartridge, and its RAM, connected
+        nes.load_rom("./log/unload_rom_writes_save.nes").unwrap();
+        nes.write_cpu_address(0x6010, 0x42);
+        nes.ppu.cpu.bus.cartridge_mut().unwrap().set_save_filename("./log/missing_directory/unload_rom_writes_save.sav");
+        nes.autosave();
+        assert!(matches!(nes.last_save_error(), Some(cartridge::CartridgeError::Io { .. })));
+        assert!(nes.unload_rom().is_err());
+        assert!(nes.ppu.cpu.bus.cartridge().is_some());
+
+        nes.ppu.cpu.bus.cartridge_mut().unwrap().set_save_filename("./log/unload_rom_writes_save.sav");
+        nes.autosave();
+        assert_eq!(nes.last_save_error(), None);
+        assert_eq!(std::fs::read("./log/unload_rom_writes_save.sav").unwrap()[0x10], 0x42);
+        nes.unload_rom().unwrap();
+        assert!(nes.ppu.cpu.bus.cartridge().is_none());
+    }
+
+    #[test]
+    fn disk_system() {
+        // a BIOS that spins on its reset vector, and a two sided disk
+        let mut bios = vec![0u8; 0x2000];
+        bios[0x0000..0x0003].copy_from_slice(&[0x4C, 0x00, 0xE0]);
+        bios[0x1FFC..0x1FFE].copy_from_slice(&[0x00, 0xE0]);
+        let mut disk = b"FDS\x1A\x02".to_vec();
+        disk.resize(16 + 2 * 65500, 0);
+        std::fs::create_dir_all("./log").unwrap();
+        std::fs::write("./log/disk_system.bin", &bios).unwrap();
+        std::fs::write("./log/disk_system.fds", &disk).unwrap();
+
+        let mut nes = create_nes();
+        assert_eq!(nes.disk_sides(), 0);
+        assert!(nes.insert_disk(0).is_err());
+        let error = nes.load_disk("./log/disk_system.fds", "./test_files/nestest.nes").err().unwrap();
+        assert!(matches!(error, cartridge::CartridgeError::BadBios { .. }));
+
+        nes.load_disk("./log/disk_system.fds", "./log/disk_system.bin").unwrap();
+        assert_eq!(nes.disk_sides(), 2);
+        assert_eq!(nes.inserted_disk_side(), Some(0));
+        assert_eq!(nes.read_cpu_address(0xE000), 0x4C);
+        nes.write_cpu_address(0xDFFF, 0x12); // the end of the adapter's 32k of RAM
+        assert_eq!(nes.read_cpu_address(0xDFFF), 0x12);
+        assert_eq!(nes.read_cpu_address(0x4032) & 0x07, 0x02); // inserted, not spinning
+
+        nes.eject_disk().unwrap();
+        assert_eq!(nes.inserted_disk_side(), None);
+        assert_eq!(nes.read_cpu_address(0x4032) & 0x07, 0x07);
+        nes.insert_disk(1).unwrap();
+        assert_eq!(nes.inserted_disk_side(), Some(1));
+        assert!(nes.insert_disk(2).is_err());
+    }
 }
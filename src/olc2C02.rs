@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+use super::cdl;
 use super::olc6502;
 
 const NAMETABLE_SIZE: usize = 1024;
@@ -14,6 +15,12 @@ pub struct Olc2C02 {
     scanline: i16,
     cycle: i16,
     frame_complete: bool,
+
+    // PPUCTRL/PPUMASK aren't wired to the cpu bus yet, so these are set directly
+    rendering_enabled: bool,
+    background_pattern_table: u16,
+    sprite_pattern_table: u16,
+    next_tile_id: u8,
 }
 
 impl Olc2C02 {
@@ -22,6 +29,18 @@ impl Olc2C02 {
         
         // TODO: set pixel here
 
+        // the cartridge sees every fetch, which is how MMC3 and friends count scanlines
+        if self.rendering_enabled && self.scanline < 240 {
+            self.fetch_tile_data();
+        }
+
+        // mappers that count scanlines see the end of each rendered line
+        if self.cycle == 260 && self.scanline < 240 {
+            if let Some(cart) = self.cpu.bus.cartridge_mut() {
+                cart.scanline();
+            }
+        }
+
         self.cycle += 1;
         if self.cycle >= 341 {
             self.cycle = 0;
@@ -34,7 +53,97 @@ impl Olc2C02 {
         }
     }
 
-    fn cpu_read(&self, addr: u16) -> u8 {
+    // memory fetches of a rendered scanline: 4 per tile for the background at
+    // cycles 1-256 and 321-336, for the 8 sprite slots at 257-320, and two
+    // dummy nametable reads at 337-340
+    fn fetch_tile_data(&mut self) {
+        let cycle = self.cycle;
+        let fetching_sprites = (257..=320).contains(&cycle);
+        if cycle == 337 || cycle == 339 {
+            // two unused fetches of the nametable byte the next line starts with
+            let line = ((self.scanline + 1) as u16) & 0xFF;
+            self.ppu_bus_read(0x2000 | ((line >> 3) << 5) | 2, cdl::ChrAccess::Rendered);
+            return;
+        }
+        if cycle == 0 || cycle > 336 {
+            return;
+        }
+        let (line, tile_x) = if cycle >= 321 {
+            (self.scanline + 1, (cycle - 321) / 8)
+        } else {
+            (self.scanline, (cycle - 1) / 8 + 2)
+        };
+        let line = (line as u16) & 0xFF;
+        let tile_x = (tile_x as u16) & 0x1F;
+        let fine_y = line & 0x07;
+
+        match (cycle - 1) % 8 {
+            0 => self.next_tile_id = self.ppu_bus_read(0x2000 | ((line >> 3) << 5) | tile_x, cdl::ChrAccess::Rendered),
+            2 => { self.ppu_bus_read(0x23C0 | ((line >> 5) << 3) | (tile_x >> 2), cdl::ChrAccess::Rendered); }
+            4 | 6 => {
+                let plane = if (cycle - 1) % 8 == 6 { 0x08 } else { 0x00 };
+                // no sprite evaluation yet, so every slot is empty and fetches tile $FF
+                let addr = if fetching_sprites {
+                    self.sprite_pattern_table | 0x0FF0 | plane | fine_y
+                } else {
+                    self.background_pattern_table | ((self.next_tile_id as u16) << 4) | plane | fine_y
+                };
+                self.ppu_bus_read(addr, cdl::ChrAccess::Rendered);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_rendering_enabled(&mut self, enabled: bool) {
+        self.rendering_enabled = enabled;
+    }
+
+    pub fn set_pattern_tables(&mut self, background: u16, sprites: u16) {
+        self.background_pattern_table = background & 0x1000;
+        self.sprite_pattern_table = sprites & 0x1000;
+    }
+
+    // true once per completed frame
+    pub fn take_frame_complete(&mut self) -> bool {
+        let complete = self.frame_complete;
+        self.frame_complete = false;
+        return complete;
+    }
+
+    // PPU bus: pattern tables and nametables come from the cartridge,
+    // which decides how the console's nametable RAM is mirrored
+    pub fn ppu_read(&mut self, addr: u16) -> u8 {
+        return self.ppu_bus_read(addr, cdl::ChrAccess::Read);
+    }
+
+    fn ppu_bus_read(&mut self, addr: u16, access: cdl::ChrAccess) -> u8 {
+        let addr = addr & 0x3FFF;
+        if addr >= 0x3F00 {
+            return self.palettes[palette_index(addr)];
+        }
+        let ciram = self.nametables.as_flattened();
+        return match self.cpu.bus.cartridge_mut() {
+            Some(cart) => cart.ppu_read(addr, ciram, access),
+            None if addr >= 0x2000 => ciram[(addr & 0x07FF) as usize],
+            None => 0x00,
+        }
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8) {
+        let addr = addr & 0x3FFF;
+        if addr >= 0x3F00 {
+            self.palettes[palette_index(addr)] = data;
+            return;
+        }
+        let ciram = self.nametables.as_flattened_mut();
+        match self.cpu.bus.cartridge_mut() {
+            Some(cart) => cart.ppu_write(addr, data, ciram),
+            None if addr >= 0x2000 => ciram[(addr & 0x07FF) as usize] = data,
+            None => {}
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
         return self.cpu.bus.read(addr);
     }
 
@@ -43,6 +152,12 @@ impl Olc2C02 {
     }
 }
 
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x001F) as usize;
+    // the backdrop entries of the sprite palettes mirror the background ones
+    return if index >= 0x10 && index % 4 == 0 { index - 0x10 } else { index };
+}
+
 #[allow(non_snake_case)]
 pub fn create_olc2C02() -> Olc2C02 {
     return Olc2C02 {
@@ -52,6 +167,10 @@ pub fn create_olc2C02() -> Olc2C02 {
         scanline: 0,
         cycle: 0,
         frame_complete: false,
+        rendering_enabled: true,
+        background_pattern_table: 0x0000,
+        sprite_pattern_table: 0x1000,
+        next_tile_id: 0,
     };
 }
 
@@ -65,4 +184,70 @@ mod tests {
         ppu.cpu_write(0x24, 0x20);
         assert_eq!(ppu.cpu_read(0x24), 0x20);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn ppu_bus_through_cartridge() {
+        let mut ppu = create_olc2C02();
+        ppu.cpu.bus.connect_cartridge(super::super::cartridge::create_cartridge_from_file("./test_files/nestest.nes", &[]).unwrap());
+        ppu.ppu_write(0x2001, 0x42);
+        assert_eq!(ppu.ppu_read(0x2401), 0x42); // horizontal mirroring
+        assert_eq!(ppu.ppu_read(0x2801), 0x00);
+        ppu.ppu_write(0x3F10, 0x0F);
+        assert_eq!(ppu.ppu_read(0x3F00), 0x0F);
+        assert_eq!(ppu.ppu_read(0x3F30), 0x0F);
+        ppu.ppu_write(0x0000, 0xFF); // CHR ROM
+        assert_eq!(ppu.ppu_read(0x0000), 0x00);
+    }
+
+    // writes a blank 32k PRG / 8k CHR rom for the given mapper and plugs it in
+    fn connect_blank_rom(ppu: &mut Olc2C02, mapper_id: u8, filename: &str) {
+        let mut rom: Vec<u8> = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, mapper_id << 4, mapper_id & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(vec![0u8; 0x8000 + 0x2000]);
+        std::fs::write(filename, &rom).unwrap();
+        ppu.cpu.bus.connect_cartridge(super::super::cartridge::create_cartridge_from_file(filename, &[]).unwrap());
+    }
+
+    // runs whole scanlines until the cartridge raises an IRQ, returning how many it took
+    fn scanlines_until_irq(ppu: &mut Olc2C02, limit: usize) -> usize {
+        let mut scanlines = 0;
+        while !ppu.cpu.bus.irq_pending() && scanlines < limit {
+            for dot in 0..341 {
+                ppu.clock();
+                if dot % 3 == 0 {
+                    ppu.cpu.bus.cpu_clock();
+                }
+            }
+            scanlines += 1;
+        }
+        return scanlines;
+    }
+
+    #[test]
+    fn pattern_fetches_clock_mmc3() {
+        let mut ppu = create_olc2C02();
+        connect_blank_rom(&mut ppu, 4, "./log/pattern_fetches_clock_mmc3.nes");
+        ppu.cpu.bus.write(0xC000, 10); // IRQ after 11 scanlines
+        ppu.cpu.bus.write(0xC001, 0);
+        ppu.cpu.bus.write(0xE001, 0);
+        assert_eq!(scanlines_until_irq(&mut ppu, 262), 11);
+
+        // nothing is fetched with rendering off
+        ppu.cpu.bus.write(0xE000, 0);
+        ppu.cpu.bus.write(0xE001, 0);
+        ppu.set_rendering_enabled(false);
+        assert_eq!(scanlines_until_irq(&mut ppu, 20), 20);
+    }
+
+    #[test]
+    fn nametable_fetches_clock_mmc5() {
+        let mut ppu = create_olc2C02();
+        connect_blank_rom(&mut ppu, 5, "./log/nametable_fetches_clock_mmc5.nes");
+        ppu.cpu.bus.write(0x5203, 100);
+        ppu.cpu.bus.write(0x5204, 0x80);
+        // lines are detected from the previous line's dummy fetches, and the ppu
+        // powers on at line 0 with no pre-render line, so the first frame is a line late
+        assert_eq!(scanlines_until_irq(&mut ppu, 262), 102);
+        ppu.cpu.bus.read(0x5204);
+        assert_eq!(scanlines_until_irq(&mut ppu, 262), 261);
+    }
+}
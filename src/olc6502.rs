@@ -6,6 +6,10 @@ use std::io::prelude::*;
 use std::io::LineWriter;
 
 use super::bus;
+use super::callstack;
+use super::cdl;
+use super::profiler;
+use super::symbols;
 
 static STACK_BASE: u16 = 0x0100;
 
@@ -41,10 +45,26 @@ pub struct Olc6502 {
     lookup: [Instruction; 256],
 
     program_complete: bool,
+    irq_line: bool, // level of the /IRQ input, driven by the APU (the cartridge is polled through the bus)
+
+    call_stack: callstack::CallStack,
+    symbols: symbols::SymbolTable,
+    profiler: Option<profiler::Profiler>,
 
     log_file: LineWriter<File>,
 }
 
+// snapshot of the programmer-visible registers, used by the debugger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub accumulator: u8,
+    pub x_reg: u8,
+    pub y_reg: u8,
+    pub stack_ptr: u8,
+    pub prog_ctr: u16,
+    pub status_reg: u8,
+}
+
 impl PartialEq for Olc6502 {
     fn eq(&self, other: &Olc6502) -> bool {
         self.accumulator == other.accumulator &&
@@ -66,9 +86,19 @@ impl PartialEq for Olc6502 {
 impl Olc6502 {
 
     pub fn clock(&mut self) {
-        if self.cycles == 0 {
+        let irq_asserted = self.irq_line || self.bus.irq_pending();
+        if self.cycles == 0 && irq_asserted && self.get_flag(Flags6502::I) == 0 {
+            // interrupts are only taken between instructions
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.begin_instruction(&self.call_stack);
+            }
+            self.irq();
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.end_instruction(self.cycles as u32, &self.call_stack);
+            }
+        } else if self.cycles == 0 {
             self.lines_of_code += 1; // debug variable
-            self.opcode = self.read(self.prog_ctr);
+            self.opcode = self.bus.read_as(self.prog_ctr, cdl::PrgAccess::Code);
             let op_index = usize::from(self.opcode);
             if self.lookup[op_index].name == "BRK" && self.stack_ptr == 0 {
                 self.program_complete = true;
@@ -85,14 +115,23 @@ impl Olc6502 {
             // Get starting number of cycles
             self.cycles = self.lookup[op_index].cycles;
 
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.begin_instruction(&self.call_stack);
+            }
+
             // execute next instruction
             let additional_cycle1: u8 = (self.lookup[op_index].addrmode)(self);
             let additional_cycle2: u8 = (self.lookup[op_index].operate)(self);
 
             // add additional cycles if necessary
             self.cycles += additional_cycle1 & additional_cycle2;
+
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.end_instruction(self.cycles as u32, &self.call_stack);
+            }
         }
 
+        self.bus.cpu_clock();
         self.cycles -= 1;
     }
 
@@ -115,12 +154,17 @@ impl Olc6502 {
         self.opcode = 0;
         self.cycles = 8; // reset takes time
         self.lookup = populate_lookup_table();
+        self.call_stack.clear();
     }
 
-    fn read(&self, addr: u16) -> u8 {
+    fn read(&mut self, addr: u16) -> u8 {
         return self.bus.read(addr);
     }
 
+    fn read_operand(&mut self, addr: u16) -> u8 {
+        return self.bus.read_as(addr, cdl::PrgAccess::Operand);
+    }
+
     fn write(mut self, addr: u16, data: u8) {
         self.bus.write(addr, data);
     }
@@ -147,8 +191,8 @@ impl Olc6502 {
         let instr = &self.lookup[self.opcode as usize];
         let op = &instr.name;
         let mut args = ["  "; 2];
-        let arg0 = format!("{:02X}", self.bus.read(self.prog_ctr + 1));
-        let arg1 = format!("{:02X}", self.bus.read(self.prog_ctr + 2));
+        let arg0 = format!("{:02X}", self.bus.peek(self.prog_ctr + 1));
+        let arg1 = format!("{:02X}", self.bus.peek(self.prog_ctr + 2));
         if instr.num_bytes >= 2 {
             args[0] = &arg0;
         } 
@@ -161,7 +205,11 @@ impl Olc6502 {
         let y_reg = format!("{:02X}", self.y_reg);
         let stack_ptr = format!("{:02X}", self.stack_ptr);
         let status_reg = format!("{:02X}", self.status_reg);
-        let logline = format!("{} {} {} {}\t\tA:{} X:{} Y:{} P:{} SP:{}\n", prog_ctr, op, args[0], args[1], accumulator, x_reg, y_reg, status_reg, stack_ptr);
+        let operands = match self.operand_label(self.prog_ctr) {
+            Some(label) => label,
+            None => format!("{} {}", args[0], args[1]),
+        };
+        let logline = format!("{} {} {}\t\tA:{} X:{} Y:{} P:{} SP:{}\n", prog_ctr, op, operands, accumulator, x_reg, y_reg, status_reg, stack_ptr);
         self.log_file.write_all(logline.as_bytes()).expect("Unable to write to log file");
     }
 
@@ -189,6 +237,178 @@ impl Olc6502 {
         }
     }
 
+    // Debugger Interface
+    pub fn step(&mut self) {
+        // finish any instruction still in flight, then run exactly one more
+        while self.cycles > 0 {
+            self.clock();
+        }
+        self.clock();
+        while self.cycles > 0 {
+            self.clock();
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        return Registers {
+            accumulator: self.accumulator,
+            x_reg: self.x_reg,
+            y_reg: self.y_reg,
+            stack_ptr: self.stack_ptr,
+            prog_ctr: self.prog_ctr,
+            status_reg: self.status_reg,
+        };
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.accumulator = registers.accumulator;
+        self.x_reg = registers.x_reg;
+        self.y_reg = registers.y_reg;
+        self.stack_ptr = registers.stack_ptr;
+        self.prog_ctr = registers.prog_ctr;
+        self.status_reg = registers.status_reg;
+    }
+
+    pub fn step_out(&mut self) {
+        // run until the innermost subroutine or interrupt handler returns
+        let depth = self.call_stack.depth();
+        if depth == 0 {
+            self.step();
+            return;
+        }
+        while self.call_stack.depth() >= depth && !self.program_complete {
+            self.step();
+        }
+    }
+
+    pub fn call_stack(&self) -> &callstack::CallStack {
+        return &self.call_stack;
+    }
+
+    pub fn clear_stack_violations(&mut self) {
+        self.call_stack.clear_violations();
+    }
+
+    pub fn set_symbols(&mut self, symbols: symbols::SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn label_for(&self, addr: u16) -> Option<&str> {
+        return self.symbols.lookup(addr, self.bus.prg_rom_offset(addr));
+    }
+
+    // returns the instruction at addr in assembler syntax, and the address of the next instruction
+    pub fn disassemble(&self, addr: u16) -> (String, u16) {
+        let opcode = self.bus.peek(addr) as usize;
+        let instr = &self.lookup[opcode];
+        let lo = self.bus.peek(u16::wrapping_add(addr, 1));
+        let hi = self.bus.peek(u16::wrapping_add(addr, 2));
+        let absolute = ((hi as u16) << 8) | lo as u16;
+        let operand = if self.addrmode_is(opcode, ACC) {
+            "A".to_string()
+        } else if self.addrmode_is(opcode, IMM) {
+            format!("#${:02X}", lo)
+        } else if self.addrmode_is(opcode, ZP0) {
+            self.address_text(lo as u16)
+        } else if self.addrmode_is(opcode, ZPX) {
+            format!("{},X", self.address_text(lo as u16))
+        } else if self.addrmode_is(opcode, ZPY) {
+            format!("{},Y", self.address_text(lo as u16))
+        } else if self.addrmode_is(opcode, ABS) {
+            self.address_text(absolute)
+        } else if self.addrmode_is(opcode, ABX) {
+            format!("{},X", self.address_text(absolute))
+        } else if self.addrmode_is(opcode, ABY) {
+            format!("{},Y", self.address_text(absolute))
+        } else if self.addrmode_is(opcode, IND) {
+            format!("({})", self.address_text(absolute))
+        } else if self.addrmode_is(opcode, IZX) {
+            format!("({},X)", self.address_text(lo as u16))
+        } else if self.addrmode_is(opcode, IZY) {
+            format!("({}),Y", self.address_text(lo as u16))
+        } else if self.addrmode_is(opcode, REL) {
+            self.address_text(branch_target(addr, lo))
+        } else {
+            String::new()
+        };
+        let text = if operand.is_empty() {
+            instr.name.clone()
+        } else {
+            format!("{} {}", instr.name, operand)
+        };
+        let length = u8::max(instr.num_bytes, 1) as u16;
+        return (text, u16::wrapping_add(addr, length));
+    }
+
+    fn addrmode_is(&self, opcode: usize, addrmode: fn(&mut Olc6502) -> u8) -> bool {
+        return self.lookup[opcode].addrmode as usize == addrmode as usize;
+    }
+
+    fn address_text(&self, addr: u16) -> String {
+        return match self.label_for(addr) {
+            Some(label) => label.to_string(),
+            None if addr <= 0xFF => format!("${:02X}", addr),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    // label for the address an instruction's operand refers to, used by the trace log
+    fn operand_label(&self, addr: u16) -> Option<String> {
+        if self.symbols.is_empty() {
+            return None;
+        }
+        let opcode = self.bus.peek(addr) as usize;
+        let lo = self.bus.peek(u16::wrapping_add(addr, 1));
+        let hi = self.bus.peek(u16::wrapping_add(addr, 2));
+        let target = if self.addrmode_is(opcode, REL) {
+            branch_target(addr, lo)
+        } else if self.lookup[opcode].num_bytes == 3 && !self.addrmode_is(opcode, IMM) {
+            ((hi as u16) << 8) | lo as u16
+        } else if self.lookup[opcode].num_bytes == 2 && !self.addrmode_is(opcode, IMM) {
+            lo as u16
+        } else {
+            return None;
+        };
+        return self.label_for(target).map(|label| label.to_string());
+    }
+
+    pub fn start_profiler(&mut self) {
+        if self.profiler.is_none() {
+            self.profiler = Some(profiler::create_profiler());
+        }
+    }
+
+    pub fn stop_profiler(&mut self) -> Option<profiler::Profiler> {
+        return self.profiler.take();
+    }
+
+    pub fn profiler(&self) -> Option<&profiler::Profiler> {
+        return self.profiler.as_ref();
+    }
+
+    pub fn end_profiler_frame(&mut self) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.end_frame();
+        }
+    }
+
+    pub fn profiler_report(&self, cumulative: bool) -> Option<String> {
+        let name_for = |addr: u16| self.label_for(addr).map(|label| label.to_string());
+        return self.profiler.as_ref().map(|profiler| profiler.report(cumulative, &name_for));
+    }
+
+    pub fn write_folded_stacks(&self, filename: &str) -> Result<(), String> {
+        let name_for = |addr: u16| self.label_for(addr).map(|label| label.to_string());
+        return match self.profiler.as_ref() {
+            Some(profiler) => profiler.write_folded_stacks(filename, &name_for),
+            None => Err("ERROR: The profiler isn't running".to_string()),
+        }
+    }
+
+    pub fn is_program_complete(&self) -> bool {
+        return self.program_complete;
+    }
+
     pub fn set_log_file(&mut self, filename: &str) {
         let file = File::create(filename).unwrap();
         self.log_file = LineWriter::new(file);
@@ -199,6 +419,7 @@ impl Olc6502 {
     }
 
     fn run_interrupt(&mut self, inter_addr: u16, cycles: u8, b_flag: bool) {
+        let interrupted_addr = self.prog_ctr;
         self.push_to_stack((self.prog_ctr >> 8) as u8);
         self.push_to_stack(self.prog_ctr as u8);
         let mut sr_copy = self.status_reg;
@@ -219,9 +440,22 @@ impl Olc6502 {
         let hi = self.bus.read(self.addr_abs + 1) as u16;
         self.prog_ctr = (hi << 8) | lo;
 
+        let frame_kind = if b_flag {
+            callstack::FrameKind::Brk
+        } else if inter_addr == 0xFFFA {
+            callstack::FrameKind::Nmi
+        } else {
+            callstack::FrameKind::Irq
+        };
+        self.call_stack.push_interrupt(frame_kind, interrupted_addr, self.prog_ctr, self.stack_ptr);
+
         self.cycles = cycles;
     }
 
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
     fn irq(&mut self) {
         if self.get_flag(Flags6502::I) == 0 {
             self.run_interrupt(0xFFFE, 7, false);
@@ -255,7 +489,12 @@ impl Olc6502 {
             self.fetched_data = self.accumulator;
         }
         else if !(addrmode == imp) {
-            self.fetched_data = self.read(self.addr_abs);
+            let access = if self.addrmode_is(i, IZX) || self.addrmode_is(i, IZY) {
+                cdl::PrgAccess::IndirectData
+            } else {
+                cdl::PrgAccess::Data
+            };
+            self.fetched_data = self.bus.read_as(self.addr_abs, access);
         }
         return self.fetched_data
     }
@@ -282,12 +521,20 @@ pub fn create_olc6502() -> Olc6502 {
         lines_of_code: 0,
         lookup: populate_lookup_table(),
         program_complete: false,
+        irq_line: false,
+        call_stack: callstack::create_call_stack(),
+        symbols: symbols::create_symbol_table(),
+        profiler: None,
         log_file: LineWriter::new(file),
     };
     o.reset();
     return o;
 }
 
+fn branch_target(addr: u16, offset: u8) -> u16 {
+    return u16::wrapping_add(u16::wrapping_add(addr, 2), offset as i8 as u16);
+}
+
 struct Instruction {
     name: String,
     operate: fn(&mut Olc6502) -> u8,
@@ -340,10 +587,10 @@ fn IMM(o: &mut Olc6502) -> u8 { // Immediate
 
 #[allow(non_snake_case)]
 fn ABS(o: &mut Olc6502) -> u8 { // Absolute Addressing
-    let lo: u16 = u16::from(o.read(o.prog_ctr));
+    let lo: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
-    let hi: u16 = u16::from(o.read(o.prog_ctr));
+    let hi: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
     o.addr_abs = (hi << 8) | lo;
@@ -352,7 +599,7 @@ fn ABS(o: &mut Olc6502) -> u8 { // Absolute Addressing
 
 #[allow(non_snake_case)]
 fn ZP0(o: &mut Olc6502) -> u8 { // Zero Page Addressing
-    o.addr_abs = u16::from(o.read(o.prog_ctr));
+    o.addr_abs = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
     o.addr_abs &= 0x00FF;
     return 0;    
@@ -360,7 +607,7 @@ fn ZP0(o: &mut Olc6502) -> u8 { // Zero Page Addressing
 
 #[allow(non_snake_case)]
 fn ZPX(o: &mut Olc6502) -> u8 { // Indexed Zero Page Addressing X
-    let fetched_addr = u16::from(o.read(o.prog_ctr));
+    let fetched_addr = u16::from(o.read_operand(o.prog_ctr));
     o.addr_abs = u16::wrapping_add(fetched_addr, o.x_reg as u16);
     o.prog_ctr += 1;
     o.addr_abs &= 0x00FF;
@@ -369,7 +616,7 @@ fn ZPX(o: &mut Olc6502) -> u8 { // Indexed Zero Page Addressing X
 
 #[allow(non_snake_case)]
 fn ZPY(o: &mut Olc6502) -> u8 { // Indexed Zero Page Addressing Y
-    let fetched_addr = u16::from(o.read(o.prog_ctr));
+    let fetched_addr = u16::from(o.read_operand(o.prog_ctr));
     o.addr_abs = u16::wrapping_add(fetched_addr, o.y_reg as u16);
     o.prog_ctr += 1;
     o.addr_abs &= 0x00FF;
@@ -378,10 +625,10 @@ fn ZPY(o: &mut Olc6502) -> u8 { // Indexed Zero Page Addressing Y
 
 #[allow(non_snake_case)]
 fn ABX(o: &mut Olc6502) -> u8 { // Indexed Absolute Addressing X
-    let lo: u16 = u16::from(o.read(o.prog_ctr));
+    let lo: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
-    let hi: u16 = u16::from(o.read(o.prog_ctr));
+    let hi: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
     o.addr_abs = (hi << 8) | lo;
@@ -396,10 +643,10 @@ fn ABX(o: &mut Olc6502) -> u8 { // Indexed Absolute Addressing X
 
 #[allow(non_snake_case)]
 fn ABY(o: &mut Olc6502) -> u8 { // Indexed Absolute Addressing Y
-    let lo: u16 = u16::from(o.read(o.prog_ctr));
+    let lo: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
-    let hi: u16 = u16::from(o.read(o.prog_ctr));
+    let hi: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
     o.addr_abs = (hi << 8) | lo;
@@ -420,7 +667,7 @@ fn IMP(_: &mut Olc6502) -> u8 { // Implied
 
 #[allow(non_snake_case)]
 fn REL(o: &mut Olc6502) -> u8 { // Relative Addressing
-    let mut argument: u16 = o.read(o.prog_ctr) as u16;
+    let mut argument: u16 = o.read_operand(o.prog_ctr) as u16;
     o.prog_ctr += 1;
     if argument & 0x80 > 1 {
         argument |= 0xFF00;
@@ -431,7 +678,7 @@ fn REL(o: &mut Olc6502) -> u8 { // Relative Addressing
 
 #[allow(non_snake_case)]
 fn IZX(o: &mut Olc6502) -> u8 { // Indexed Indirect Addressing X
-    let t: u16 = u16::from(o.read(o.prog_ctr));
+    let t: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
     let x: u16 = u16::from(o.x_reg);
@@ -446,7 +693,7 @@ fn IZX(o: &mut Olc6502) -> u8 { // Indexed Indirect Addressing X
 
 #[allow(non_snake_case)]
 fn IZY(o: &mut Olc6502) -> u8 { // Indirect Indexed Addressing Y
-    let t: u16 = u16::from(o.read(o.prog_ctr));
+    let t: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
     let lo: u16 = u16::from(o.read(t & 0x00FF));
@@ -464,9 +711,9 @@ fn IZY(o: &mut Olc6502) -> u8 { // Indirect Indexed Addressing Y
 
 #[allow(non_snake_case)]
 fn IND(o: &mut Olc6502) -> u8 { // Absolute Indirect
-    let ptr_lo: u16 = u16::from(o.read(o.prog_ctr));
+    let ptr_lo: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
-    let ptr_hi: u16 = u16::from(o.read(o.prog_ctr));
+    let ptr_hi: u16 = u16::from(o.read_operand(o.prog_ctr));
     o.prog_ctr += 1;
 
     let ptr: u16 = (ptr_hi << 8) | ptr_lo;
@@ -522,11 +769,18 @@ fn ASL(o: &mut Olc6502) -> u8 { // Shift Left One Bit (Memory or Accumulator)
     if o.lookup[o.opcode as usize].addrmode as usize == ACC as usize {
         o.accumulator = result;
     } else {
-        o.bus.write(o.addr_abs, result);
+        write_modified(o, data, result);
     }
     return 0;
 }
 
+// read-modify-write instructions write the unmodified value back before the
+// result, which mappers with serial ports (MMC1) can observe
+fn write_modified(o: &mut Olc6502, data: u8, result: u8) {
+    o.bus.write(o.addr_abs, data);
+    o.bus.write(o.addr_abs, result);
+}
+
 fn perform_jump(o: &mut Olc6502) {
     o.cycles += 1;
     if (o.addr_abs & 0xFF00) != (o.prog_ctr & 0xFF00) {
@@ -677,7 +931,7 @@ fn DEC(o: &mut Olc6502) -> u8 { // Decrement Memory by One
     let result = u8::wrapping_sub(data, 1);
     o.set_flag(Flags6502::N, (result & 0x80) > 1);
     o.set_flag(Flags6502::Z, result == 0x00);
-    o.bus.write(o.addr_abs, result);
+    write_modified(o, data, result);
     return 0;
 }
 
@@ -712,7 +966,7 @@ fn INC(o: &mut Olc6502) -> u8 { // Increment Memory by One
     let result = ((data as u16) + 1) as u8; // cast to u16 to handle incrementing 0xFF
     o.set_flag(Flags6502::N, (result & 0x80) > 1);
     o.set_flag(Flags6502::Z, result == 0x00);
-    o.bus.write(o.addr_abs, result);
+    write_modified(o, data, result);
     return 0;
 }
 
@@ -734,6 +988,9 @@ fn INY(o: &mut Olc6502) -> u8 { // Increment Index Y by One
 
 #[allow(non_snake_case)]
 fn JMP(o: &mut Olc6502) -> u8 { // Jump to New Location
+    if o.addrmode_is(o.opcode as usize, IND) {
+        o.bus.log_prg_access(o.addr_abs, cdl::PrgAccess::IndirectCode);
+    }
     o.prog_ctr = o.addr_abs;
     return 0;
 }
@@ -745,6 +1002,8 @@ fn JSR(o: &mut Olc6502) -> u8 { // Jump to New Location Saving Return Address
     let lo = (temp & 0x00FF) as u8;
     o.push_to_stack(hi);
     o.push_to_stack(lo);
+    let call_site = u16::wrapping_sub(o.prog_ctr, 3);
+    o.call_stack.push_call(call_site, o.addr_abs, o.prog_ctr, o.stack_ptr);
     o.prog_ctr = o.addr_abs;
     return 0;
 }
@@ -784,7 +1043,7 @@ fn LSR(o: &mut Olc6502) -> u8 { // Shift One Bit Right (Memory or Accumulator)
     if o.lookup[o.opcode as usize].addrmode as usize == ACC as usize {
         o.accumulator = result;
     } else {
-        o.bus.write(o.addr_abs, result);
+        write_modified(o, data, result);
     }
     return 0;
 }
@@ -848,7 +1107,7 @@ fn ROL(o: &mut Olc6502) -> u8 { // Rotate One Bit Left (Memory or Accumulator)
     if o.lookup[o.opcode as usize].addrmode as usize == ACC as usize {
         o.accumulator = result;
     } else {
-        o.bus.write(o.addr_abs, result);
+        write_modified(o, data, result);
     }
     return 0;
 }
@@ -864,13 +1123,14 @@ fn ROR(o: &mut Olc6502) -> u8 { // Rotate One Bit Right (Memory or Accumulator)
     if o.lookup[o.opcode as usize].addrmode as usize == ACC as usize {
         o.accumulator = result;
     } else {
-        o.bus.write(o.addr_abs, result);
+        write_modified(o, data, result);
     }
     return 0;
 }
 
 #[allow(non_snake_case)]
 fn RTI(o: &mut Olc6502) -> u8 { // Return from Interrupt
+    let stack_ptr = o.stack_ptr;
     let temp = o.pop_from_stack();
     o.set_flag(Flags6502::C, temp & 0x1 > 0);
     o.set_flag(Flags6502::Z, temp & 0x2 > 0);
@@ -882,14 +1142,17 @@ fn RTI(o: &mut Olc6502) -> u8 { // Return from Interrupt
 
     o.prog_ctr = o.pop_from_stack() as u16;
     o.prog_ctr |= (o.pop_from_stack() as u16) << 8;
+    o.call_stack.pop_return(callstack::ReturnKind::Rti, stack_ptr, o.prog_ctr);
     return 0;
 }
 
 #[allow(non_snake_case)]
 fn RTS(o: &mut Olc6502) -> u8 { // Return from Subroutine
+    let stack_ptr = o.stack_ptr;
     let mut temp = o.pop_from_stack() as u16;
     temp |= (o.pop_from_stack() as u16) << 8;
     o.prog_ctr = temp + 1;
+    o.call_stack.pop_return(callstack::ReturnKind::Rts, stack_ptr, o.prog_ctr);
     return 0;
 }
 
@@ -1068,6 +1331,29 @@ mod tests {
         assert_eq!(o.stack_top(), old_stack);
     }
 
+    #[test]
+    fn irq_line_taken_between_instructions() {
+        let assembled_source: String = "58 EA EA EA".to_string(); // CLI; NOP; NOP; NOP
+        let mut o: Olc6502 = create_olc6502();
+        o.set_log_file("./log/irq_line_taken_between_instructions.log");
+        o.load_program(assembled_source);
+        o.bus.write(0xFFFC, 0x00);
+        o.bus.write(0xFFFD, 0x80);
+        o.bus.write(0xFFFE, 0x00);
+        o.bus.write(0xFFFF, 0x90);
+        o.bus.write(0x9000, 0xEA);
+        o.reset();
+        o.set_irq_line(true);
+        o.step(); // CLI
+        assert_eq!(o.prog_ctr, 0x8001);
+        o.step(); // interrupt sequence instead of the NOP
+        assert_eq!(o.prog_ctr, 0x9000);
+        assert_eq!(o.get_flag(Flags6502::I), 1);
+        assert_eq!(o.call_stack().frames()[0].kind, callstack::FrameKind::Irq);
+        o.step(); // I is set now, so the handler runs
+        assert_eq!(o.prog_ctr, 0x9001);
+    }
+
     #[test]
     fn test_nmi() {
         let mut o: Olc6502 = create_olc6502();
@@ -2948,6 +3234,122 @@ mod tests {
         assert_eq!(o.bus.read(0x0002), 0x1E);
     }
 
+    #[test]
+    fn step_runs_one_instruction() {
+        let assembled_source: String = "A2 08 CA 8E 00 02".to_string();
+        let mut o: Olc6502 = create_olc6502();
+        o.set_log_file("./log/step_runs_one_instruction.log");
+        o.load_program(assembled_source);
+        o.bus.write(0xFFFC, 0x00);
+        o.bus.write(0xFFFD, 0x80);
+        o.reset();
+        o.step();
+        assert_eq!(o.registers().prog_ctr, 0x8002);
+        assert_eq!(o.registers().x_reg, 0x08);
+        o.step();
+        assert_eq!(o.registers().prog_ctr, 0x8003);
+        assert_eq!(o.registers().x_reg, 0x07);
+        assert_eq!(o.cycles, 0);
+    }
+
+    #[test]
+    fn call_stack_and_step_out() {
+        /* Program listing
+          *=$8000
+          JSR outer
+          NOP
+          outer:
+          JSR inner
+          RTS
+          inner:
+          LDA #$01
+          RTS
+        */
+        let assembled_source: String = "20 04 80 EA 20 08 80 60 A9 01 60".to_string();
+        let mut o: Olc6502 = create_olc6502();
+        o.set_log_file("./log/call_stack_and_step_out.log");
+        o.load_program(assembled_source);
+        o.bus.write(0xFFFC, 0x00);
+        o.bus.write(0xFFFD, 0x80);
+        o.reset();
+        o.step();
+        o.step();
+        let frames = o.call_stack().frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].target, 0x8004);
+        assert_eq!(frames[0].return_addr, 0x8003);
+        assert_eq!(frames[1].call_site, 0x8004);
+        assert_eq!(frames[1].target, 0x8008);
+        o.step_out();
+        assert_eq!(o.registers().prog_ctr, 0x8007);
+        assert_eq!(o.registers().accumulator, 0x01);
+        o.step_out();
+        assert_eq!(o.registers().prog_ctr, 0x8003);
+        assert_eq!(o.call_stack().depth(), 0);
+        assert!(o.call_stack().violations().is_empty());
+    }
+
+    #[test]
+    fn disassemble_with_symbols() {
+        let assembled_source: String = "20 0B 80 A2 08 CA 9D 00 02 D0 FA 6C 34 12 A1 10 0A".to_string();
+        let mut o: Olc6502 = create_olc6502();
+        o.set_log_file("./log/disassemble_with_symbols.log");
+        o.load_program(assembled_source);
+        assert_eq!(o.disassemble(0x8000), ("JSR $800B".to_string(), 0x8003));
+        assert_eq!(o.disassemble(0x8003), ("LDX #$08".to_string(), 0x8005));
+        assert_eq!(o.disassemble(0x8006), ("STA $0200,X".to_string(), 0x8009));
+        assert_eq!(o.disassemble(0x8009), ("BNE $8005".to_string(), 0x800B));
+        assert_eq!(o.disassemble(0x800B), ("JMP ($1234)".to_string(), 0x800E));
+        assert_eq!(o.disassemble(0x800E), ("LDA ($10,X)".to_string(), 0x8010));
+        assert_eq!(o.disassemble(0x8010), ("ASL A".to_string(), 0x8011));
+
+        let mut table = symbols::create_symbol_table();
+        table.add_cpu_label(0x800B, "update_player");
+        table.add_cpu_label(0x8005, "loop");
+        table.add_cpu_label(0x0200, "buffer");
+        o.set_symbols(table);
+        assert_eq!(o.disassemble(0x8000).0, "JSR update_player");
+        assert_eq!(o.disassemble(0x8006).0, "STA buffer,X");
+        assert_eq!(o.disassemble(0x8009).0, "BNE loop");
+    }
+
+    #[test]
+    fn profile_subroutine_cycles() {
+        /* Program listing
+          *=$8000
+          JSR wait
+          NOP
+          wait:
+          LDX #$02
+          loop:
+          DEX
+          BNE loop
+          RTS
+        */
+        let assembled_source: String = "20 04 80 EA A2 02 CA D0 FD 60".to_string();
+        let mut o: Olc6502 = create_olc6502();
+        o.set_log_file("./log/profile_subroutine_cycles.log");
+        o.load_program(assembled_source);
+        o.bus.write(0xFFFC, 0x00);
+        o.bus.write(0xFFFD, 0x80);
+        o.reset();
+        o.start_profiler();
+        for _ in 0..7 {
+            o.step();
+        }
+        let stats = o.profiler().unwrap().cumulative();
+        // JSR 6 in main; LDX 2, DEX 2 x2, BNE 3 (taken) + 2, RTS 6 in wait
+        assert_eq!(stats[&profiler::Routine::Main].exclusive_cycles, 6);
+        assert_eq!(stats[&profiler::Routine::Subroutine(0x8004)].exclusive_cycles, 2 + 2 + 3 + 2 + 2 + 6);
+        assert_eq!(stats[&profiler::Routine::Subroutine(0x8004)].calls, 1);
+
+        let mut table = symbols::create_symbol_table();
+        table.add_cpu_label(0x8004, "wait");
+        o.set_symbols(table);
+        let report = o.profiler_report(true).unwrap();
+        assert!(report.lines().nth(2).unwrap().ends_with("wait ($8004)"));
+    }
+
     #[test]
     fn short_loop() {
         /* Program listing
@@ -0,0 +1,422 @@
+#![allow(dead_code)]
+use super::romdb;
+
+/*
+ * Soft-patching: translations and hacks are distributed as patches against
+ * the original ROM, which are applied in memory when the ROM is loaded.
+ * Errors are plain descriptions, which the cartridge loader wraps.
+ */
+
+// BPS and UPS patches say how big their output is; anything past this isn't a
+// NES ROM and would only exhaust memory
+pub const MAX_TARGET_SIZE: usize = 16 * 1024 * 1024;
+
+// picks the patch format from its magic number
+pub fn apply_patch(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    return if patch.starts_with(IPS_MAGIC) {
+        apply_ips(source, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(source, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(source, patch)
+    } else {
+        Err("Not an IPS, BPS or UPS patch".to_string())
+    }
+}
+
+/*
+ * IPS patches: https://zerosoft.zophar.net/ips.php
+ *
+ * "PATCH", then records of a 3 byte offset, a 2 byte length and that many
+ * bytes to write (a length of 0 means a run: 2 byte count and 1 byte value),
+ * then "EOF".  All values are big endian.
+ */
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const IPS_MAX_RECORD: usize = 0xFFFF;
+
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err("Not an IPS patch".to_string());
+    }
+    let mut output = source.to_vec();
+    let mut position = IPS_MAGIC.len();
+    loop {
+        let record = match patch.get(position..position + 3) {
+            Some(record) => record,
+            None => return Err("IPS patch is truncated".to_string()),
+        };
+        if record == IPS_EOF {
+            break;
+        }
+        let offset = (record[0] as usize) << 16 | (record[1] as usize) << 8 | record[2] as usize;
+        let length = read_u16_be(patch, position + 3)?;
+        position += 5;
+        let data: Vec<u8> = if length > 0 {
+            let data = patch.get(position..position + length).ok_or("IPS patch is truncated")?;
+            position += length;
+            data.to_vec()
+        } else {
+            let count = read_u16_be(patch, position)?;
+            let value = *patch.get(position + 2).ok_or("IPS patch is truncated")?;
+            position += 3;
+            vec![value; count]
+        };
+        if output.len() < offset + data.len() {
+            output.resize(offset + data.len(), 0);
+        }
+        output[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    // some patches finish with a 3 byte size to truncate the output to
+    if let Some(size) = patch.get(position + 3..position + 6) {
+        output.truncate((size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize);
+    }
+    return Ok(output);
+}
+
+// the bytes that differ between two equally sized images, as an IPS patch
+pub fn create_ips(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut offset = 0;
+    while offset < modified.len() {
+        if original.get(offset) == Some(&modified[offset]) {
+            offset += 1;
+            continue;
+        }
+        // "EOF" is a valid offset ($454F46) but would end the patch, so start a byte early
+        if offset == 0x454F46 {
+            offset -= 1;
+        }
+        let mut end = offset;
+        while end < modified.len() && end - offset < IPS_MAX_RECORD && original.get(end) != Some(&modified[end]) {
+            end += 1;
+        }
+        patch.extend_from_slice(&[(offset >> 16) as u8, (offset >> 8) as u8, offset as u8]);
+        patch.extend_from_slice(&((end - offset) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[offset..end]);
+        offset = end;
+    }
+    patch.extend_from_slice(IPS_EOF);
+    return patch;
+}
+
+/*
+ * BPS patches: https://www.romhacking.net/documents/746/
+ *
+ * "BPS1", the source, target and metadata sizes, the metadata, then actions
+ * that build the target from runs of the source, the patch itself, or
+ * earlier parts of either.  Ends with CRC32s of the source, target and patch.
+ */
+const BPS_MAGIC: &[u8] = b"BPS1";
+const BPS_SOURCE_READ: usize = 0;
+const BPS_TARGET_READ: usize = 1;
+const BPS_SOURCE_COPY: usize = 2;
+const BPS_TARGET_COPY: usize = 3;
+
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let target_crc = check_footer(source, patch, BPS_MAGIC, "BPS")?;
+    let actions_end = patch.len() - 12;
+    let mut position = BPS_MAGIC.len();
+    let source_size = read_varint(patch, &mut position, actions_end)?;
+    let target_size = read_varint(patch, &mut position, actions_end)?;
+    let metadata_size = read_varint(patch, &mut position, actions_end)?;
+    position += metadata_size;
+    if source_size != source.len() {
+        return Err(format!("BPS patch expects a {} byte ROM, found {} bytes", source_size, source.len()));
+    }
+    check_target_size(target_size, "BPS")?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while position < actions_end {
+        let action = read_varint(patch, &mut position, actions_end)?;
+        let length = (action >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err("BPS patch writes past the end of its output".to_string());
+        }
+        match action & 0x03 {
+            BPS_SOURCE_READ => {
+                let start = target.len();
+                target.extend_from_slice(source.get(start..start + length).ok_or("BPS patch reads past the end of the ROM")?);
+            }
+            BPS_TARGET_READ => {
+                target.extend_from_slice(patch.get(position..position + length).ok_or("BPS patch is truncated")?);
+                position += length;
+            }
+            BPS_SOURCE_COPY => {
+                source_offset = relative_offset(source_offset, read_varint(patch, &mut position, actions_end)?)?;
+                let copied = source.get(source_offset..).and_then(|rest| rest.get(..length));
+                target.extend_from_slice(copied.ok_or("BPS patch copies past the end of the ROM")?);
+                source_offset += length;
+            }
+            _ => {
+                // byte by byte, since the copy can overlap what it's writing
+                target_offset = relative_offset(target_offset, read_varint(patch, &mut position, actions_end)?)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_offset).ok_or("BPS patch copies from past the end of its output")?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size || romdb::crc32(&target) != target_crc {
+        return Err("BPS patch produced the wrong output".to_string());
+    }
+    return Ok(target);
+}
+
+// BPS copy offsets are stored as a sign bit and a distance from the last copy
+fn relative_offset(offset: usize, data: usize) -> Result<usize, String> {
+    let distance = data >> 1;
+    return if data & 1 > 0 {
+        offset.checked_sub(distance).ok_or_else(|| "BPS patch copies from before the start of the ROM".to_string())
+    } else {
+        offset.checked_add(distance).ok_or_else(|| "BPS patch copies from past the end of the ROM".to_string())
+    }
+}
+
+/*
+ * UPS patches: http://individual.utoronto.ca/dmeunier/ups-spec.pdf
+ *
+ * "UPS1", the source and target sizes, then records of a distance to skip
+ * and bytes to XOR with the source, ending with a 0.  Ends with CRC32s of the
+ * source, target and patch.
+ */
+const UPS_MAGIC: &[u8] = b"UPS1";
+
+pub fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let target_crc = check_footer(source, patch, UPS_MAGIC, "UPS")?;
+    let records_end = patch.len() - 12;
+    let mut position = UPS_MAGIC.len();
+    let source_size = read_varint(patch, &mut position, records_end)?;
+    let target_size = read_varint(patch, &mut position, records_end)?;
+    if source_size != source.len() {
+        return Err(format!("UPS patch expects a {} byte ROM, found {} bytes", source_size, source.len()));
+    }
+    check_target_size(target_size, "UPS")?;
+
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+    let mut offset: usize = 0;
+    while position < records_end {
+        offset = offset.checked_add(read_varint(patch, &mut position, records_end)?).ok_or("UPS patch has a bad offset")?;
+        loop {
+            let byte = *patch.get(position).filter(|_| position < records_end).ok_or("UPS patch is truncated")?;
+            position += 1;
+            if byte == 0 {
+                break;
+            }
+            match target.get_mut(offset) {
+                Some(target_byte) => *target_byte ^= byte,
+                None => return Err("UPS patch writes past the end of its output".to_string()),
+            }
+            offset += 1;
+        }
+        offset = offset.saturating_add(1); // the terminating 0 stands for an unchanged byte
+    }
+    if romdb::crc32(&target) != target_crc {
+        return Err("UPS patch produced the wrong output".to_string());
+    }
+    return Ok(target);
+}
+
+fn check_target_size(target_size: usize, format: &str) -> Result<(), String> {
+    if target_size > MAX_TARGET_SIZE {
+        return Err(format!("{} patch output is too large ({} bytes)", format, target_size));
+    }
+    return Ok(());
+}
+
+// checks the patch and source CRC32s, returning the CRC32 the output should have
+fn check_footer(source: &[u8], patch: &[u8], magic: &[u8], format: &str) -> Result<u32, String> {
+    if !patch.starts_with(magic) || patch.len() < magic.len() + 12 {
+        return Err(format!("Not a {} patch", format));
+    }
+    let footer = &patch[patch.len() - 12..];
+    let read_crc = |index: usize| u32::from_le_bytes([footer[index], footer[index + 1], footer[index + 2], footer[index + 3]]);
+    let (source_crc, target_crc, patch_crc) = (read_crc(0), read_crc(4), read_crc(8));
+    if romdb::crc32(&patch[..patch.len() - 4]) != patch_crc {
+        return Err(format!("{} patch is corrupt (checksum mismatch)", format));
+    }
+    if romdb::crc32(source) != source_crc {
+        return Err(format!("{} patch is for a different ROM (checksum mismatch)", format));
+    }
+    return Ok(target_crc);
+}
+
+// BPS and UPS numbers: 7 bits per byte, least significant first, with the
+// top bit marking the last byte
+fn read_varint(data: &[u8], position: &mut usize, end: usize) -> Result<usize, String> {
+    let mut value: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+        if *position >= end {
+            return Err("Patch is truncated".to_string());
+        }
+        let byte = data[*position] as usize;
+        *position += 1;
+        value = value.checked_add((byte & 0x7F).checked_mul(shift).ok_or("Patch has a bad number")?).ok_or("Patch has a bad number")?;
+        if byte & 0x80 > 0 {
+            return Ok(value);
+        }
+        shift = shift.checked_shl(7).ok_or("Patch has a bad number")?;
+        value = value.checked_add(shift).ok_or("Patch has a bad number")?;
+    }
+}
+
+fn read_u16_be(data: &[u8], position: usize) -> Result<usize, String> {
+    return match data.get(position..position + 2) {
+        Some(bytes) => Ok((bytes[0] as usize) << 8 | bytes[1] as usize),
+        None => Err("IPS patch is truncated".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let bits = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                output.push(0x80 | bits);
+                return;
+            }
+            output.push(bits);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&romdb::crc32(source).to_le_bytes());
+        patch.extend_from_slice(&romdb::crc32(target).to_le_bytes());
+        let crc = romdb::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        return patch;
+    }
+
+    #[test]
+    fn varints() {
+        for value in [0, 1, 127, 128, 255, 16511, 16512, 0x123456].iter() {
+            let mut encoded = Vec::new();
+            write_varint(&mut encoded, *value);
+            let mut position = 0;
+            assert_eq!(read_varint(&encoded, &mut position, encoded.len()), Ok(*value));
+            assert_eq!(position, encoded.len());
+        }
+        assert!(read_varint(&[0x00, 0x00], &mut 0, 2).is_err());
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"hello world";
+        let target = b"hello there world!hello";
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, target.len());
+        write_varint(&mut patch, 2);
+        patch.extend_from_slice(b"{}");
+        write_varint(&mut patch, ((6 - 1) << 2) | BPS_SOURCE_READ); // "hello "
+        write_varint(&mut patch, ((6 - 1) << 2) | BPS_TARGET_READ);
+        patch.extend_from_slice(b"there ");
+        write_varint(&mut patch, ((5 - 1) << 2) | BPS_SOURCE_COPY); // "world"
+        write_varint(&mut patch, 6 << 1);
+        write_varint(&mut patch, ((1 - 1) << 2) | BPS_TARGET_READ);
+        patch.extend_from_slice(b"!");
+        write_varint(&mut patch, ((5 - 1) << 2) | BPS_TARGET_COPY); // "hello" again
+        write_varint(&mut patch, 0);
+        let patch = with_footer(patch, source, target);
+
+        assert_eq!(apply_patch(source, &patch).unwrap(), target.to_vec());
+        assert!(apply_bps(b"hello worle", &patch).unwrap_err().contains("different ROM"));
+        let mut corrupt = patch.clone();
+        corrupt[10] ^= 0x01;
+        assert!(apply_bps(source, &corrupt).unwrap_err().contains("corrupt"));
+    }
+
+    #[test]
+    fn ups() {
+        let source = b"hello world";
+        let target = b"jello world!!";
+        let mut patch = b"UPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, target.len());
+        write_varint(&mut patch, 0);
+        patch.extend_from_slice(&[b'h' ^ b'j', 0x00]);
+        write_varint(&mut patch, 9); // skip " world" past the first terminator
+        patch.extend_from_slice(&[b'!', b'!', 0x00]);
+        let patch = with_footer(patch, source, target);
+
+        assert_eq!(apply_patch(source, &patch).unwrap(), target.to_vec());
+        assert!(apply_ups(b"jello world", &patch).unwrap_err().contains("different ROM"));
+        assert!(apply_patch(source, b"NOPE").is_err());
+    }
+
+    #[test]
+    fn hostile_sizes() {
+        // valid checksums, but the output would be 2^60 bytes
+        let source = b"hello world";
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 1 << 60);
+        write_varint(&mut patch, 0);
+        let patch = with_footer(patch, source, b"");
+        assert_eq!(apply_bps(source, &patch).unwrap_err(), format!("BPS patch output is too large ({} bytes)", 1usize << 60));
+
+        // a target copy far longer than the output it claims to produce
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 6);
+        write_varint(&mut patch, 0);
+        write_varint(&mut patch, ((1 - 1) << 2) | BPS_SOURCE_READ);
+        write_varint(&mut patch, (((1 << 40) - 1) << 2) | BPS_TARGET_COPY);
+        write_varint(&mut patch, 0);
+        let patch = with_footer(patch, source, b"hhhhhh");
+        assert_eq!(apply_bps(source, &patch).unwrap_err(), "BPS patch writes past the end of its output");
+
+        let mut patch = b"UPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 1 << 60);
+        let patch = with_footer(patch, source, b"");
+        assert!(apply_ups(source, &patch).unwrap_err().contains("too large"));
+
+        // a record past the end of the output
+        let mut patch = b"UPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 1 << 50);
+        patch.extend_from_slice(&[0x01, 0x00]);
+        let patch = with_footer(patch, source, source);
+        assert_eq!(apply_ups(source, &patch).unwrap_err(), "UPS patch writes past the end of its output");
+    }
+
+    #[test]
+    fn ips_round_trip() {
+        let original = vec![0u8; 0x200];
+        let mut modified = original.clone();
+        modified[0x000] = 0x01;
+        modified[0x100..0x110].copy_from_slice(&[0xAB; 0x10]);
+        modified[0x1FF] = 0xFF;
+        let patch = create_ips(&original, &modified);
+        assert!(patch.starts_with(b"PATCH") && patch.ends_with(b"EOF"));
+        assert_eq!(patch.len(), 5 + (5 + 1) + (5 + 0x10) + (5 + 1) + 3);
+        assert_eq!(apply_ips(&original, &patch).unwrap(), modified);
+        assert_eq!(create_ips(&original, &original), b"PATCHEOF".to_vec());
+    }
+
+    #[test]
+    fn ips_runs_and_errors() {
+        // a run of 4 $EE at $0002, growing the output
+        let patch = b"PATCH\x00\x00\x02\x00\x00\x00\x04\xEEEOF";
+        assert_eq!(apply_ips(&[1, 2, 3], patch).unwrap(), vec![1, 2, 0xEE, 0xEE, 0xEE, 0xEE]);
+        // with a truncation size after EOF
+        let patch = b"PATCH\x00\x00\x00\x00\x01\x09EOF\x00\x00\x02";
+        assert_eq!(apply_ips(&[1, 2, 3], patch).unwrap(), vec![9, 2]);
+
+        assert!(apply_ips(&[], b"PATCX").is_err());
+        assert!(apply_ips(&[], b"PATCH\x00\x00\x00\x00\x05\x01").is_err());
+    }
+}
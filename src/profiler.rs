@@ -0,0 +1,294 @@
+#![allow(dead_code)]
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use super::callstack;
+
+/*
+ * Cycle profiler.  Every executed instruction's cycles are charged to the
+ * routine that executed it (exclusive time) and to every routine on the
+ * shadow call stack beneath it (inclusive time).  Code running with an empty
+ * call stack is charged to "main".  Statistics are kept for the current video
+ * frame and cumulatively, and the cumulative call paths can be written out in
+ * the folded-stack format read by flamegraph.pl, inferno and speedscope.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Routine {
+    Main,
+    Subroutine(u16),
+    Nmi(u16),
+    Irq(u16),
+    Brk(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RoutineStats {
+    pub exclusive_cycles: u64,
+    pub inclusive_cycles: u64,
+    pub calls: u64,
+}
+
+pub struct Profiler {
+    current_frame: HashMap<Routine, RoutineStats>,
+    last_frame: HashMap<Routine, RoutineStats>,
+    cumulative: HashMap<Routine, RoutineStats>,
+    folded_stacks: HashMap<Vec<Routine>, u64>,
+    frames_completed: u64,
+    path: Vec<Routine>, // call path of the instruction being executed, outermost first
+}
+
+impl Profiler {
+
+    // call before executing an instruction
+    pub fn begin_instruction(&mut self, call_stack: &callstack::CallStack) {
+        self.path.clear();
+        self.path.push(Routine::Main);
+        self.path.extend(call_stack.frames().iter().map(routine_for));
+    }
+
+    // call after executing it, with the cycles it took
+    pub fn end_instruction(&mut self, cycles: u32, call_stack: &callstack::CallStack) {
+        let cycles = cycles as u64;
+        let top = *self.path.last().unwrap_or(&Routine::Main);
+        for stats in [&mut self.current_frame, &mut self.cumulative].iter_mut() {
+            stats.entry(top).or_default().exclusive_cycles += cycles;
+            for (depth, routine) in self.path.iter().enumerate() {
+                // recursive routines only count once towards their own inclusive time
+                if !self.path[..depth].contains(routine) {
+                    stats.entry(*routine).or_default().inclusive_cycles += cycles;
+                }
+            }
+        }
+        // only copy the path the first time it's seen, this runs for every instruction
+        match self.folded_stacks.get_mut(&self.path) {
+            Some(total) => *total += cycles,
+            None => {
+                self.folded_stacks.insert(self.path.clone(), cycles);
+            }
+        }
+
+        // a JSR or interrupt just entered a new routine
+        if call_stack.depth() + 1 > self.path.len() {
+            if let Some(frame) = call_stack.frames().last() {
+                let callee = routine_for(frame);
+                self.current_frame.entry(callee).or_default().calls += 1;
+                self.cumulative.entry(callee).or_default().calls += 1;
+            }
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.last_frame = std::mem::take(&mut self.current_frame);
+        self.frames_completed += 1;
+    }
+
+    pub fn frames_completed(&self) -> u64 {
+        return self.frames_completed;
+    }
+
+    pub fn last_frame(&self) -> &HashMap<Routine, RoutineStats> {
+        return &self.last_frame;
+    }
+
+    pub fn cumulative(&self) -> &HashMap<Routine, RoutineStats> {
+        return &self.cumulative;
+    }
+
+    pub fn reset(&mut self) {
+        self.current_frame.clear();
+        self.last_frame.clear();
+        self.cumulative.clear();
+        self.folded_stacks.clear();
+        self.frames_completed = 0;
+    }
+
+    // table sorted by exclusive cycles, most expensive first
+    pub fn report(&self, cumulative: bool, name_for: &dyn Fn(u16) -> Option<String>) -> String {
+        let stats = if cumulative { &self.cumulative } else { &self.last_frame };
+        let total: u64 = stats.values().map(|s| s.exclusive_cycles).sum();
+        let mut rows: Vec<(&Routine, &RoutineStats)> = stats.iter().collect();
+        rows.sort_by(|a, b| b.1.exclusive_cycles.cmp(&a.1.exclusive_cycles).then(routine_addr(a.0).cmp(&routine_addr(b.0))));
+
+        let title = if cumulative {
+            format!("Cumulative profile over {} frames, {} cycles\n", self.frames_completed, total)
+        } else {
+            format!("Profile of frame {}, {} cycles\n", self.frames_completed, total)
+        };
+        let mut output = title;
+        output.push_str(&format!("{:>10} {:>6} {:>10} {:>6} {:>7}  {}\n", "self", "%", "total", "%", "calls", "routine"));
+        for (routine, routine_stats) in rows {
+            output.push_str(&format!("{:>10} {:>6.2} {:>10} {:>6.2} {:>7}  {}\n",
+                routine_stats.exclusive_cycles,
+                percent(routine_stats.exclusive_cycles, total),
+                routine_stats.inclusive_cycles,
+                percent(routine_stats.inclusive_cycles, total),
+                routine_stats.calls,
+                describe(routine, name_for)));
+        }
+        return output;
+    }
+
+    // one "main;outer;inner cycles" line per distinct call path
+    pub fn folded_stacks(&self, name_for: &dyn Fn(u16) -> Option<String>) -> String {
+        let mut lines: Vec<String> = self.folded_stacks.iter()
+            .map(|(path, cycles)| {
+                let names: Vec<String> = path.iter().map(|r| folded_name(r, name_for)).collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        let mut output = lines.join("\n");
+        output.push('\n');
+        return output;
+    }
+
+    pub fn write_folded_stacks(&self, filename: &str, name_for: &dyn Fn(u16) -> Option<String>) -> Result<(), String> {
+        let mut file = match File::create(filename) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("ERROR: Unable to create '{}': {}", filename, e)),
+        };
+        return match file.write_all(self.folded_stacks(name_for).as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("ERROR: Unable to write '{}': {}", filename, e)),
+        }
+    }
+}
+
+pub fn create_profiler() -> Profiler {
+    return Profiler {
+        current_frame: HashMap::new(),
+        last_frame: HashMap::new(),
+        cumulative: HashMap::new(),
+        folded_stacks: HashMap::new(),
+        frames_completed: 0,
+        path: Vec::new(),
+    };
+}
+
+fn routine_for(frame: &callstack::StackFrame) -> Routine {
+    return match frame.kind {
+        callstack::FrameKind::Subroutine => Routine::Subroutine(frame.target),
+        callstack::FrameKind::Nmi => Routine::Nmi(frame.target),
+        callstack::FrameKind::Irq => Routine::Irq(frame.target),
+        callstack::FrameKind::Brk => Routine::Brk(frame.target),
+    }
+}
+
+fn routine_addr(routine: &Routine) -> u32 {
+    return match routine {
+        Routine::Main => 0,
+        Routine::Subroutine(addr) | Routine::Nmi(addr) | Routine::Irq(addr) | Routine::Brk(addr) => *addr as u32 + 1,
+    }
+}
+
+fn percent(cycles: u64, total: u64) -> f64 {
+    return if total == 0 { 0.0 } else { cycles as f64 * 100.0 / total as f64 };
+}
+
+fn describe(routine: &Routine, name_for: &dyn Fn(u16) -> Option<String>) -> String {
+    let (kind, addr) = match routine {
+        Routine::Main => return "main".to_string(),
+        Routine::Subroutine(addr) => ("", *addr),
+        Routine::Nmi(addr) => ("NMI ", *addr),
+        Routine::Irq(addr) => ("IRQ ", *addr),
+        Routine::Brk(addr) => ("BRK ", *addr),
+    };
+    return match name_for(addr) {
+        Some(name) => format!("{}{} (${:04X})", kind, name, addr),
+        None => format!("{}${:04X}", kind, addr),
+    }
+}
+
+fn folded_name(routine: &Routine, name_for: &dyn Fn(u16) -> Option<String>) -> String {
+    let (prefix, addr) = match routine {
+        Routine::Main => return "main".to_string(),
+        Routine::Subroutine(addr) => ("", *addr),
+        Routine::Nmi(addr) => ("nmi:", *addr),
+        Routine::Irq(addr) => ("irq:", *addr),
+        Routine::Brk(addr) => ("brk:", *addr),
+    };
+    return match name_for(addr) {
+        Some(name) => format!("{}{}", prefix, name),
+        None => format!("{}${:04X}", prefix, addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_names(_: u16) -> Option<String> {
+        return None;
+    }
+
+    #[test]
+    fn attributes_cycles_to_routines() {
+        let mut profiler = create_profiler();
+        let mut stack = callstack::create_call_stack();
+
+        profiler.begin_instruction(&stack);
+        stack.push_call(0x8000, 0x9000, 0x8003, 0xFB); // JSR $9000
+        profiler.end_instruction(6, &stack);
+
+        profiler.begin_instruction(&stack);
+        profiler.end_instruction(2, &stack); // NOP inside $9000
+
+        profiler.begin_instruction(&stack);
+        stack.pop_return(callstack::ReturnKind::Rts, 0xFB, 0x8003);
+        profiler.end_instruction(6, &stack); // RTS
+
+        profiler.begin_instruction(&stack);
+        profiler.end_instruction(3, &stack); // JMP in main
+
+        let stats = profiler.cumulative();
+        assert_eq!(stats[&Routine::Main].exclusive_cycles, 9);
+        assert_eq!(stats[&Routine::Main].inclusive_cycles, 17);
+        assert_eq!(stats[&Routine::Subroutine(0x9000)].exclusive_cycles, 8);
+        assert_eq!(stats[&Routine::Subroutine(0x9000)].inclusive_cycles, 8);
+        assert_eq!(stats[&Routine::Subroutine(0x9000)].calls, 1);
+    }
+
+    #[test]
+    fn frames_and_interrupts() {
+        let mut profiler = create_profiler();
+        let mut stack = callstack::create_call_stack();
+        profiler.begin_instruction(&stack);
+        profiler.end_instruction(4, &stack);
+        profiler.end_frame();
+
+        profiler.begin_instruction(&stack);
+        stack.push_interrupt(callstack::FrameKind::Nmi, 0x8010, 0xC000, 0xFA);
+        profiler.end_instruction(7, &stack);
+        profiler.begin_instruction(&stack);
+        profiler.end_instruction(2, &stack);
+        profiler.end_frame();
+
+        assert_eq!(profiler.frames_completed(), 2);
+        assert_eq!(profiler.last_frame()[&Routine::Main].exclusive_cycles, 7);
+        assert_eq!(profiler.last_frame()[&Routine::Nmi(0xC000)].exclusive_cycles, 2);
+        assert_eq!(profiler.last_frame()[&Routine::Nmi(0xC000)].calls, 1);
+        assert_eq!(profiler.cumulative()[&Routine::Main].exclusive_cycles, 11);
+
+        let report = profiler.report(false, &no_names);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Profile of frame 2, 9 cycles");
+        assert!(lines[2].ends_with("main"));
+        assert!(lines[3].ends_with("NMI $C000"));
+    }
+
+    #[test]
+    fn folded_stack_output() {
+        let mut profiler = create_profiler();
+        let mut stack = callstack::create_call_stack();
+        stack.push_call(0x8000, 0x9000, 0x8003, 0xFB);
+        stack.push_call(0x9000, 0xA000, 0x9003, 0xF9);
+        profiler.begin_instruction(&stack);
+        profiler.end_instruction(5, &stack);
+        profiler.begin_instruction(&stack);
+        profiler.end_instruction(5, &stack);
+        let names = |addr: u16| if addr == 0x9000 { Some("update_player".to_string()) } else { None };
+        assert_eq!(profiler.folded_stacks(&names), "main;update_player;$A000 10\n");
+    }
+}
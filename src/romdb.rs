@@ -0,0 +1,233 @@
+#![allow(dead_code)]
+extern crate hex;
+
+use std::convert::TryInto;
+
+use super::cartridge::Timing;
+use super::mapper::Mirroring;
+
+/*
+ * Game database
+ *
+ * Plenty of dumps in the wild carry iNES headers with the wrong mapper,
+ * mirroring or RAM sizes.  A game database records what the board really is,
+ * keyed by a hash of PRG+CHR ROM, so the cartridge loader can correct the
+ * header and the front-end can show the game's title and region.
+ *
+ * None is built in; the front-end loads one (e.g. converted from the NES 2.0
+ * header database) with load_database_file.  One game per line, '#' starts a
+ * comment, and the SHA-1 may be '-' when only the CRC32 is known:
+ *
+ * crc32|sha1|mapper|submapper|mirroring (H, V, 4 or - for mapper controlled)|battery (0/1)|
+ *     prg ram|prg nvram|chr ram|timing (NTSC, PAL, MULTI, DENDY)|region|title
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameInfo {
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper_id: u16,
+    pub submapper: u8,
+    pub mirroring: Option<Mirroring>, // None when the mapper controls it
+    pub has_battery: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub timing: Timing,
+    pub region: String,
+    pub title: String,
+}
+
+pub fn load_database_file(filename: &str) -> Result<Vec<GameInfo>, String> {
+    let text = match std::fs::read_to_string(filename) {
+        Ok(text) => text,
+        Err(e) => return Err(format!("ERROR: Unable to read '{}': {}", filename, e)),
+    };
+    return parse_database(&text);
+}
+
+// rom is PRG ROM followed by CHR ROM
+pub fn lookup_in<'a>(database: &'a [GameInfo], rom: &[u8]) -> Option<&'a GameInfo> {
+    let crc = crc32(rom);
+    let mut digest = None; // only hash with SHA-1 if a CRC32 matches
+    for game in database.iter().filter(|game| game.crc32 == crc) {
+        match game.sha1 {
+            Some(expected) => {
+                if *digest.get_or_insert_with(|| sha1(rom)) == expected {
+                    return Some(game);
+                }
+            }
+            None => return Some(game),
+        }
+    }
+    return None;
+}
+
+pub fn parse_database(text: &str) -> Result<Vec<GameInfo>, String> {
+    let mut games = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_game(line) {
+            Some(game) => games.push(game),
+            None => return Err(format!("ERROR: Bad game database entry on line {}: {}", line_number + 1, line)),
+        }
+    }
+    return Ok(games);
+}
+
+fn parse_game(line: &str) -> Option<GameInfo> {
+    let fields: Vec<&str> = line.splitn(12, '|').map(|field| field.trim()).collect();
+    if fields.len() != 12 {
+        return None;
+    }
+    let sha1 = match fields[1] {
+        "-" => None,
+        digest => Some(hex::decode(digest).ok()?.as_slice().try_into().ok()?),
+    };
+    let mirroring = match fields[4] {
+        "H" => Some(Mirroring::Horizontal),
+        "V" => Some(Mirroring::Vertical),
+        "4" => Some(Mirroring::FourScreen),
+        "-" => None,
+        _ => return None,
+    };
+    let timing = match fields[9] {
+        "NTSC" => Timing::Ntsc,
+        "PAL" => Timing::Pal,
+        "MULTI" => Timing::MultiRegion,
+        "DENDY" => Timing::Dendy,
+        _ => return None,
+    };
+    return Some(GameInfo {
+        crc32: u32::from_str_radix(fields[0], 16).ok()?,
+        sha1,
+        mapper_id: fields[2].parse().ok()?,
+        submapper: fields[3].parse().ok()?,
+        mirroring,
+        has_battery: fields[5] == "1",
+        prg_ram_size: fields[6].parse().ok()?,
+        prg_nvram_size: fields[7].parse().ok()?,
+        chr_ram_size: fields[8].parse().ok()?,
+        timing,
+        region: fields[10].to_string(),
+        title: fields[11].to_string(),
+    });
+}
+
+// CRC-32 as used by zip and every rom database (reflected, polynomial $EDB88320)
+pub fn crc32(data: &[u8]) -> u32 {
+    lazy_static! {
+        static ref TABLE: [u32; 256] = {
+            let mut table = [0u32; 256];
+            for i in 0..256 {
+                let mut crc = i as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 > 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+                }
+                table[i] = crc;
+            }
+            table
+        };
+    }
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    return !crc;
+}
+
+// https://en.wikipedia.org/wiki/SHA-1#SHA-1_pseudocode
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // pad to a multiple of 64 bytes, ending with the message length in bits
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0x00);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(chunk[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in 0..80 {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    return digest;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes() {
+        assert_eq!(crc32(b""), 0x00000000);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(hex::encode_upper(sha1(b"abc")), "A9993E364706816ABA3E25717850C26C9CD0D89D");
+        assert_eq!(hex::encode_upper(sha1(b"")), "DA39A3EE5E6B4B0D3255BFEF95601890AFD80709");
+        // longer than one 64 byte block
+        assert_eq!(
+            hex::encode_upper(sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983E441C3BD26EBAAE4AA1F95129E5E54670F1"
+        );
+    }
+
+    #[test]
+    fn parse_and_lookup() {
+        let database = parse_database("
+            # comment
+            CBF43926|-|4|0|-|1|0|8192|0|NTSC|USA|Digits
+            CBF43926|0000000000000000000000000000000000000000|1|0|V|0|8192|0|8192|PAL|Europe|Never matches
+        ").unwrap();
+        assert_eq!(database.len(), 2);
+        let game = lookup_in(&database, b"123456789").unwrap();
+        assert_eq!(game.title, "Digits");
+        assert_eq!((game.mapper_id, game.mirroring, game.has_battery, game.prg_nvram_size), (4, None, true, 8192));
+        assert_eq!(lookup_in(&database, b"12345678"), None);
+
+        assert!(parse_database("CBF43926|-|4|0|X|1|0|8192|0|NTSC|USA|Bad mirroring").unwrap_err().contains("line 1"));
+        assert!(parse_database("CBF43926|-|4").is_err());
+    }
+
+    #[test]
+    fn loaded_database() {
+        std::fs::create_dir_all("./log").unwrap();
+        std::fs::write("./log/loaded_database.txt", "256F4507|-|2|0|V|0|0|0|8192|PAL|Europe|Loaded\n").unwrap();
+        let database = load_database_file("./log/loaded_database.txt").unwrap();
+        assert_eq!(database.len(), 1);
+        assert_eq!(lookup_in(&database, b"loaded database").map(|game| game.title.as_str()), Some("Loaded"));
+        assert!(load_database_file("./log/missing_database.txt").unwrap_err().contains("missing_database.txt"));
+    }
+}
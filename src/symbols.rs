@@ -0,0 +1,355 @@
+#![allow(dead_code)]
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/*
+ * Address labels loaded from assembler/debugger symbol files.
+ *
+ * Labels in banked PRG ROM are keyed by their offset into PRG ROM, so the
+ * same CPU address can resolve to different names depending on which bank
+ * the mapper currently has switched in.  Everything else (internal RAM,
+ * registers, PRG RAM, or ROM whose bank isn't known) is keyed by CPU address.
+ *
+ * Supported formats:
+ *   ca65 debug info (ld65 --dbgfile)   game.dbg
+ *   FCEUX name lists                   game.nes.ram.nl, game.nes.0.nl, game.nes.1.nl, ...
+ *   Mesen label files                  game.mlb
+ */
+
+const INES_HEADER_SIZE: u32 = 16;
+const NL_BANK_SIZE: u32 = 0x4000;
+const PRG_RAM_START: u16 = 0x6000;
+
+pub struct SymbolTable {
+    cpu: HashMap<u16, String>,
+    prg_rom: HashMap<u32, String>,
+}
+
+impl SymbolTable {
+
+    pub fn add_cpu_label(&mut self, addr: u16, name: &str) {
+        self.cpu.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn add_prg_rom_label(&mut self, offset: u32, name: &str) {
+        self.prg_rom.entry(offset).or_insert_with(|| name.to_string());
+    }
+
+    // prg_offset is where addr currently lands in PRG ROM, if it's mapped there
+    pub fn lookup(&self, addr: u16, prg_offset: Option<u32>) -> Option<&str> {
+        if let Some(offset) = prg_offset {
+            if let Some(name) = self.prg_rom.get(&offset) {
+                return Some(name);
+            }
+        }
+        return self.cpu.get(&addr).map(|name| name.as_str());
+    }
+
+    // labels from other are added unless this table already names the address
+    pub fn merge(&mut self, other: SymbolTable) {
+        for (addr, name) in other.cpu {
+            self.cpu.entry(addr).or_insert(name);
+        }
+        for (offset, name) in other.prg_rom {
+            self.prg_rom.entry(offset).or_insert(name);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.cpu.len() + self.prg_rom.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+pub fn create_symbol_table() -> SymbolTable {
+    return SymbolTable {
+        cpu: HashMap::new(),
+        prg_rom: HashMap::new(),
+    };
+}
+
+// picks the parser from the file name
+pub fn load_symbol_file(filename: &str) -> Result<SymbolTable, String> {
+    let contents = match fs::read_to_string(filename) {
+        Ok(contents) => contents,
+        Err(e) => return Err(format!("ERROR: Unable to read symbol file '{}': {}", filename, e)),
+    };
+    let lower = filename.to_lowercase();
+    return if lower.ends_with(".dbg") {
+        parse_ca65_dbg(&contents)
+    } else if lower.ends_with(".mlb") {
+        parse_mesen_mlb(&contents)
+    } else if lower.ends_with(".nl") {
+        parse_fceux_nl(&contents, fceux_nl_bank(&lower))
+    } else {
+        Err(format!("ERROR: Unrecognized symbol file type: '{}'", filename))
+    }
+}
+
+// loads every symbol file sitting next to the rom that follows the usual naming conventions
+pub fn load_symbols_for_rom(rom_filename: &str) -> SymbolTable {
+    let mut table = create_symbol_table();
+    let rom_path = Path::new(rom_filename);
+    let mut candidates: Vec<String> = vec![
+        rom_path.with_extension("dbg").to_string_lossy().into_owned(),
+        rom_path.with_extension("mlb").to_string_lossy().into_owned(),
+        format!("{}.ram.nl", rom_filename),
+    ];
+    for bank in 0..256 {
+        candidates.push(format!("{}.{:X}.nl", rom_filename, bank));
+    }
+    for candidate in candidates {
+        if Path::new(&candidate).is_file() {
+            if let Ok(symbols) = load_symbol_file(&candidate) {
+                table.merge(symbols);
+            }
+        }
+    }
+    return table;
+}
+
+// "game.nes.ram.nl" holds cpu addresses, "game.nes.3.nl" holds 16k PRG bank 3
+fn fceux_nl_bank(filename: &str) -> Option<u32> {
+    let stem = filename.trim_end_matches(".nl");
+    let suffix = stem.rsplit('.').next().unwrap_or("");
+    if suffix == "ram" {
+        return None;
+    }
+    return u32::from_str_radix(suffix, 16).ok();
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    let digits = text.trim().trim_start_matches('$').trim_start_matches("0x").trim_start_matches("0X");
+    return u32::from_str_radix(digits, 16).ok();
+}
+
+// $C000#Label#Comment, optionally $C000/10#Label# for arrays
+pub fn parse_fceux_nl(contents: &str, bank: Option<u32>) -> Result<SymbolTable, String> {
+    let mut table = create_symbol_table();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if !line.starts_with('$') {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(3, '#').collect();
+        if fields.len() < 2 {
+            return Err(format!("ERROR: Malformed name list entry on line {}: '{}'", line_number + 1, line));
+        }
+        let addr_field = fields[0].split('/').next().unwrap_or("");
+        let addr = match parse_hex(addr_field) {
+            Some(addr) if addr <= 0xFFFF => addr as u16,
+            _ => return Err(format!("ERROR: Bad address on line {}: '{}'", line_number + 1, line)),
+        };
+        let name = fields[1].trim();
+        if name.is_empty() {
+            continue; // comment-only entry
+        }
+        match bank {
+            // only by ROM offset, or whichever bank was parsed first would name every bank at this address
+            Some(bank) if addr >= 0x8000 => table.add_prg_rom_label(bank * NL_BANK_SIZE + (addr as u32 & (NL_BANK_SIZE - 1)), name),
+            _ => table.add_cpu_label(addr, name),
+        }
+    }
+    return Ok(table);
+}
+
+// TYPE:ADDR[-END]:Label[:Comment]
+pub fn parse_mesen_mlb(contents: &str) -> Result<SymbolTable, String> {
+    let mut table = create_symbol_table();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(4, ':').collect();
+        if fields.len() < 3 {
+            return Err(format!("ERROR: Malformed label on line {}: '{}'", line_number + 1, line));
+        }
+        let addr = match parse_hex(fields[1].split('-').next().unwrap_or("")) {
+            Some(addr) => addr,
+            None => return Err(format!("ERROR: Bad address on line {}: '{}'", line_number + 1, line)),
+        };
+        let name = fields[2].trim();
+        if name.is_empty() {
+            continue;
+        }
+        match fields[0] {
+            "P" | "NesPrgRom" => table.add_prg_rom_label(addr, name),
+            "R" | "NesInternalRam" => table.add_cpu_label((addr & 0x7FF) as u16, name),
+            "S" | "W" | "NesSaveRam" | "NesWorkRam" => table.add_cpu_label(PRG_RAM_START + (addr & 0x1FFF) as u16, name),
+            "G" | "NesMemory" => table.add_cpu_label(addr as u16, name),
+            _ => {} // CHR, palette, etc. labels don't name cpu addresses
+        }
+    }
+    return Ok(table);
+}
+
+struct Ca65Segment {
+    start: u32,
+    file_offset: Option<u32>,
+}
+
+// key=value,key="quoted, value",...
+fn parse_ca65_fields(line: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut rest = line;
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => break,
+        };
+        let key = rest[..eq].trim();
+        let after = &rest[eq + 1..];
+        let (value, remainder) = if after.starts_with('"') {
+            match after[1..].find('"') {
+                Some(close) => (&after[1..close + 1], &after[close + 2..]),
+                None => (&after[1..], ""), // unterminated, take the rest of the line
+            }
+        } else {
+            let comma = after.find(',').unwrap_or(after.len());
+            (&after[..comma], &after[comma..])
+        };
+        fields.insert(key, value);
+        rest = remainder.trim_start_matches(',');
+    }
+    return fields;
+}
+
+pub fn parse_ca65_dbg(contents: &str) -> Result<SymbolTable, String> {
+    let mut table = create_symbol_table();
+    let mut segments: HashMap<&str, Ca65Segment> = HashMap::new();
+    let mut has_header_segment = false;
+    let mut labels: Vec<(&str, u32, Option<&str>)> = Vec::new();
+
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let record = parts.next().unwrap_or("");
+        let fields = parse_ca65_fields(parts.next().unwrap_or("").trim());
+        match record {
+            "seg" => {
+                let id = match fields.get("id") {
+                    Some(id) => *id,
+                    None => continue,
+                };
+                let start = fields.get("start").and_then(|v| parse_hex(v)).unwrap_or(0);
+                let file_offset = fields.get("ooffs").and_then(|v| v.parse::<u32>().ok());
+                if fields.get("name") == Some(&"HEADER") && file_offset == Some(0) {
+                    has_header_segment = true;
+                }
+                segments.insert(id, Ca65Segment { start, file_offset });
+            }
+            "sym" => {
+                if fields.get("type") != Some(&"lab") {
+                    continue;
+                }
+                let name = match fields.get("name") {
+                    Some(name) => *name,
+                    None => continue,
+                };
+                let value = match fields.get("val").and_then(|v| parse_hex(v)) {
+                    Some(value) => value,
+                    None => return Err(format!("ERROR: Symbol '{}' has no value", name)),
+                };
+                labels.push((name, value, fields.get("seg").copied()));
+            }
+            _ => {}
+        }
+    }
+
+    let header_size = if has_header_segment { INES_HEADER_SIZE } else { 0 };
+    for (name, value, segment_id) in labels {
+        if value > 0xFFFF {
+            continue;
+        }
+        // segments written to the rom file have an output offset; RAM segments don't
+        let prg_offset = match segment_id.and_then(|id| segments.get(id)) {
+            Some(Ca65Segment { file_offset: Some(file_offset), start, .. }) if value >= *start && file_offset + (value - start) >= header_size => {
+                Some(file_offset + (value - start) - header_size)
+            }
+            _ => None,
+        };
+        // banked code is only labelled by ROM offset, like .nl bank files
+        match prg_offset {
+            Some(offset) => table.add_prg_rom_label(offset, name),
+            None => table.add_cpu_label(value as u16, name),
+        }
+    }
+    return Ok(table);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fceux_ram_name_list() {
+        let contents = "$0000#temp#scratch byte\n$0300/10#oam_buffer#\n$0400##comment only\n";
+        let table = parse_fceux_nl(contents, None).unwrap();
+        assert_eq!(table.lookup(0x0000, None), Some("temp"));
+        assert_eq!(table.lookup(0x0300, None), Some("oam_buffer"));
+        assert_eq!(table.lookup(0x0400, None), None);
+    }
+
+    #[test]
+    fn fceux_bank_name_list() {
+        let contents = "$8A12#update_player#\n$C000#reset#\n";
+        let table = parse_fceux_nl(contents, Some(2)).unwrap();
+        assert_eq!(table.lookup(0x8A12, Some(0x8A12)), Some("update_player"));
+        assert_eq!(table.lookup(0xC000, Some(0x8000)), Some("reset"));
+        // another bank mapped at the same address isn't given this bank's labels
+        assert_eq!(table.lookup(0xC000, Some(0x4000)), None);
+        assert_eq!(table.lookup(0xC000, None), None);
+        assert_eq!(fceux_nl_bank("game.nes.a.nl"), Some(10));
+        assert_eq!(fceux_nl_bank("game.nes.ram.nl"), None);
+    }
+
+    #[test]
+    fn mesen_labels() {
+        let contents = "P:0A12:update_player:moves the player\nR:0010:frame_counter\nS:0000-00FF:save_data\nG:2000:PPUCTRL\n";
+        let table = parse_mesen_mlb(contents).unwrap();
+        assert_eq!(table.lookup(0x8A12, Some(0x0A12)), Some("update_player"));
+        assert_eq!(table.lookup(0x8A12, Some(0x4A12)), None);
+        assert_eq!(table.lookup(0x0010, None), Some("frame_counter"));
+        assert_eq!(table.lookup(0x6000, None), Some("save_data"));
+        assert_eq!(table.lookup(0x2000, None), Some("PPUCTRL"));
+    }
+
+    #[test]
+    fn ca65_debug_info() {
+        let contents = "version\tmajor=2,minor=0\n\
+seg\tid=0,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=0\n\
+seg\tid=1,name=\"CODE\",start=0x008000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16\n\
+seg\tid=2,name=\"BSS\",start=0x000300,size=0x0100,addrsize=absolute,type=rw\n\
+sym\tid=0,name=\"update_player\",addrsize=absolute,scope=0,def=1,val=0x8A12,seg=1,type=lab\n\
+sym\tid=1,name=\"player_x\",addrsize=absolute,scope=0,def=2,val=0x300,seg=2,type=lab\n\
+sym\tid=2,name=\"SPEED\",addrsize=zeropage,scope=0,def=3,val=0x3,type=equ\n";
+        let table = parse_ca65_dbg(contents).unwrap();
+        assert_eq!(table.lookup(0x8A12, Some(0x0A12)), Some("update_player"));
+        assert_eq!(table.lookup(0x8A12, Some(0x4A12)), None);
+        assert_eq!(table.lookup(0x0300, None), Some("player_x"));
+        assert_eq!(table.lookup(0x0003, None), None);
+    }
+
+    #[test]
+    fn ca65_unterminated_quote() {
+        let fields = parse_ca65_fields("id=0,name=\"");
+        assert_eq!(fields.get("name"), Some(&""));
+        let fields = parse_ca65_fields("id=0,name=\"abc");
+        assert_eq!(fields.get("name"), Some(&"abc"));
+        assert_eq!(fields.get("id"), Some(&"0"));
+        assert!(parse_ca65_dbg("sym\tid=0,name=\"").is_ok());
+    }
+
+    #[test]
+    fn merge_keeps_existing_names() {
+        let mut table = parse_fceux_nl("$0010#counter#\n", None).unwrap();
+        table.merge(parse_fceux_nl("$0010#other#\n$0011#flags#\n", None).unwrap());
+        assert_eq!(table.lookup(0x0010, None), Some("counter"));
+        assert_eq!(table.lookup(0x0011, None), Some("flags"));
+        assert_eq!(table.len(), 2);
+    }
+}
@@ -0,0 +1,180 @@
+#![allow(dead_code)]
+use std::convert::TryInto;
+
+use super::cartridge::CartridgeError;
+
+/*
+ * UNIF: https://wiki.nesdev.com/w/index.php/UNIF
+ *
+ * A 32 byte header ("UNIF", a revision number and padding) followed by
+ * chunks of a 4 character id, a little endian length and the data.  Instead
+ * of a mapper number the MAPR chunk names the board, and PRG/CHR ROM are
+ * split across PRG0-PRGF and CHR0-CHRF, which are joined in numeric order.
+ */
+pub const UNIF_HEADER_SIZE: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UnifImage {
+    pub revision: u32,
+    pub board: String,
+    pub name: Option<String>,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mirroring: Option<u8>,   // MIRR: 0 H, 1 V, 2/3 single screen, 4 four screen, 5 mapper controlled
+    pub has_battery: bool,
+    pub tv_system: Option<u8>,   // TVCI: 0 NTSC, 1 PAL, 2 either
+    pub controllers: Option<u8>, // CTRL bitfield
+}
+
+pub fn parse_unif(bytes: &[u8]) -> Result<UnifImage, CartridgeError> {
+    if bytes.len() < UNIF_HEADER_SIZE {
+        return Err(CartridgeError::TruncatedHeader { size: bytes.len() });
+    }
+    let mut image = UnifImage {
+        revision: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        ..Default::default()
+    };
+    let mut prg_chunks: Vec<Option<&[u8]>> = vec![None; 16];
+    let mut chr_chunks: Vec<Option<&[u8]>> = vec![None; 16];
+    let mut board = None;
+
+    let mut position = UNIF_HEADER_SIZE;
+    while position + 8 <= bytes.len() {
+        let id = String::from_utf8_lossy(&bytes[position..position + 4]).into_owned();
+        let length = u32::from_le_bytes(bytes[position + 4..position + 8].try_into().unwrap()) as usize;
+        let start = position + 8;
+        if bytes.len() - start < length {
+            return Err(CartridgeError::TruncatedChunk { id, expected: length, found: bytes.len() - start });
+        }
+        let data = &bytes[start..start + length];
+        match id.as_str() {
+            "MAPR" => board = Some(read_string(data)),
+            "NAME" => image.name = Some(read_string(data)),
+            "MIRR" => image.mirroring = data.first().copied(),
+            "BATR" => image.has_battery = true,
+            "TVCI" => image.tv_system = data.first().copied(),
+            "CTRL" => image.controllers = data.first().copied(),
+            _ => {
+                if let Some(index) = chunk_index(&id, "PRG") {
+                    prg_chunks[index] = Some(data);
+                } else if let Some(index) = chunk_index(&id, "CHR") {
+                    chr_chunks[index] = Some(data);
+                }
+                // anything else (READ, DINF, PCKn/CCKn checksums...) isn't needed to run the game
+            }
+        }
+        position = start + length;
+    }
+
+    image.board = match board {
+        Some(board) => board,
+        None => return Err(CartridgeError::MissingChunk("MAPR".to_string())),
+    };
+    if prg_chunks[0].is_none() {
+        return Err(CartridgeError::MissingChunk("PRG0".to_string()));
+    }
+    image.prg_rom = prg_chunks.iter().flatten().flat_map(|chunk| chunk.iter().copied()).collect();
+    image.chr_rom = chr_chunks.iter().flatten().flat_map(|chunk| chunk.iter().copied()).collect();
+    return Ok(image);
+}
+
+// PRG0-PRGF / CHR0-CHRF
+fn chunk_index(id: &str, prefix: &str) -> Option<usize> {
+    if !id.starts_with(prefix) {
+        return None;
+    }
+    return usize::from_str_radix(&id[prefix.len()..], 16).ok().filter(|&index| index < 16);
+}
+
+// strings are null terminated, though not every dumper bothered
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+    return String::from_utf8_lossy(&data[..end]).trim().to_string();
+}
+
+// mapper and submapper numbers for the boards we have a mapper for.  Multicart
+// (BMC-) boards all need mappers of their own, so none of them are here yet
+pub fn board_mapper(board: &str) -> Option<(u16, u8)> {
+    // the prefix says who made or sold the board, not how it's wired
+    let name = ["NES-", "HVC-", "UNL-", "BTL-", "IREM-", "JALECO-", "KONAMI-", "TAITO-"].iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    return match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => Some((0, 0)),
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" | "SKROM"
+            | "SLROM" | "SL1ROM" | "SNROM" | "SOROM" | "SUROM" | "SXROM" => Some((1, 0)),
+        "UNROM" | "UOROM" => Some((2, 0)),
+        "CNROM" => Some((3, 0)),
+        "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TL1ROM" | "TR1ROM" | "TSROM"
+            | "TVROM" => Some((4, 0)),
+        "EKROM" | "ELROM" | "ETROM" | "EWROM" => Some((5, 0)),
+        "AMROM" | "ANROM" | "AN1ROM" | "AOROM" => Some((7, 0)),
+        "PNROM" | "PEEOROM" => Some((9, 0)),
+        "FJROM" | "FKROM" => Some((10, 0)),
+        "GNROM" | "MHROM" => Some((66, 0)),
+        "BNROM" => Some((34, 2)),
+        // unlicensed discrete logic boards
+        "COLORDREAMS-74*377" => Some((11, 0)),
+        "AVE-NINA-01" | "AVE-NINA-02" => Some((34, 1)),
+        "CAMERICA-BF9093" => Some((71, 0)),
+        "CAMERICA-BF9097" => Some((71, 1)), // Fire Hawk, with single screen mirroring control
+        "HOLYDIVER" => Some((78, 3)),
+        "JF-16" => Some((78, 1)),
+        "AVE-NINA-03" | "AVE-NINA-06" | "AVE-MB-91" => Some((79, 0)),
+        "JF-11" | "JF-14" => Some((140, 0)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &str, data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.as_bytes().to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        return chunk;
+    }
+
+    #[test]
+    fn chunks() {
+        let mut bytes = b"UNIF".to_vec();
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.resize(UNIF_HEADER_SIZE, 0);
+        bytes.extend(chunk("MAPR", b"NES-UNROM\0"));
+        bytes.extend(chunk("PRG1", &[0x22; 4]));
+        bytes.extend(chunk("PRG0", &[0x11; 4]));
+        bytes.extend(chunk("MIRR", &[1]));
+        bytes.extend(chunk("BATR", &[0]));
+        bytes.extend(chunk("PCK0", &[0; 4]));
+        let image = parse_unif(&bytes).unwrap();
+
+        assert_eq!(image.revision, 7);
+        assert_eq!(image.board, "NES-UNROM");
+        assert_eq!(image.prg_rom, vec![0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22]);
+        assert!(image.chr_rom.is_empty());
+        assert_eq!(image.mirroring, Some(1));
+        assert!(image.has_battery);
+        assert_eq!(image.tv_system, None);
+
+        bytes.extend(chunk("CHR0", &[0; 16]));
+        let length = bytes.len();
+        assert_eq!(parse_unif(&bytes[..length - 4]).err(), Some(CartridgeError::TruncatedChunk { id: "CHR0".to_string(), expected: 16, found: 12 }));
+        assert_eq!(parse_unif(&bytes[..UNIF_HEADER_SIZE]).err(), Some(CartridgeError::MissingChunk("MAPR".to_string())));
+        assert_eq!(parse_unif(b"UNIF").err(), Some(CartridgeError::TruncatedHeader { size: 4 }));
+    }
+
+    #[test]
+    fn board_names() {
+        assert_eq!(board_mapper("NES-SLROM"), Some((1, 0)));
+        assert_eq!(board_mapper("HVC-TLROM"), Some((4, 0)));
+        assert_eq!(board_mapper("UNL-BNROM"), Some((34, 2)));
+        assert_eq!(board_mapper("COLORDREAMS-74*377"), Some((11, 0)));
+        assert_eq!(board_mapper("UNL-CAMERICA-BF9097"), Some((71, 1)));
+        assert_eq!(board_mapper("IREM-HOLYDIVER"), Some((78, 3)));
+        assert_eq!(board_mapper("JALECO-JF-14"), Some((140, 0)));
+        assert_eq!(board_mapper("AVE-NINA-06"), Some((79, 0)));
+        assert_eq!(board_mapper("BMC-Super24in1SC03"), None);
+    }
+}